@@ -18,20 +18,39 @@ use crate::{
 	http_client::HttpClient,
 	module_loader::TypescriptModuleLoader,
 	progress_manager::ProgressManager,
-	tools::{bindgen, doc, upgrade},
-	util::display::write_to_stdout_ignore_sigpipe,
+	tools::{bindgen, doc, policy, state, upgrade, workspace},
+	util::{
+		display::{write_json_to_stdout, write_to_stdout_ignore_sigpipe},
+		passphrase::{read_new_passphrase, read_passphrase},
+	},
 	version, Result,
 };
-use clap::Parser;
+use anyhow::{anyhow, bail};
+use clap::{Parser, ValueEnum};
 use console::{style, Emoji};
+use deno_core::serde_json;
 use dialoguer::Confirm;
 use indicatif::HumanDuration;
 use mashin_runtime::{
-	BackendState, ExecutedResources, MashinBuilder, MashinDir, MashinEngine, Runtime,
-	RuntimeCommand,
+	add_recipient, generate_identity, remove_recipient, BackendConfig, BackendState,
+	ExecutedResources, Journal, MashinBuilder, MashinDir, MashinEngine, Plan, PlanReport,
+	Recipients, Runtime, RuntimeCommand, Snapshot, StackOutputs, StateExport, DEFAULT_WORKSPACE,
+};
+use mashin_sdk::{LockInfo, ResourceAction};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+	cell::RefCell,
+	collections::BTreeSet,
+	env::current_dir,
+	fs::OpenOptions,
+	io::Write,
+	path::{Path, PathBuf},
+	rc::Rc,
+	str::FromStr,
+	sync::Arc,
+	time::{Duration, Instant},
 };
-use mashin_sdk::{ResourceAction, Urn};
-use std::{cell::RefCell, env::current_dir, rc::Rc, str::FromStr, sync::Arc, time::Instant};
 
 pub enum Config {}
 
@@ -53,8 +72,22 @@ pub struct Cli {
 pub enum Subcommand {
 	/// Run a JavaScript or TypeScript program.
 	Run(RunCmd),
+	/// Plan the changes of a program, the plan can be saved to be applied later.
+	Plan(PlanCmd),
+	/// Apply the changes of a plan saved by `mashin plan`.
+	Apply(ApplyCmd),
 	/// Destroy all resources in the current state.
 	Destroy(DestroyCmd),
+	/// Adopt an existing resource into the state.
+	Import(ImportCmd),
+	/// Detect the changes made outside of Mashin and update the state.
+	Refresh(RefreshCmd),
+	/// Print the outputs saved by the last apply.
+	Output(OutputCmd),
+	/// Inspect and manage the project state.
+	State(StateCmd),
+	/// Manage the workspaces of the project, each one with its own state.
+	Workspace(WorkspaceCmd),
 	/// Generate TypeScript binding for providers.
 	Bindgen(BindgenCmd),
 	/// Generate documentation from TypeScript compatible with Mashin registry.
@@ -71,6 +104,160 @@ pub struct RunCmd {
 	pub main_module: String,
 	#[arg(long, default_value_t = false)]
 	pub dry_run: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Seconds to wait for the state lock, instead of failing immediately
+	#[arg(long)]
+	pub lock_timeout: Option<u64>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+	/// Format of the plan, `json` is printed on stdout
+	#[arg(long, value_enum, default_value_t = PlanFormat::Text)]
+	pub format: PlanFormat,
+	/// Folder of policy modules checked against the planned changes, a mandatory
+	/// violation stops the run before anything is applied
+	#[arg(long)]
+	pub policy: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct PlanCmd {
+	pub main_module: String,
+	/// Write the plan to this file, to be applied with `mashin apply`. The file
+	/// holds the config of the resources in plaintext, secrets included
+	#[arg(long, short)]
+	pub out: Option<PathBuf>,
+	/// Format of the plan, `json` is printed on stdout
+	#[arg(long, value_enum, default_value_t = PlanFormat::Text)]
+	pub format: PlanFormat,
+	/// Folder of policy modules checked against the planned changes, a mandatory
+	/// violation fails the plan
+	#[arg(long)]
+	pub policy: Option<PathBuf>,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Seconds to wait for the state lock, instead of failing immediately
+	#[arg(long)]
+	pub lock_timeout: Option<u64>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct ApplyCmd {
+	/// Plan written by `mashin plan --out`
+	pub plan: PathBuf,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Seconds to wait for the state lock, instead of failing immediately
+	#[arg(long)]
+	pub lock_timeout: Option<u64>,
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct RefreshCmd {
+	pub main_module: String,
+	/// Report the changes without updating the state
+	#[arg(long, default_value_t = false, conflicts_with = "yes")]
+	pub dry_run: bool,
+	/// Update the state without confirmation, eg; in a scheduled job
+	#[arg(long, short, default_value_t = false)]
+	pub yes: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Seconds to wait for the state lock, instead of failing immediately
+	#[arg(long)]
+	pub lock_timeout: Option<u64>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct ImportCmd {
+	/// URN of the resource, as declared in the main module
+	pub urn: String,
+	/// Identifier of the existing resource within the provider
+	pub id: String,
+	/// Program declaring the resource and its provider
+	#[arg(long, short)]
+	pub main_module: String,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Seconds to wait for the state lock, instead of failing immediately
+	#[arg(long)]
+	pub lock_timeout: Option<u64>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct OutputCmd {
+	/// Name of the output, all outputs are printed by default
+	pub name: Option<String>,
+	/// Print as JSON, sensitive values included
+	#[arg(long, default_value_t = false)]
+	pub json: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+impl OutputCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		let outputs = state::outputs(&mashin_dir, &passphrase)?;
+
+		// a single output is printed raw, to be consumed by scripts
+		if let Some(name) = &self.name {
+			let output = outputs.get(name).ok_or_else(|| anyhow!("output `{name}` not found"))?;
+			return match output.display_value(self.json) {
+				serde_json::Value::String(value) if !self.json =>
+					Ok(write_to_stdout_ignore_sigpipe(format!("{value}\n").as_bytes())?),
+				value => write_json_to_stdout(&value),
+			}
+		}
+
+		if self.json {
+			let outputs = outputs
+				.iter()
+				.map(|(name, output)| {
+					(
+						name.clone(),
+						serde_json::json!({ "value": output.value, "sensitive": output.sensitive }),
+					)
+				})
+				.collect::<serde_json::Map<_, _>>();
+			return write_json_to_stdout(&outputs)
+		}
+
+		if outputs.is_empty() {
+			log::info!("    No output in the state");
+		}
+
+		for (name, output) in outputs.iter() {
+			log::info!("    {} = {}", style(name).bold(), output.display_value(false));
+		}
+
+		Ok(())
+	}
 }
 
 #[derive(Debug, Parser)]
@@ -186,139 +373,1351 @@ impl DocCmd {
 }
 
 #[derive(Debug, Parser)]
-pub struct DestroyCmd {
-	pub main_module: String,
+pub struct StateCmd {
+	#[clap(subcommand)]
+	pub subcommand: StateSubcommand,
 }
 
-impl RunCmd {
-	pub async fn run(&self, args: Vec<String>) -> Result<()> {
-		write_to_stdout_ignore_sigpipe(
-			format!("\n\n{}\n", style(crate::MASHIN).bold()).as_bytes(),
-		)?;
+#[derive(Debug, Parser)]
+pub enum StateSubcommand {
+	/// List the resources in the state.
+	List(StateListCmd),
+	/// Print the state of a resource.
+	Show(StateShowCmd),
+	/// Forget a resource, the infrastructure is not modified.
+	Rm(StateRmCmd),
+	/// Move the state of a resource to a new name.
+	Mv(StateMvCmd),
+	/// Export the whole state to a portable JSON document.
+	Export(StateExportCmd),
+	/// Import a document written by `mashin state export`.
+	Import(StateImportCmd),
+	/// Copy the whole state from a backend to another.
+	Migrate(StateMigrateCmd),
+	/// Re-encrypt the state with a new passphrase.
+	Rekey(StateRekeyCmd),
+	/// Encrypt the state for the keys of the team members instead of a passphrase.
+	Recipients(StateRecipientsCmd),
+	/// Release the state lock left by a crashed run.
+	Unlock(StateUnlockCmd),
+	/// List the state snapshots, one is recorded after every apply.
+	History(StateHistoryCmd),
+	/// Compare the resources of two snapshots.
+	Diff(StateDiffCmd),
+	/// Restore the state recorded in a snapshot.
+	Rollback(StateRollbackCmd),
+}
 
-		let started = Instant::now();
+impl StateCmd {
+	pub async fn run(&self) -> Result<()> {
+		match &self.subcommand {
+			StateSubcommand::List(cmd) => cmd.run().await,
+			StateSubcommand::Show(cmd) => cmd.run().await,
+			StateSubcommand::Rm(cmd) => cmd.run().await,
+			StateSubcommand::Mv(cmd) => cmd.run().await,
+			StateSubcommand::Export(cmd) => cmd.run().await,
+			StateSubcommand::Import(cmd) => cmd.run().await,
+			StateSubcommand::Migrate(cmd) => cmd.run().await,
+			StateSubcommand::Rekey(cmd) => cmd.run().await,
+			StateSubcommand::Recipients(cmd) => cmd.run().await,
+			StateSubcommand::Unlock(cmd) => cmd.run().await,
+			StateSubcommand::History(cmd) => cmd.run().await,
+			StateSubcommand::Diff(cmd) => cmd.run().await,
+			StateSubcommand::Rollback(cmd) => cmd.run().await,
+		}
+	}
+}
 
-		let mashin_dir = MashinDir::new(None)?;
-		let backend_state = BackendState::new(&mashin_dir)?;
-		let backend = Rc::new(RefCell::new(backend_state));
-		let mut progress_manager = ProgressManager::new();
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateListCmd {
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
 
-		let http_client = HttpClient::new(
-			HttpCache::new(&mashin_dir.deps_folder_path()),
-			None,
-			true,
-			log::Level::Info,
-			Some(progress_manager.http_progress.clone()),
-		)?;
+impl StateListCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let resources = state::list(&mashin_dir)?;
 
-		let create_runtime = |command, executed_resource, maybe_count, progress_bar| {
-			let BuiltEngine { engine, module_loader } = build_engine(
-				command,
-				progress_bar,
-				executed_resource,
-				maybe_count,
-				backend.clone(),
-				mashin_dir.clone(),
-				http_client.clone(),
-			)?;
-			Runtime::new(&self.main_module, engine, module_loader, args.clone())
-		};
+		if resources.is_empty() {
+			log::info!("    No resource in the state");
+		}
 
-		log::info!("    Starting the engine");
+		for (provider, urn) in resources {
+			log::info!("    {}  {}", style(provider).bold(), urn.as_display());
+		}
 
-		upgrade::check_for_upgrades(
-			Arc::new(http_client.clone()),
-			mashin_dir.upgrade_check_file_path(),
-		);
+		Ok(())
+	}
+}
 
-		let isolated_pm = progress_manager.clone();
-		let total_resources = create_runtime(RuntimeCommand::Prepare, None, None, &isolated_pm)?
-			.prepare()
-			.await?;
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateShowCmd {
+	/// URN of the resource, as displayed by `mashin state list`
+	pub urn: String,
+	/// Print the sensitive values instead of masking them
+	#[arg(long, default_value_t = false)]
+	pub show_sensitive: bool,
+	/// Print the metadata stored beside the entry (provider, resource type,
+	/// last update and Mashin version) instead of its state, the passphrase is
+	/// not required. Only the sqlite backend stores them
+	#[arg(long, default_value_t = false, conflicts_with = "show_sensitive")]
+	pub metadata: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
 
-		progress_manager.set_resource_progress(total_resources)?;
+impl StateShowCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let urn = state::parse_urn(&self.urn)?;
 
-		log::info!("    Reading {} resources", total_resources);
+		if self.metadata {
+			return write_json_to_stdout(&state::entry_metadata(&mashin_dir, &urn)?)
+		}
 
-		let isolated_pm = progress_manager.clone();
-		let runtime_result =
-			create_runtime(RuntimeCommand::Read, None, Some(total_resources), &isolated_pm)?
-				.run()
-				.await?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
 
-		progress_manager.maybe_finish_resource_progress();
+		let raw_state = state::show(&mashin_dir, &passphrase, &urn)?;
+		write_json_to_stdout(&raw_state.display_value(self.show_sensitive))
+	}
+}
 
-		// clone our resource to prevent `BorrowMutError` on the engine on the second run
-		let executed_resouces = runtime_result.executed_resources.borrow().clone();
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRmCmd {
+	/// URN of the resource, as displayed by `mashin state list`
+	pub urn: String,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
 
-		// FIXME: Move to cli print_diff
-		//print_diff(&executed_resouces)?;
-		executed_resouces.print_diff_plan();
+impl StateRmCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let urn = state::parse_urn(&self.urn)?;
 
-		if !self.dry_run &&
-			!executed_resouces.actions().is_empty() &&
-			Confirm::new().with_prompt("\n    Do you want to apply?").interact()?
+		if !Confirm::new()
+			.with_prompt(format!(
+				"\n    Remove `{}` from the state? The infrastructure is not modified",
+				urn.as_display()
+			))
+			.interact()?
 		{
-			progress_manager.set_resource_progress(total_resources)?;
-			log::info!("    Applying changes");
-
-			// delete non-present resources that will not receive any hooks
-			// probably removed within the client code (TS)
-			for (urn, resource) in executed_resouces.iter() {
-				if resource.required_change == Some(ResourceAction::Delete) {
-					let urn = Urn::from_str(urn)?;
-					backend.borrow().delete(&urn)?;
-				}
-			}
+			return Ok(())
+		}
 
-			create_runtime(
-				RuntimeCommand::Apply,
-				Some(runtime_result.executed_resources),
-				Some(total_resources),
-				&progress_manager,
-			)?
-			.run()
-			.await?;
+		state::remove(&mashin_dir, &urn)?;
+
+		log::info!("    Removed {}", urn.as_display());
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateMvCmd {
+	/// Current URN of the resource
+	pub from: String,
+	/// New URN of the resource, of the same resource type
+	pub to: String,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+impl StateMvCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let from = state::parse_urn(&self.from)?;
+		let to = state::parse_urn(&self.to)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+
+		state::move_entry(&mashin_dir, &passphrase, &from, &to)?;
+
+		log::info!("    Moved {} to {}", from.as_display(), to.as_display());
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateExportCmd {
+	/// File to write the export to, default to stdout
+	#[arg(long, short)]
+	pub output: Option<PathBuf>,
+	/// Decrypt the entries, sensitive values are written in clear
+	#[arg(long, default_value_t = false)]
+	pub plaintext: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+impl StateExportCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+
+		let export = state::export(&mashin_dir, &passphrase, self.plaintext)?;
+
+		match &self.output {
+			Some(output) => {
+				std::fs::write(output, serde_json::to_vec_pretty(&export)?)?;
+				log::info!("    State exported to {}", output.display());
+			},
+			None => write_json_to_stdout(&export)?,
 		}
 
-		progress_manager.maybe_finish_resource_progress();
+		if self.plaintext {
+			log::warn!("    The export contains sensitive values in clear, keep it safe");
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateImportCmd {
+	/// File written by `mashin state export`
+	pub file: PathBuf,
+	/// Overwrite the entries already in the state
+	#[arg(long, default_value_t = false)]
+	pub force: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+impl StateImportCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let export = StateExport::from_slice(&std::fs::read(&self.file)?)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+
+		let total_entries = state::import(&mashin_dir, &passphrase, &export, self.force)?;
+
+		log::info!("    Imported {total_entries} state entries");
 		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
 
 		Ok(())
 	}
 }
 
-impl DestroyCmd {
-	pub async fn run(&self, _args: Vec<String>) -> Result<()> {
-		todo!()
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateMigrateCmd {
+	/// Source backend config, as a JSON file, inline JSON or a backend type (`local`)
+	#[arg(long)]
+	pub from: String,
+	/// Target backend config, as a JSON file, inline JSON or a backend type (`local`)
+	#[arg(long)]
+	pub to: String,
+	/// Delete the source entries once migrated
+	#[arg(long, default_value_t = false)]
+	pub delete_source: bool,
+	/// Overwrite the entries already in the target backend
+	#[arg(long, default_value_t = false)]
+	pub force: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+impl StateMigrateCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		let from = BackendConfig::from_str(&self.from)?;
+		let to = BackendConfig::from_str(&self.to)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+
+		if self.delete_source &&
+			!Confirm::new()
+				.with_prompt("\n    Delete the source state once migrated?")
+				.interact()?
+		{
+			return Ok(())
+		}
+
+		let total_entries =
+			state::migrate(&mashin_dir, &passphrase, &from, &to, self.force, self.delete_source)?;
+
+		log::info!("    Migrated {total_entries} state entries");
+		log::info!(
+			"    Update the backend in `{}` to use the new state",
+			mashin_dir.config_file_path().display()
+		);
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRekeyCmd {
+	/// File containing the current state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// File containing the new state passphrase, default to `MASHIN_NEW_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub new_key_file: Option<PathBuf>,
+}
+
+impl StateRekeyCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = MashinDir::new(None)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		let new_passphrase = read_new_passphrase(self.new_key_file.as_deref())?;
+
+		let total_entries = state::rekey(&mashin_dir, &passphrase, &new_passphrase)?;
+
+		log::info!("    Re-encrypted {total_entries} state entries");
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+pub struct StateRecipientsCmd {
+	#[clap(subcommand)]
+	pub subcommand: StateRecipientsSubcommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum StateRecipientsSubcommand {
+	/// List the recipients able to decrypt the state.
+	List(StateRecipientsListCmd),
+	/// Allow a recipient to decrypt the state, the first one replaces the passphrase.
+	Add(StateRecipientsAddCmd),
+	/// Remove a recipient, the data key is not rotated.
+	Remove(StateRecipientsRemoveCmd),
+	/// Generate the private key of a recipient.
+	Keygen(StateRecipientsKeygenCmd),
+}
+
+impl StateRecipientsCmd {
+	pub async fn run(&self) -> Result<()> {
+		match &self.subcommand {
+			StateRecipientsSubcommand::List(cmd) => cmd.run().await,
+			StateRecipientsSubcommand::Add(cmd) => cmd.run().await,
+			StateRecipientsSubcommand::Remove(cmd) => cmd.run().await,
+			StateRecipientsSubcommand::Keygen(cmd) => cmd.run().await,
+		}
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRecipientsListCmd {}
+
+impl StateRecipientsListCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = MashinDir::new(None)?;
+
+		match Recipients::load(&mashin_dir)? {
+			Some(recipients) =>
+				for recipient in recipients.iter() {
+					log::info!("    {}  {}", style(&recipient.name).bold(), recipient.public_key);
+				},
+			None => log::info!("    The state is encrypted with a passphrase"),
+		}
+
+		Ok(())
 	}
 }
 
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRecipientsAddCmd {
+	/// Name of the recipient, eg; `alice`
+	pub name: String,
+	/// Public key printed by `mashin state recipients keygen`
+	pub public_key: String,
+	/// File containing the state passphrase or the identity of a recipient,
+	/// default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+}
+
+impl StateRecipientsAddCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = MashinDir::new(None)?;
+		let is_first_recipient = Recipients::load(&mashin_dir)?.is_none();
+
+		if is_first_recipient &&
+			!Confirm::new()
+				.with_prompt(
+					"\n    The state will be re-encrypted for the recipients and the passphrase will not work anymore, continue?",
+				)
+				.interact()?
+		{
+			return Ok(())
+		}
+
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		add_recipient(&mashin_dir, passphrase.as_bytes(), &self.name, &self.public_key)?;
+
+		log::info!("    Added recipient {}", style(&self.name).bold());
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRecipientsRemoveCmd {
+	/// Name of the recipient
+	pub name: String,
+}
+
+impl StateRecipientsRemoveCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = MashinDir::new(None)?;
+
+		remove_recipient(&mashin_dir, &self.name)?;
+
+		log::info!("    Removed recipient {}", self.name);
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRecipientsKeygenCmd {
+	/// File to write the private key to, used with `--key-file`
+	#[arg(long, short)]
+	pub output: PathBuf,
+}
+
+impl StateRecipientsKeygenCmd {
+	pub async fn run(&self) -> Result<()> {
+		let (identity, public_key) = generate_identity()?;
+
+		let mut options = OpenOptions::new();
+		options.write(true).create_new(true);
+		#[cfg(unix)]
+		options.mode(0o600);
+		options
+			.open(&self.output)
+			.and_then(|mut file| file.write_all(format!("{identity}\n").as_bytes()))
+			.map_err(|err| anyhow!("unable to write `{}`; {err}", self.output.display()))?;
+
+		log::info!("    Wrote private key to: {}", style(self.output.display()).underlined());
+		log::info!("    Public key: {}", style(public_key).bold());
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateUnlockCmd {
+	/// Remove the lock even if it is held by another process
+	#[arg(long, default_value_t = false)]
+	pub force: bool,
+}
+
+impl StateUnlockCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		if !self.force {
+			bail!("the lock may be held by a running process, use `--force` to remove it anyway");
+		}
+
+		let mashin_dir = MashinDir::new(None)?;
+		state::force_unlock(&mashin_dir)?;
+
+		log::info!("    State unlocked");
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateHistoryCmd {}
+
+impl StateHistoryCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = MashinDir::new(None)?;
+		let snapshots = Snapshot::list(&mashin_dir)?;
+
+		if snapshots.is_empty() {
+			log::info!("    No snapshot recorded yet");
+		}
+
+		for snapshot in snapshots {
+			log::info!(
+				"    {}  {}  mashin {}  {}",
+				style(snapshot.id).bold(),
+				snapshot.created_at,
+				snapshot.mashin_version,
+				snapshot.main_module
+			);
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateDiffCmd {
+	/// Id of the previous snapshot
+	pub from: u64,
+	/// Id of the next snapshot
+	pub to: u64,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+}
+
+impl StateDiffCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = MashinDir::new(None)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		let executed_resources =
+			state::diff_snapshots(&mashin_dir, &passphrase, self.from, self.to)?;
+
+		if executed_resources.actions().is_empty() {
+			log::info!("    No changes between snapshot {} and {}", self.from, self.to);
+			return Ok(())
+		}
+
+		log::info!("\nChanges from snapshot {} to {}:\n", self.from, self.to);
+		for (urn, executed_resource) in executed_resources.iter() {
+			if let Err(err) = executed_resource.print_diff(urn) {
+				// no changes for this resource
+				log::trace!("{err}")
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct StateRollbackCmd {
+	/// Id of the snapshot to restore
+	pub id: u64,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+}
+
+impl StateRollbackCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = MashinDir::new(None)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+
+		if !Confirm::new()
+			.with_prompt(format!(
+				"\n    Replace the current state with snapshot {}? The infrastructure is not modified",
+				self.id
+			))
+			.interact()?
+		{
+			return Ok(())
+		}
+
+		let (total_entries, snapshot) = state::rollback(&mashin_dir, &passphrase, self.id)?;
+
+		log::info!(
+			"    Restored {total_entries} state entries, recorded as snapshot {}",
+			snapshot.id
+		);
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+pub struct WorkspaceCmd {
+	#[clap(subcommand)]
+	pub subcommand: WorkspaceSubcommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum WorkspaceSubcommand {
+	/// Create a workspace and select it.
+	New(WorkspaceNewCmd),
+	/// Select the workspace used by the next commands.
+	Select(WorkspaceSelectCmd),
+	/// List the workspaces, the selected one is highlighted.
+	List(WorkspaceListCmd),
+	/// Delete a workspace and its state.
+	Delete(WorkspaceDeleteCmd),
+}
+
+impl WorkspaceCmd {
+	pub async fn run(&self) -> Result<()> {
+		match &self.subcommand {
+			WorkspaceSubcommand::New(cmd) => cmd.run().await,
+			WorkspaceSubcommand::Select(cmd) => cmd.run().await,
+			WorkspaceSubcommand::List(cmd) => cmd.run().await,
+			WorkspaceSubcommand::Delete(cmd) => cmd.run().await,
+		}
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct WorkspaceNewCmd {
+	/// Name of the workspace, eg; `staging`
+	pub name: String,
+}
+
+impl WorkspaceNewCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = MashinDir::new(None)?;
+		workspace::create(&mashin_dir, &self.name)?;
+		workspace::select(&mashin_dir, &self.name)?;
+
+		log::info!("    Created and selected workspace {}", style(&self.name).bold());
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct WorkspaceSelectCmd {
+	/// Name of the workspace, `default` for the initial workspace
+	pub name: String,
+}
+
+impl WorkspaceSelectCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = MashinDir::new(None)?;
+		workspace::select(&mashin_dir, &self.name)?;
+
+		log::info!("    Selected workspace {}", style(&self.name).bold());
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct WorkspaceListCmd {}
+
+impl WorkspaceListCmd {
+	pub async fn run(&self) -> Result<()> {
+		let mashin_dir = MashinDir::new(None)?;
+
+		for name in workspace::list(&mashin_dir)? {
+			if name == mashin_dir.workspace() {
+				log::info!("  * {}", style(name).bold());
+			} else {
+				log::info!("    {name}");
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct WorkspaceDeleteCmd {
+	/// Name of the workspace
+	pub name: String,
+	/// Delete the state even if it still contains resources, they are not destroyed
+	#[arg(long, default_value_t = false)]
+	pub force: bool,
+}
+
+impl WorkspaceDeleteCmd {
+	pub async fn run(&self) -> Result<()> {
+		let started = Instant::now();
+		let mashin_dir = MashinDir::new(None)?;
+
+		if !Confirm::new()
+			.with_prompt(format!("\n    Delete workspace `{}` and its state?", self.name))
+			.interact()?
+		{
+			return Ok(())
+		}
+
+		workspace::delete(&mashin_dir, &self.name, self.force)?;
+
+		log::info!("    Deleted workspace {}", self.name);
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Parser)]
+#[group(skip)]
+pub struct DestroyCmd {
+	pub main_module: String,
+	#[arg(long, default_value_t = false)]
+	pub dry_run: bool,
+	/// File containing the state passphrase, default to `MASHIN_PASSPHRASE` or prompt
+	#[arg(long)]
+	pub key_file: Option<PathBuf>,
+	/// Seconds to wait for the state lock, instead of failing immediately
+	#[arg(long)]
+	pub lock_timeout: Option<u64>,
+	/// Workspace to use instead of the selected one
+	#[arg(long)]
+	pub workspace: Option<String>,
+}
+
+impl RunCmd {
+	pub async fn run(&self, args: Vec<String>) -> Result<()> {
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		plan_changes(
+			&self.main_module,
+			args,
+			mashin_dir,
+			self.key_file.as_deref(),
+			self.lock_timeout,
+			self.format,
+			self.policy.as_deref(),
+			PlanAction::Prompt { dry_run: self.dry_run },
+		)
+		.await
+	}
+}
+
+impl PlanCmd {
+	pub async fn run(&self, args: Vec<String>) -> Result<()> {
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		plan_changes(
+			&self.main_module,
+			args,
+			mashin_dir,
+			self.key_file.as_deref(),
+			self.lock_timeout,
+			self.format,
+			self.policy.as_deref(),
+			PlanAction::Save { out: self.out.as_deref() },
+		)
+		.await
+	}
+}
+
+impl ApplyCmd {
+	pub async fn run(&self) -> Result<()> {
+		let plan = Plan::load(&self.plan)?;
+		// the plan is applied with the same workspace, arguments and policies it
+		// has been made with
+		let mashin_dir = workspace::current(Some(&plan.workspace))?;
+		plan_changes(
+			&plan.main_module,
+			plan.args.clone(),
+			mashin_dir,
+			self.key_file.as_deref(),
+			self.lock_timeout,
+			PlanFormat::Text,
+			plan.policy_dir.as_deref(),
+			PlanAction::Apply { plan: &plan },
+		)
+		.await
+	}
+}
+
+/// How the planned changes are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PlanFormat {
+	/// Coloured diff, for humans
+	Text,
+	/// Stable schema for scripts, see `docs/plan-json.md`
+	Json,
+}
+
+/// What to do with the changes planned by the main module.
+enum PlanAction<'a> {
+	/// Prompt to apply them, unless it's a dry run (`mashin run`)
+	Prompt { dry_run: bool },
+	/// Write them to a plan file, when provided (`mashin plan`)
+	Save { out: Option<&'a Path> },
+	/// Apply the saved plan as is, if the state has not changed (`mashin apply`)
+	Apply { plan: &'a Plan },
+}
+
+/// Run the main module to plan the changes of the resources, then apply them
+/// depending of the `PlanAction`.
+#[allow(clippy::too_many_arguments)]
+async fn plan_changes(
+	main_module: &str,
+	args: Vec<String>,
+	mashin_dir: MashinDir,
+	key_file: Option<&Path>,
+	lock_timeout: Option<u64>,
+	format: PlanFormat,
+	maybe_policy_dir: Option<&Path>,
+	action: PlanAction<'_>,
+) -> Result<()> {
+	// stdout is kept for the plan
+	if format == PlanFormat::Text {
+		write_to_stdout_ignore_sigpipe(
+			format!("\n\n{}\n", style(crate::MASHIN).bold()).as_bytes(),
+		)?;
+	}
+
+	let started = Instant::now();
+
+	if mashin_dir.workspace() != DEFAULT_WORKSPACE {
+		log::info!("    Using workspace {}", style(mashin_dir.workspace()).bold());
+	}
+	let passphrase = read_passphrase(key_file)?;
+	let backend_state = BackendState::new(&mashin_dir)?;
+	let backend = Rc::new(RefCell::new(backend_state));
+	let state_lock = BackendState::lock_guard(&backend, lock_timeout.map(Duration::from_secs))?;
+	let mut progress_manager = ProgressManager::new();
+
+	let http_client = HttpClient::new(
+		HttpCache::new(&mashin_dir.deps_folder_path()),
+		None,
+		true,
+		log::Level::Info,
+		Some(progress_manager.http_progress.clone()),
+	)?;
+
+	let create_runtime = |command, executed_resource, maybe_count, progress_bar| {
+		let BuiltEngine { engine, module_loader } = build_engine(
+			command,
+			progress_bar,
+			executed_resource,
+			maybe_count,
+			backend.clone(),
+			state_lock.lock(),
+			mashin_dir.clone(),
+			http_client.clone(),
+			passphrase.as_bytes(),
+		)?;
+		Runtime::new(main_module, engine, module_loader, args.clone())
+	};
+
+	reconcile_journal(
+		main_module,
+		&args,
+		backend.clone(),
+		state_lock.lock(),
+		&mashin_dir,
+		&http_client,
+		&passphrase,
+	)
+	.await?;
+
+	log::info!("    Starting the engine");
+
+	upgrade::check_for_upgrades(
+		Arc::new(http_client.clone()),
+		mashin_dir.upgrade_check_file_path(),
+	);
+
+	let isolated_pm = progress_manager.clone();
+	let mut prepare_runtime = create_runtime(RuntimeCommand::Prepare, None, None, &isolated_pm)?;
+
+	// a saved plan is only valid against the state it has been made with
+	if let PlanAction::Apply { plan } = &action {
+		prepare_runtime.verify_plan(plan)?;
+	}
+
+	let total_resources = prepare_runtime.prepare().await?;
+
+	let (planning_runtime, executed_resources, has_changes) = if let PlanAction::Apply { plan } =
+		&action
+	{
+		// the saved actions are applied as is, the resources are not read again
+		plan.resources.print_diff_plan();
+		(prepare_runtime, Rc::new(RefCell::new(plan.resources.clone())), plan.has_changes)
+	} else {
+		drop(prepare_runtime);
+
+		progress_manager.set_resource_progress(total_resources)?;
+
+		log::info!("    Reading {} resources", total_resources);
+
+		let isolated_pm = progress_manager.clone();
+		let mut read_runtime =
+			create_runtime(RuntimeCommand::Read, None, Some(total_resources), &isolated_pm)?;
+		let runtime_result = read_runtime.run().await?;
+
+		progress_manager.maybe_finish_resource_progress();
+
+		// clone our resource to prevent `BorrowMutError` on the engine on the second run
+		let executed_resouces = runtime_result.executed_resources.borrow().clone();
+
+		// FIXME: Move to cli print_diff
+		//print_diff(&executed_resouces)?;
+		match format {
+			PlanFormat::Text => executed_resouces.print_diff_plan(),
+			PlanFormat::Json => write_json_to_stdout(&PlanReport::new(&executed_resouces))?,
+		}
+		let has_output_changes =
+			read_runtime.outputs().print_changes(&read_runtime.stored_outputs()?);
+		let has_reference_changes = read_runtime
+			.stack_references()
+			.print_changes(&read_runtime.stored_stack_references()?);
+		let has_changes =
+			!executed_resouces.actions().is_empty() || has_output_changes || has_reference_changes;
+
+		(read_runtime, runtime_result.executed_resources, has_changes)
+	};
+	let executed_resouces = executed_resources.borrow().clone();
+
+	// a mandatory violation blocks the apply, and the plan is not saved
+	if let Some(policy_dir) = maybe_policy_dir {
+		policy::check(policy_dir, &executed_resouces, &http_client).await?;
+	}
+
+	let should_apply = match action {
+		PlanAction::Prompt { dry_run } =>
+			!dry_run &&
+				has_changes && Confirm::new().with_prompt("\n    Do you want to apply?").interact()?,
+		PlanAction::Save { out } => {
+			if let Some(out) = out {
+				Plan::new(
+					version::mashin(),
+					mashin_dir.workspace(),
+					planning_runtime.main_module_specifier()?.as_str(),
+					args.clone(),
+					planning_runtime.state_hash()?,
+					executed_resouces.clone(),
+					has_changes,
+					maybe_policy_dir,
+				)
+				.save(out)?;
+				log::info!(
+					"\n    Plan saved to {}, apply it with `mashin apply {}`",
+					out.display(),
+					out.display()
+				);
+			}
+			false
+		},
+		PlanAction::Apply { .. } => {
+			if !has_changes {
+				log::info!("\n    No changes to apply");
+			}
+			has_changes
+		},
+	};
+
+	if should_apply {
+		progress_manager.set_resource_progress(total_resources)?;
+		log::info!("    Applying changes");
+
+		// delete non-present resources that will not receive any hooks
+		// probably removed within the client code (TS)
+		planning_runtime.delete_resources(&executed_resouces)?;
+		drop(planning_runtime);
+
+		let mut apply_runtime = create_runtime(
+			RuntimeCommand::Apply,
+			Some(executed_resources),
+			Some(total_resources),
+			&progress_manager,
+		)?;
+		apply_runtime.run().await?;
+
+		// the outputs are evaluated with the applied resources
+		apply_runtime.save_outputs()?;
+		apply_runtime.save_stack_references()?;
+
+		// all operations are saved within the state
+		Journal::new(&mashin_dir).clear()?;
+
+		let snapshot = state::snapshot(&mashin_dir, &backend.borrow(), &passphrase, main_module)?;
+		log::info!("    State recorded as snapshot {}", snapshot.id);
+	}
+
+	progress_manager.maybe_finish_resource_progress();
+	log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+	Ok(())
+}
+
+impl DestroyCmd {
+	pub async fn run(&self, args: Vec<String>) -> Result<()> {
+		write_to_stdout_ignore_sigpipe(
+			format!("\n\n{}\n", style(crate::MASHIN).bold()).as_bytes(),
+		)?;
+
+		let started = Instant::now();
+
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		if mashin_dir.workspace() != DEFAULT_WORKSPACE {
+			log::info!("    Using workspace {}", style(mashin_dir.workspace()).bold());
+		}
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		let backend_state = BackendState::new(&mashin_dir)?;
+		let total_resources = backend_state.resources()?.len() as u64;
+		let backend = Rc::new(RefCell::new(backend_state));
+		let state_lock =
+			BackendState::lock_guard(&backend, self.lock_timeout.map(Duration::from_secs))?;
+		let mut progress_manager = ProgressManager::new();
+
+		let http_client = HttpClient::new(
+			HttpCache::new(&mashin_dir.deps_folder_path()),
+			None,
+			true,
+			log::Level::Info,
+			Some(progress_manager.http_progress.clone()),
+		)?;
+
+		reconcile_journal(
+			&self.main_module,
+			&args,
+			backend.clone(),
+			state_lock.lock(),
+			&mashin_dir,
+			&http_client,
+			&passphrase,
+		)
+		.await?;
+
+		log::info!("    Starting the engine");
+
+		// the progress bar is only used once the providers get called
+		progress_manager.set_resource_progress(total_resources)?;
+
+		let BuiltEngine { engine, module_loader } = build_engine(
+			RuntimeCommand::Prepare,
+			&progress_manager,
+			None,
+			None,
+			backend.clone(),
+			state_lock.lock(),
+			mashin_dir.clone(),
+			http_client,
+			passphrase.as_bytes(),
+		)?;
+
+		// the main module is evaluated to register all providers
+		let mut runtime = Runtime::new(&self.main_module, engine, module_loader, args)?;
+		let runtime_result = runtime.destroy().await?;
+		let executed_resouces = runtime_result.executed_resources.borrow().clone();
+		let stored_outputs = runtime.stored_outputs()?;
+
+		executed_resouces.print_diff_plan();
+		// the outputs are removed with the resources
+		StackOutputs::default().print_changes(&stored_outputs);
+
+		if !self.dry_run &&
+			(!executed_resouces.actions().is_empty() || !stored_outputs.is_empty()) &&
+			Confirm::new()
+				.with_prompt("\n    Do you want to destroy all resources?")
+				.interact()?
+		{
+			log::info!("    Destroying resources");
+			runtime.delete_resources(&executed_resouces)?;
+			runtime.clear_outputs()?;
+			// nothing is read while destroying, the stored references are removed
+			runtime.save_stack_references()?;
+			Journal::new(&mashin_dir).clear()?;
+
+			let snapshot =
+				state::snapshot(&mashin_dir, &backend.borrow(), &passphrase, &self.main_module)?;
+			log::info!("    State recorded as snapshot {}", snapshot.id);
+		}
+
+		progress_manager.maybe_finish_resource_progress();
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+impl RefreshCmd {
+	pub async fn run(&self, args: Vec<String>) -> Result<()> {
+		write_to_stdout_ignore_sigpipe(
+			format!("\n\n{}\n", style(crate::MASHIN).bold()).as_bytes(),
+		)?;
+
+		let started = Instant::now();
+
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		if mashin_dir.workspace() != DEFAULT_WORKSPACE {
+			log::info!("    Using workspace {}", style(mashin_dir.workspace()).bold());
+		}
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		let backend_state = BackendState::new(&mashin_dir)?;
+		let total_resources = backend_state.resources()?.len() as u64;
+		let backend = Rc::new(RefCell::new(backend_state));
+		let state_lock =
+			BackendState::lock_guard(&backend, self.lock_timeout.map(Duration::from_secs))?;
+		let mut progress_manager = ProgressManager::new();
+
+		let http_client = HttpClient::new(
+			HttpCache::new(&mashin_dir.deps_folder_path()),
+			None,
+			true,
+			log::Level::Info,
+			Some(progress_manager.http_progress.clone()),
+		)?;
+
+		log::info!("    Starting the engine");
+
+		progress_manager.set_resource_progress(total_resources)?;
+
+		let BuiltEngine { engine, module_loader } = build_engine(
+			RuntimeCommand::Refresh,
+			&progress_manager,
+			None,
+			None,
+			backend.clone(),
+			state_lock.lock(),
+			mashin_dir.clone(),
+			http_client,
+			passphrase.as_bytes(),
+		)?;
+
+		log::info!("    Refreshing {} resources", total_resources);
+
+		let mut runtime = Runtime::new(&self.main_module, engine, module_loader, args)?;
+		let refreshed_resources = runtime.refresh().await?;
+
+		progress_manager.maybe_finish_resource_progress();
+
+		let refreshed_resources = refreshed_resources.borrow();
+
+		if refreshed_resources.drifted.is_empty() {
+			log::info!("\n    No changes made outside of Mashin");
+		} else {
+			log::info!("\nChanges made outside of Mashin:\n");
+			for (urn, executed_resource) in refreshed_resources.drifted.iter() {
+				if let Err(err) = executed_resource.print_diff(urn) {
+					log::trace!("{err}")
+				}
+			}
+		}
+
+		if !refreshed_resources.code_changes.is_empty() {
+			log::info!("\nChanges in the code, applied by `mashin run` only:\n");
+			for (urn, executed_resource) in refreshed_resources.code_changes.iter() {
+				if let Err(err) = executed_resource.print_diff(urn) {
+					log::trace!("{err}")
+				}
+			}
+		}
+
+		if !self.dry_run &&
+			!refreshed_resources.drifted.is_empty() &&
+			(self.yes ||
+				Confirm::new()
+					.with_prompt(
+						"\n    Do you want to update the state? The infrastructure is not modified",
+					)
+					.interact()?)
+		{
+			let total_refreshed = runtime.save_refreshed_states()?;
+			log::info!("    Refreshed {total_refreshed} resources");
+
+			let snapshot =
+				state::snapshot(&mashin_dir, &backend.borrow(), &passphrase, &self.main_module)?;
+			log::info!("    State recorded as snapshot {}", snapshot.id);
+		}
+
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+impl ImportCmd {
+	pub async fn run(&self, args: Vec<String>) -> Result<()> {
+		write_to_stdout_ignore_sigpipe(
+			format!("\n\n{}\n", style(crate::MASHIN).bold()).as_bytes(),
+		)?;
+
+		let started = Instant::now();
+
+		let mashin_dir = workspace::current(self.workspace.as_deref())?;
+		if mashin_dir.workspace() != DEFAULT_WORKSPACE {
+			log::info!("    Using workspace {}", style(mashin_dir.workspace()).bold());
+		}
+		let urn = state::parse_urn(&self.urn)?;
+		let passphrase = read_passphrase(self.key_file.as_deref())?;
+		let backend = Rc::new(RefCell::new(BackendState::new(&mashin_dir)?));
+		let state_lock =
+			BackendState::lock_guard(&backend, self.lock_timeout.map(Duration::from_secs))?;
+		let progress_manager = ProgressManager::new();
+
+		let http_client = HttpClient::new(
+			HttpCache::new(&mashin_dir.deps_folder_path()),
+			None,
+			true,
+			log::Level::Info,
+			Some(progress_manager.http_progress.clone()),
+		)?;
+
+		log::info!("    Starting the engine");
+
+		let BuiltEngine { engine, module_loader } = build_engine(
+			RuntimeCommand::Import { urn: urn.clone(), id: self.id.clone() },
+			&progress_manager,
+			None,
+			None,
+			backend.clone(),
+			state_lock.lock(),
+			mashin_dir.clone(),
+			http_client,
+			passphrase.as_bytes(),
+		)?;
+
+		log::info!("    Importing {} from `{}`", urn.as_display(), self.id);
+
+		// the main module is evaluated to register the providers and the config
+		// of the resource, only this resource is read by its provider
+		Runtime::new(&self.main_module, engine, module_loader, args)?.import().await?;
+
+		let snapshot =
+			state::snapshot(&mashin_dir, &backend.borrow(), &passphrase, &self.main_module)?;
+		log::info!("    State recorded as snapshot {}", snapshot.id);
+		log::info!("{} Done in {}", Emoji("✨ ", "* "), HumanDuration(started.elapsed()));
+
+		Ok(())
+	}
+}
+
+/// Offer to reconcile the state when the previous apply has been interrupted,
+/// the outcome recorded within the journal is saved and the resources without
+/// outcome are read back from their provider.
+async fn reconcile_journal(
+	main_module: &str,
+	args: &[String],
+	backend: Rc<RefCell<BackendState>>,
+	state_lock: &LockInfo,
+	mashin_dir: &MashinDir,
+	http_client: &HttpClient,
+	passphrase: &str,
+) -> Result<()> {
+	let journal = Journal::new(mashin_dir);
+	let pending = journal.pending()?;
+	if pending.is_empty() {
+		return Ok(())
+	}
+
+	log::info!("    The previous apply has been interrupted, these operations may not be saved:");
+	for (urn, operation) in &pending {
+		log::info!("      {} {}", operation.action.action_present_str(), urn.as_display());
+	}
+
+	if !Confirm::new()
+		.with_prompt("\n    Do you want to reconcile the state?")
+		.interact()?
+	{
+		bail!(
+			"the state may be missing resources, it has to be reconciled before applying changes"
+		);
+	}
+
+	// deleted resources are kept within the state until the provider succeed,
+	// the next apply deletes them again
+	let urns = journal
+		.reconcile_results(&backend.borrow())?
+		.into_iter()
+		.filter(|(_, operation)| operation.action != ResourceAction::Delete)
+		.map(|(urn, _)| urn)
+		.collect::<BTreeSet<_>>();
+
+	if !urns.is_empty() {
+		log::info!("    Reading back {} resources", urns.len());
+
+		let BuiltEngine { engine, module_loader } = build_engine(
+			RuntimeCommand::Reconcile { urns: urns.clone() },
+			&ProgressManager::new(),
+			None,
+			None,
+			backend.clone(),
+			state_lock,
+			mashin_dir.clone(),
+			http_client.clone(),
+			passphrase.as_bytes(),
+		)?;
+		Runtime::new(main_module, engine, module_loader, args.to_vec())?
+			.reconcile()
+			.await?;
+
+		for (urn, operation) in journal.pending()? {
+			if urns.contains(&urn) {
+				log::warn!(
+					"    Unable to read back {} ({}), use `mashin import` if it exists",
+					urn.as_display(),
+					operation.action.action_present_str().to_lowercase()
+				);
+			}
+		}
+	}
+
+	journal.clear()?;
+	log::info!("    State reconciled");
+
+	Ok(())
+}
+
 pub struct BuiltEngine {
 	engine: Rc<MashinEngine<Config>>,
 	module_loader: Rc<dyn deno_core::ModuleLoader>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn build_engine(
 	command: RuntimeCommand,
 	progress_manager: &ProgressManager,
 	executed_resources: Option<Rc<RefCell<ExecutedResources>>>,
 	maybe_resources_count: Option<u64>,
 	backend: Rc<RefCell<BackendState>>,
+	state_lock: &LockInfo,
 	mashin_dir: MashinDir,
 	http_client: HttpClient,
+	passphrase: &[u8],
 ) -> Result<BuiltEngine> {
 	let http_client_rc = Rc::new(http_client.clone());
 	let module_loader = Rc::new(TypescriptModuleLoader { http_client: Arc::new(http_client) });
 
 	let mashin_engine = MashinBuilder::<Config>::new()
-		.with_passphrase(b"mysuperpassword")
-		.with_salt(&[
-			0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
-			24, 25, 26, 27, 28, 29, 30, 31,
-		])
+		.with_passphrase(passphrase)
 		.with_mashin_dir(mashin_dir)
 		.with_state_handler(backend)
+		.with_state_lock(state_lock)
 		.with_runtime_command(command)
 		.with_executed_resources(executed_resources)
 		.with_progress_manager(Rc::new(progress_manager.clone()))
@@ -45,14 +45,23 @@ pub async fn main() -> Result<(), anyhow::Error> {
 	let cli = Cli::parse();
 
 	logger::init();
+	// recorded beside the state entries
+	mashin_runtime::set_client_version(version::mashin());
 
 	match cli.subcommand {
+		Subcommand::Apply(cmd) => cmd.run().await,
 		Subcommand::Bindgen(cmd) => cmd.run().await,
 		Subcommand::Doc(cmd) => cmd.run().await,
 		Subcommand::Destroy(cmd) => cmd.run(args).await,
+		Subcommand::Import(cmd) => cmd.run(args).await,
+		Subcommand::Output(cmd) => cmd.run().await,
+		Subcommand::Plan(cmd) => cmd.run(args).await,
+		Subcommand::Refresh(cmd) => cmd.run(args).await,
 		Subcommand::Run(cmd) => cmd.run(args).await,
+		Subcommand::State(cmd) => cmd.run().await,
 		Subcommand::Upgrade(cmd) => cmd.run().await,
 		Subcommand::Version(cmd) => cmd.run().await,
+		Subcommand::Workspace(cmd) => cmd.run().await,
 	}
 }
 
@@ -15,4 +15,7 @@
 
 pub mod bindgen;
 pub mod doc;
+pub mod policy;
+pub mod state;
 pub mod upgrade;
+pub mod workspace;
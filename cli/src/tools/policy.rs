@@ -0,0 +1,161 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::{http_client::HttpClient, module_loader::TypescriptModuleLoader, Result};
+use anyhow::bail;
+use console::style;
+use mashin_runtime::{Enforcement, ExecutedResources, PlanReport, PolicyRuntime, PolicyViolation};
+use std::{path::Path, rc::Rc, sync::Arc};
+
+/// Check the planned resources against the policies of the folder, fails when
+/// a mandatory policy is violated.
+pub async fn check(
+	policy_dir: &Path,
+	executed_resources: &ExecutedResources,
+	http_client: &HttpClient,
+) -> Result<()> {
+	enforce(&violations(policy_dir, executed_resources, http_client).await?)
+}
+
+/// Violations of the planned resources, the policies get the resources of the
+/// JSON plan with their sensitive values redacted.
+async fn violations(
+	policy_dir: &Path,
+	executed_resources: &ExecutedResources,
+	http_client: &HttpClient,
+) -> Result<Vec<PolicyViolation>> {
+	let module_loader =
+		Rc::new(TypescriptModuleLoader { http_client: Arc::new(http_client.clone()) });
+	let mut policy_runtime = PolicyRuntime::new(policy_dir, module_loader).await?;
+	policy_runtime.check(&PlanReport::new(executed_resources).resources)
+}
+
+/// Print the violations, fails when a mandatory policy is violated so the
+/// command exits with a non-zero code.
+fn enforce(violations: &[PolicyViolation]) -> Result<()> {
+	if violations.is_empty() {
+		log::info!("\n    All policies passed");
+		return Ok(())
+	}
+
+	log::info!("\nPolicy violations:\n");
+	for violation in violations {
+		let enforcement = match violation.enforcement {
+			Enforcement::Advisory => style("advisory").yellow().bold(),
+			Enforcement::Mandatory => style("mandatory").red().bold(),
+		};
+		log::info!(
+			"   {enforcement} [{}] {}: {}",
+			style(violation.urn.replace("urn:provider:", "")).bold(),
+			violation.policy,
+			violation.message
+		);
+	}
+
+	let total_mandatory = violations
+		.iter()
+		.filter(|violation| violation.enforcement == Enforcement::Mandatory)
+		.count();
+	if total_mandatory > 0 {
+		bail!("{total_mandatory} mandatory policy violations, the changes can't be applied");
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cache::HttpCache;
+	use deno_core::serde_json::json;
+	use mashin_runtime::{ExecutedResource, RawState};
+	use mashin_sdk::Urn;
+	use std::{fs, str::FromStr};
+
+	fn violation(enforcement: Enforcement) -> PolicyViolation {
+		PolicyViolation {
+			policy: "buckets.ts:privateBuckets".to_string(),
+			urn: "urn:provider:aws:s3:bucket?=logs".to_string(),
+			enforcement,
+			message: "buckets must be private".to_string(),
+		}
+	}
+
+	#[test]
+	fn only_mandatory_violations_fail() {
+		assert!(enforce(&[]).is_ok());
+		assert!(enforce(&[violation(Enforcement::Advisory)]).is_ok());
+
+		let err = enforce(&[violation(Enforcement::Advisory), violation(Enforcement::Mandatory)])
+			.unwrap_err();
+		assert_eq!(err.to_string(), "1 mandatory policy violations, the changes can't be applied");
+	}
+
+	#[tokio::test]
+	async fn mandatory_violations_stop_before_the_apply() {
+		let root = tempfile::tempdir().unwrap();
+		let policy_dir = root.path().join("policies");
+		fs::create_dir(&policy_dir).unwrap();
+		fs::write(
+			policy_dir.join("buckets.ts"),
+			r#"
+			export const privateBuckets = {
+				validate: (resource: any) =>
+					resource.config.acl === "public-read" ? "buckets must be private" : undefined,
+			};
+			export const redactedSecrets = {
+				enforcement: "advisory",
+				validate: (resource: any) =>
+					resource.config.password !== "[sensitive]" ? "the password is visible" : undefined,
+			};
+			"#,
+		)
+		.unwrap();
+
+		let state: RawState = json!({
+			"__config": {
+				"__value": { "acl": "public-read", "password": "hunter2" },
+				"__sensitive": false,
+			},
+			"acl": { "__value": "public-read", "__sensitive": false },
+			"password": { "__value": "hunter2", "__sensitive": true },
+		})
+		.into();
+		let mut executed_resources = ExecutedResources::default();
+		executed_resources.insert(
+			&Urn::from_str("urn:provider:aws:s3:bucket?=logs").unwrap(),
+			ExecutedResource::new("aws".to_string(), &RawState::default(), &state),
+		);
+		let http_client = HttpClient::new(
+			HttpCache::new(&root.path().join("deps")),
+			None,
+			true,
+			log::Level::Info,
+			None,
+		)
+		.unwrap();
+
+		// the policies only see the redacted password
+		let violations = violations(&policy_dir, &executed_resources, &http_client).await.unwrap();
+		assert_eq!(violations.len(), 1);
+		assert_eq!(violations[0].policy, "buckets.ts:privateBuckets");
+		assert_eq!(violations[0].urn, "urn:provider:aws:s3:bucket?=logs");
+		assert_eq!(violations[0].enforcement, Enforcement::Mandatory);
+
+		// `plan_changes` checks the policies before asking to apply
+		let err = check(&policy_dir, &executed_resources, &http_client).await.unwrap_err();
+		assert_eq!(err.to_string(), "1 mandatory policy violations, the changes can't be applied");
+	}
+}
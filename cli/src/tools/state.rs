@@ -0,0 +1,406 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::{version, Result};
+use anyhow::{anyhow, bail};
+use mashin_runtime::{
+	migrate_state, rekey_state, state_key, verify_key, BackendConfig, BackendState,
+	ExecutedResources, MashinDir, RawState, Snapshot, StackOutputs, StateEntryMetadata,
+	StateExport, StateOperation,
+};
+use mashin_sdk::Urn;
+use std::{cell::RefCell, rc::Rc, str::FromStr};
+
+/// Parse a resource URN, the `urn:provider:` prefix can be omitted as
+/// displayed by `mashin state list`.
+pub fn parse_urn(urn: &str) -> Result<Urn> {
+	if urn.starts_with("urn:") {
+		Urn::from_str(urn)
+	} else {
+		Urn::from_str(&format!("urn:provider:{urn}"))
+	}
+}
+
+/// All resources in the state, with their provider.
+pub fn list(mashin_dir: &MashinDir) -> Result<Vec<(String, Urn)>> {
+	BackendState::new(mashin_dir)?
+		.resources()?
+		.into_iter()
+		.map(|urn| Ok((urn.as_provider()?, urn)))
+		.collect()
+}
+
+/// Decrypted state of a resource.
+pub fn show(mashin_dir: &MashinDir, passphrase: &str, urn: &Urn) -> Result<RawState> {
+	let backend = BackendState::new(mashin_dir)?;
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&backend, &key)?;
+
+	backend
+		.get(urn)?
+		.ok_or_else(|| anyhow!("`{}` not found in the state", urn.as_display()))?
+		.decrypt(&key)
+}
+
+/// Metadata stored beside an entry, see `StateShowCmd::metadata`.
+pub fn entry_metadata(mashin_dir: &MashinDir, urn: &Urn) -> Result<StateEntryMetadata> {
+	BackendState::new(mashin_dir)?
+		.entry_metadata(urn)?
+		.ok_or_else(|| anyhow!("`{}` not found in the state", urn.as_display()))
+}
+
+/// Outputs saved by the last apply.
+pub fn outputs(mashin_dir: &MashinDir, passphrase: &str) -> Result<StackOutputs> {
+	let backend = BackendState::new(mashin_dir)?;
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&backend, &key)?;
+
+	StackOutputs::load(&backend, &key)
+}
+
+/// Forget a resource, the infrastructure is not modified.
+pub fn remove(mashin_dir: &MashinDir, urn: &Urn) -> Result<()> {
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+	let backend = backend.borrow();
+
+	if backend.get(urn)?.is_none() {
+		bail!("`{}` not found in the state", urn.as_display());
+	}
+
+	backend.delete(urn)
+}
+
+/// Move the state of a resource to a new URN of the same resource type.
+pub fn move_entry(mashin_dir: &MashinDir, passphrase: &str, from: &Urn, to: &Urn) -> Result<()> {
+	if from.nss() != to.nss() {
+		bail!("`{}` and `{}` are not the same resource type", from.as_display(), to.as_display());
+	}
+
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+	let backend = backend.borrow();
+
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&backend, &key)?;
+
+	if backend.get(to)?.is_some() {
+		bail!("`{}` already exists in the state", to.as_display());
+	}
+
+	let mut state = backend
+		.get(from)?
+		.ok_or_else(|| anyhow!("`{}` not found in the state", from.as_display()))?
+		.decrypt(&key)?;
+	state.set_urn(to);
+
+	backend.apply_batch(vec![
+		StateOperation::Save(to.clone(), state.encrypt(&key)?),
+		StateOperation::Delete(from.clone()),
+	])
+}
+
+/// Re-encrypt the whole state with the new passphrase.
+pub fn rekey(mashin_dir: &MashinDir, passphrase: &str, new_passphrase: &str) -> Result<usize> {
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+	let backend = backend.borrow();
+	rekey_state(mashin_dir, &backend, passphrase.as_bytes(), new_passphrase.as_bytes())
+}
+
+/// Remove the state lock, whoever holds it.
+pub fn force_unlock(mashin_dir: &MashinDir) -> Result<()> {
+	BackendState::new(mashin_dir)?.force_unlock()
+}
+
+/// Record the current state in the history.
+pub fn snapshot(
+	mashin_dir: &MashinDir,
+	backend: &BackendState,
+	passphrase: &str,
+	main_module: &str,
+) -> Result<Snapshot> {
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	Snapshot::create(mashin_dir, backend, &key, version::mashin(), main_module)
+}
+
+/// Changes of the resources between two snapshots.
+pub fn diff_snapshots(
+	mashin_dir: &MashinDir,
+	passphrase: &str,
+	from: u64,
+	to: u64,
+) -> Result<ExecutedResources> {
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	let from = Snapshot::load(mashin_dir, from)?;
+	let to = Snapshot::load(mashin_dir, to)?;
+	from.diff(&to, &key)
+}
+
+/// Restore the state recorded in the snapshot, the restored state is
+/// recorded as a new snapshot.
+pub fn rollback(mashin_dir: &MashinDir, passphrase: &str, id: u64) -> Result<(usize, Snapshot)> {
+	let snapshot = Snapshot::load(mashin_dir, id)?;
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+	let backend = backend.borrow();
+
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&backend, &key)?;
+
+	let total_entries = snapshot.restore(&backend, &key)?;
+	let new_snapshot =
+		Snapshot::create(mashin_dir, &backend, &key, version::mashin(), &snapshot.main_module)?;
+
+	Ok((total_entries, new_snapshot))
+}
+
+/// Export every entry of the state, decrypted with `plaintext`.
+pub fn export(mashin_dir: &MashinDir, passphrase: &str, plaintext: bool) -> Result<StateExport> {
+	let backend = BackendState::new(mashin_dir)?;
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&backend, &key)?;
+
+	StateExport::create(mashin_dir, &backend, plaintext.then_some(&key), version::mashin())
+}
+
+/// Load an export into the state, existing entries are only overwritten
+/// with `force`.
+pub fn import(
+	mashin_dir: &MashinDir,
+	passphrase: &str,
+	export: &StateExport,
+	force: bool,
+) -> Result<usize> {
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+	let backend = backend.borrow();
+
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&backend, &key)?;
+
+	export.import(&backend, passphrase.as_bytes(), &key, force)
+}
+
+/// Copy the whole state from a backend to another.
+pub fn migrate(
+	mashin_dir: &MashinDir,
+	passphrase: &str,
+	from: &BackendConfig,
+	to: &BackendConfig,
+	force: bool,
+	delete_source: bool,
+) -> Result<usize> {
+	let source = Rc::new(RefCell::new(BackendState::from_config(mashin_dir, from)?));
+	let _source_lock = BackendState::lock_guard(&source, None)?;
+	let target = Rc::new(RefCell::new(BackendState::from_config(mashin_dir, to)?));
+	let _target_lock = BackendState::lock_guard(&target, None)?;
+	let source = source.borrow();
+	let target = target.borrow();
+
+	let key = state_key(mashin_dir, passphrase.as_bytes())?;
+	verify_key(&source, &key)?;
+
+	migrate_state(&source, &target, &key, force, delete_source)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tools::workspace;
+	use deno_core::serde_json::{self, json};
+	use tempfile::TempDir;
+
+	const PASSPHRASE: &str = "passphrase";
+
+	fn project() -> (TempDir, MashinDir) {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		(root, mashin_dir)
+	}
+
+	fn save(mashin_dir: &MashinDir, urn: &str, size: u64) -> Urn {
+		let urn = parse_urn(urn).unwrap();
+		let backend = BackendState::new(mashin_dir).unwrap();
+		let key = state_key(mashin_dir, PASSPHRASE.as_bytes()).unwrap();
+		verify_key(&backend, &key).unwrap();
+
+		let mut state =
+			RawState::from(json!({ "size": { "__value": size, "__sensitive": false } }));
+		state.set_urn(&urn);
+		backend.save(&urn, &state.encrypt(&key).unwrap()).unwrap();
+		urn
+	}
+
+	fn size(mashin_dir: &MashinDir, urn: &Urn) -> u64 {
+		show(mashin_dir, PASSPHRASE, urn).unwrap().inner()["size"]["__value"]
+			.as_u64()
+			.unwrap()
+	}
+
+	#[test]
+	fn resources_are_listed_shown_and_removed() {
+		let (_root, mashin_dir) = project();
+		let logs = save(&mashin_dir, "aws:s3:bucket?=logs", 1);
+		let assets = save(&mashin_dir, "urn:provider:aws:s3:bucket?=assets", 2);
+
+		let resources = list(&mashin_dir).unwrap();
+		assert_eq!(resources.len(), 2);
+		assert!(resources.iter().all(|(provider, _)| provider == "aws"));
+		assert_eq!(size(&mashin_dir, &logs), 1);
+		assert_eq!(size(&mashin_dir, &assets), 2);
+
+		remove(&mashin_dir, &logs).unwrap();
+		assert_eq!(list(&mashin_dir).unwrap(), vec![("aws".to_string(), assets)]);
+
+		let err = remove(&mashin_dir, &logs).unwrap_err();
+		assert_eq!(err.to_string(), "`aws:s3:bucket?=logs` not found in the state");
+		let err = show(&mashin_dir, PASSPHRASE, &logs).unwrap_err();
+		assert_eq!(err.to_string(), "`aws:s3:bucket?=logs` not found in the state");
+	}
+
+	#[test]
+	fn moved_entries_keep_their_state() {
+		let (_root, mashin_dir) = project();
+		let logs = save(&mashin_dir, "aws:s3:bucket?=logs", 1);
+		let assets = save(&mashin_dir, "aws:s3:bucket?=assets", 2);
+		let archive = parse_urn("aws:s3:bucket?=archive").unwrap();
+
+		move_entry(&mashin_dir, PASSPHRASE, &logs, &archive).unwrap();
+		assert_eq!(size(&mashin_dir, &archive), 1);
+		let moved = show(&mashin_dir, PASSPHRASE, &archive).unwrap();
+		assert_eq!(moved.inner()["__urn"]["__value"], json!(archive.to_string()));
+		assert!(show(&mashin_dir, PASSPHRASE, &logs).is_err());
+
+		// the existing entry is never overwritten
+		let err = move_entry(&mashin_dir, PASSPHRASE, &archive, &assets).unwrap_err();
+		assert_eq!(err.to_string(), "`aws:s3:bucket?=assets` already exists in the state");
+		assert_eq!(size(&mashin_dir, &archive), 1);
+		assert_eq!(size(&mashin_dir, &assets), 2);
+
+		let err = move_entry(&mashin_dir, PASSPHRASE, &logs, &archive).unwrap_err();
+		assert_eq!(err.to_string(), "`aws:s3:bucket?=archive` already exists in the state");
+
+		let queue = parse_urn("aws:sqs:queue?=logs").unwrap();
+		let err = move_entry(&mashin_dir, PASSPHRASE, &archive, &queue).unwrap_err();
+		assert!(err.to_string().ends_with("are not the same resource type"), "{err}");
+	}
+
+	#[test]
+	fn workspaces_have_their_own_state() {
+		let (_root, mashin_dir) = project();
+		let logs = save(&mashin_dir, "aws:s3:bucket?=logs", 1);
+
+		let staging = workspace::create(&mashin_dir, "staging").unwrap();
+		assert!(list(&staging).unwrap().is_empty());
+		assert!(remove(&staging, &logs).is_err());
+
+		save(&staging, "aws:s3:bucket?=logs", 2);
+		assert_eq!(size(&staging, &logs), 2);
+		assert_eq!(size(&mashin_dir, &logs), 1);
+
+		let staging = workspace::open(&mashin_dir, "staging").unwrap();
+		remove(&staging, &logs).unwrap();
+		assert_eq!(size(&mashin_dir, &logs), 1);
+	}
+
+	#[test]
+	fn exports_are_imported_into_another_project() {
+		let (_root, mashin_dir) = project();
+		let logs = save(&mashin_dir, "aws:s3:bucket?=logs", 1);
+		let assets = save(&mashin_dir, "aws:s3:bucket?=assets", 2);
+
+		// the encrypted export is decrypted with the passphrase and the salt
+		// of the exported project
+		let (_target_root, target) = project();
+		let encrypted =
+			serde_json::to_vec(&export(&mashin_dir, PASSPHRASE, false).unwrap()).unwrap();
+		let encrypted = StateExport::from_slice(&encrypted).unwrap();
+		assert_eq!(import(&target, PASSPHRASE, &encrypted, false).unwrap(), 2);
+		assert_eq!(list(&target).unwrap(), list(&mashin_dir).unwrap());
+		assert_eq!(size(&target, &logs), 1);
+		assert_eq!(size(&target, &assets), 2);
+
+		let (_other_root, other) = project();
+		let err = import(&other, "another passphrase", &encrypted, false).unwrap_err();
+		assert_eq!(err.to_string(), "invalid passphrase, unable to decrypt the state export");
+		assert!(list(&other).unwrap().is_empty());
+
+		let plaintext =
+			serde_json::to_vec(&export(&mashin_dir, PASSPHRASE, true).unwrap()).unwrap();
+		let plaintext = StateExport::from_slice(&plaintext).unwrap();
+		assert_eq!(import(&other, "another passphrase", &plaintext, false).unwrap(), 2);
+		let imported = show(&other, "another passphrase", &logs).unwrap();
+		assert_eq!(imported.inner(), show(&mashin_dir, PASSPHRASE, &logs).unwrap().inner());
+	}
+
+	#[test]
+	fn existing_entries_are_only_overwritten_with_force() {
+		let (_root, mashin_dir) = project();
+		let logs = save(&mashin_dir, "aws:s3:bucket?=logs", 1);
+		let state_export = export(&mashin_dir, PASSPHRASE, false).unwrap();
+
+		let (_target_root, target) = project();
+		save(&target, "aws:s3:bucket?=logs", 2);
+		let assets = save(&target, "aws:s3:bucket?=assets", 3);
+
+		let err = import(&target, PASSPHRASE, &state_export, false).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"aws:s3:bucket?=logs already exist in the state, use `--force` to overwrite them"
+		);
+		assert_eq!(size(&target, &logs), 2);
+
+		assert_eq!(import(&target, PASSPHRASE, &state_export, true).unwrap(), 1);
+		assert_eq!(size(&target, &logs), 1);
+		assert_eq!(size(&target, &assets), 3);
+	}
+
+	#[test]
+	fn malformed_exports_are_rejected() {
+		let err = StateExport::from_slice(b"{ \"version\": 1 }").unwrap_err();
+		assert!(err.to_string().starts_with("invalid state export;"), "{err}");
+
+		let raw_export = |version: u32, urn: &str| {
+			json!({
+				"version": version,
+				"createdAt": "2023-06-01T00:00:00+00:00",
+				"mashinVersion": "0.1.0",
+				"format": "plaintext",
+				"entries": {
+					"urn:provider:aws:s3:bucket?=logs": { "size": { "__value": 1, "__sensitive": false } },
+					urn: { "size": { "__value": 2, "__sensitive": false } },
+				},
+			})
+			.to_string()
+		};
+
+		let err =
+			StateExport::from_slice(raw_export(2, "urn:provider:aws:s3:bucket?=assets").as_bytes())
+				.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"state export version 2 is not supported, upgrade mashin to import it"
+		);
+
+		// nothing is imported when one of the entries is invalid
+		let (_root, mashin_dir) = project();
+		let state_export =
+			StateExport::from_slice(raw_export(1, "aws-s3-bucket").as_bytes()).unwrap();
+		let err = import(&mashin_dir, PASSPHRASE, &state_export, false).unwrap_err();
+		assert!(err.to_string().starts_with("invalid urn `aws-s3-bucket` in export;"), "{err}");
+		assert!(list(&mashin_dir).unwrap().is_empty());
+	}
+}
@@ -0,0 +1,185 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::Result;
+use anyhow::bail;
+use mashin_runtime::{BackendState, MashinDir, StateOperation, DEFAULT_WORKSPACE};
+use std::fs;
+
+/// Workspace names are used as folder names and within the remote backends.
+fn validate_name(name: &str) -> Result<()> {
+	if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+		bail!("invalid workspace name `{name}`, only letters, digits, `-` and `_` are allowed");
+	}
+	Ok(())
+}
+
+fn exists(mashin_dir: &MashinDir, name: &str) -> bool {
+	name == DEFAULT_WORKSPACE || mashin_dir.workspaces_folder_path().join(name).is_dir()
+}
+
+/// All workspaces of the project, starting with the default one.
+pub fn list(mashin_dir: &MashinDir) -> Result<Vec<String>> {
+	let mut workspaces = Vec::new();
+	let workspaces_path = mashin_dir.workspaces_folder_path();
+
+	if workspaces_path.exists() {
+		for entry in fs::read_dir(workspaces_path)? {
+			let entry = entry?;
+			if entry.file_type()?.is_dir() {
+				workspaces.push(entry.file_name().to_string_lossy().to_string());
+			}
+		}
+	}
+
+	workspaces.sort();
+	workspaces.insert(0, DEFAULT_WORKSPACE.to_string());
+	Ok(workspaces)
+}
+
+/// Use an existing workspace instead of the selected one.
+pub fn open(mashin_dir: &MashinDir, name: &str) -> Result<MashinDir> {
+	validate_name(name)?;
+	if !exists(mashin_dir, name) {
+		bail!("workspace `{name}` not found, run `mashin workspace new {name}`");
+	}
+	Ok(mashin_dir.with_workspace(name))
+}
+
+/// Create a workspace with an empty state.
+pub fn create(mashin_dir: &MashinDir, name: &str) -> Result<MashinDir> {
+	validate_name(name)?;
+	if exists(mashin_dir, name) {
+		bail!("workspace `{name}` already exists");
+	}
+	fs::create_dir_all(mashin_dir.workspaces_folder_path().join(name))?;
+	Ok(mashin_dir.with_workspace(name))
+}
+
+/// Select the workspace used by the next commands.
+pub fn select(mashin_dir: &MashinDir, name: &str) -> Result<()> {
+	open(mashin_dir, name)?;
+	fs::write(mashin_dir.workspace_file_path(), name)?;
+	Ok(())
+}
+
+/// Delete a workspace and its state, it can't be the selected one and its
+/// state must be empty unless `force` is set.
+pub fn delete(mashin_dir: &MashinDir, name: &str, force: bool) -> Result<()> {
+	let workspace_dir = open(mashin_dir, name)?;
+
+	if name == DEFAULT_WORKSPACE {
+		bail!("the default workspace can't be deleted");
+	}
+	if mashin_dir.workspace() == name {
+		bail!("workspace `{name}` is selected, select another workspace first");
+	}
+
+	let backend = BackendState::new(&workspace_dir)?;
+	let total_resources = backend.resources()?.len();
+	if total_resources > 0 && !force {
+		bail!(
+			"workspace `{name}` still manages {total_resources} resources, destroy them first or use `--force`"
+		);
+	}
+
+	// remote backends keep the entries outside of the workspace folder
+	backend.apply_batch(backend.entries()?.into_iter().map(StateOperation::Delete).collect())?;
+	drop(backend);
+
+	fs::remove_dir_all(mashin_dir.workspaces_folder_path().join(name))?;
+	Ok(())
+}
+
+/// Project directory with the selected workspace, or with `maybe_name`
+/// when the workspace is given to the command.
+pub fn current(maybe_name: Option<&str>) -> Result<MashinDir> {
+	let mashin_dir = MashinDir::new(None)?;
+	match maybe_name {
+		Some(name) => open(&mashin_dir, name),
+		None => Ok(mashin_dir),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use deno_core::serde_json::json;
+	use mashin_runtime::{state_key, verify_key, RawState};
+	use mashin_sdk::Urn;
+	use std::str::FromStr;
+
+	fn save(mashin_dir: &MashinDir, urn: &Urn) {
+		let backend = BackendState::new(mashin_dir).unwrap();
+		let key = state_key(mashin_dir, b"passphrase").unwrap();
+		verify_key(&backend, &key).unwrap();
+		let state = RawState::from(json!({ "size": { "__value": 1, "__sensitive": false } }));
+		backend.save(urn, &state.encrypt(&key).unwrap()).unwrap();
+	}
+
+	fn resources(mashin_dir: &MashinDir) -> Vec<Urn> {
+		BackendState::new(mashin_dir)
+			.unwrap()
+			.resources()
+			.unwrap()
+			.into_iter()
+			.collect()
+	}
+
+	#[test]
+	fn workspaces_have_isolated_states() {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		let logs = Urn::from_str("urn:provider:aws:s3:bucket?=logs").unwrap();
+		let assets = Urn::from_str("urn:provider:aws:s3:bucket?=assets").unwrap();
+		save(&mashin_dir, &logs);
+
+		let staging = create(&mashin_dir, "staging").unwrap();
+		assert!(create(&mashin_dir, "staging").is_err());
+		assert!(create(&mashin_dir, "../prod").is_err());
+		assert!(resources(&staging).is_empty());
+		save(&staging, &assets);
+		assert_eq!(list(&mashin_dir).unwrap(), vec!["default", "staging"]);
+
+		// the selected workspace is used by the next commands
+		select(&mashin_dir, "staging").unwrap();
+		let selected = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		assert_eq!(selected.workspace(), "staging");
+		assert_eq!(resources(&selected), vec![assets.clone()]);
+		assert_eq!(resources(&mashin_dir), vec![logs.clone()]);
+
+		let err = delete(&selected, "staging", false).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"workspace `staging` is selected, select another workspace first"
+		);
+		select(&selected, DEFAULT_WORKSPACE).unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		assert_eq!(mashin_dir.workspace(), DEFAULT_WORKSPACE);
+
+		let err = delete(&mashin_dir, "staging", false).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"workspace `staging` still manages 1 resources, destroy them first or use `--force`"
+		);
+		delete(&mashin_dir, "staging", true).unwrap();
+		assert!(open(&mashin_dir, "staging").is_err());
+		assert_eq!(list(&mashin_dir).unwrap(), vec![DEFAULT_WORKSPACE]);
+		assert_eq!(resources(&mashin_dir), vec![logs]);
+
+		// a new workspace with the same name starts empty
+		assert!(resources(&create(&mashin_dir, "staging").unwrap()).is_empty());
+	}
+}
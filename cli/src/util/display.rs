@@ -28,7 +28,6 @@ pub fn write_to_stdout_ignore_sigpipe(bytes: &[u8]) -> Result<(), std::io::Error
 	}
 }
 
-#[allow(dead_code)]
 pub fn write_json_to_stdout<T>(value: &T) -> Result<(), AnyError>
 where
 	T: ?Sized + serde::ser::Serialize,
@@ -16,5 +16,6 @@
 pub mod display;
 pub mod file;
 pub mod glue;
+pub mod passphrase;
 pub mod print_diff;
 pub mod time;
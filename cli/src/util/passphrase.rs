@@ -0,0 +1,80 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::Result;
+use anyhow::{anyhow, bail};
+use dialoguer::Password;
+use mashin_runtime::{run_key_command, MashinDir, ProjectConfig};
+use std::{env, fs, path::Path};
+
+pub const PASSPHRASE_ENV: &str = "MASHIN_PASSPHRASE";
+pub const NEW_PASSPHRASE_ENV: &str = "MASHIN_NEW_PASSPHRASE";
+
+/// Resolve the passphrase used to encrypt the state, from the key file when
+/// provided, then from the key command of the project, the `MASHIN_PASSPHRASE`
+/// env variable and finally by prompting the user.
+pub fn read_passphrase(maybe_key_file: Option<&Path>) -> Result<String> {
+	let key_command = ProjectConfig::load(&MashinDir::new(None)?)?.key_command;
+	let passphrase = if let Some(key_file) = maybe_key_file {
+		read_key_file(key_file)?
+	} else if let Some(key_command) = key_command {
+		run_key_command(&key_command)?
+	} else if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+		passphrase
+	} else if atty::is(atty::Stream::Stdin) {
+		Password::new().with_prompt("    State passphrase").interact()?
+	} else {
+		bail!("no passphrase provided, use the `{PASSPHRASE_ENV}` env variable or `--key-file`")
+	};
+
+	if passphrase.is_empty() {
+		bail!("the state passphrase cannot be empty")
+	}
+
+	Ok(passphrase)
+}
+
+/// Resolve the new passphrase when the state key is rotated, from the key file
+/// when provided, then from the `MASHIN_NEW_PASSPHRASE` env variable and
+/// finally by prompting the user with a confirmation.
+pub fn read_new_passphrase(maybe_key_file: Option<&Path>) -> Result<String> {
+	let passphrase = if let Some(key_file) = maybe_key_file {
+		read_key_file(key_file)?
+	} else if let Ok(passphrase) = env::var(NEW_PASSPHRASE_ENV) {
+		passphrase
+	} else if atty::is(atty::Stream::Stdin) {
+		Password::new()
+			.with_prompt("    New state passphrase")
+			.with_confirmation("    Confirm new state passphrase", "Passphrases mismatching")
+			.interact()?
+	} else {
+		bail!(
+			"no new passphrase provided, use the `{NEW_PASSPHRASE_ENV}` env variable or `--new-key-file`"
+		)
+	};
+
+	if passphrase.is_empty() {
+		bail!("the state passphrase cannot be empty")
+	}
+
+	Ok(passphrase)
+}
+
+fn read_key_file(key_file: &Path) -> Result<String> {
+	Ok(fs::read_to_string(key_file)
+		.map_err(|err| anyhow!("unable to read key file `{}`; {err}", key_file.display()))?
+		.trim_end_matches(&['\n', '\r'][..])
+		.to_string())
+}
@@ -13,44 +13,393 @@
  *                                                          *
 \* ---------------------------------------------------------*/
 
-use crate::{mashin_dir::MashinDir, EncryptedState, FileState, Result, StateHandler, StateInner};
-use mashin_sdk::Urn;
-use std::collections::BTreeSet;
+#[cfg(feature = "sqlite")]
+use crate::state::SqliteState;
+use crate::{
+	mashin_dir::{MashinDir, DEFAULT_WORKSPACE},
+	state::{
+		HttpState, HttpStateConfig, PluginStateConfig, S3State, S3StateConfig, StateInner,
+		ENGINE_NID,
+	},
+	EncryptedState, FileState, ProjectConfig, Result, StateEntryMetadata, StateHandler,
+	StateOperation,
+};
+use anyhow::{anyhow, bail};
+use mashin_sdk::{LockInfo, Urn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+	cell::RefCell,
+	collections::BTreeSet,
+	fs,
+	path::Path,
+	rc::Rc,
+	str::FromStr,
+	thread,
+	time::{Duration, Instant},
+};
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Version of the client writing the state, see [`set_client_version`]
+static CLIENT_VERSION: OnceCell<String> = OnceCell::new();
+
+/// Set the version of the client recorded beside the state entries, eg; by
+/// the SQLite backend. It can only be set once, default to the version of
+/// `mashin_core`.
+pub fn set_client_version(version: &str) {
+	let _ = CLIENT_VERSION.set(version.to_string());
+}
+
+pub(crate) fn client_version() -> &'static str {
+	CLIENT_VERSION.get().map(String::as_str).unwrap_or(env!("CARGO_PKG_VERSION"))
+}
+
+/// State backend selected in the project config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendConfig {
+	/// Local rkv store within `.mashin/state`
+	#[default]
+	Local,
+	/// Remote REST server, see [`HttpState`]
+	Http(HttpStateConfig),
+	/// S3-compatible bucket, see [`S3State`]
+	S3(S3StateConfig),
+	/// Local SQLite database within `.mashin/state.db`, requires the `sqlite`
+	/// feature
+	Sqlite,
+	/// Backend loaded from a cdylib, see [`StateInner`]
+	Plugin(PluginStateConfig),
+}
+
+impl BackendConfig {
+	/// Whether the salt and the recipients are stored within the backend,
+	/// instead of `.mashin/`.
+	pub fn shares_metadata(&self) -> bool {
+		matches!(self, BackendConfig::Http(_) | BackendConfig::S3(_))
+	}
+}
+
+impl FromStr for BackendConfig {
+	type Err = anyhow::Error;
+
+	/// Parse a backend config from a JSON file, an inline JSON object or a
+	/// backend type without options (`local`).
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let raw_config = if Path::new(s).is_file() {
+			fs::read_to_string(s)?
+		} else if s.trim_start().starts_with('{') {
+			s.to_string()
+		} else {
+			serde_json::json!({ "type": s }).to_string()
+		};
+
+		serde_json::from_str(&raw_config)
+			.map_err(|err| anyhow!("invalid backend config `{s}`; {err}"))
+	}
+}
 
 pub enum BackendState {
 	Local(FileState),
+	Http(HttpState),
+	S3(S3State),
+	#[cfg(feature = "sqlite")]
+	Sqlite(SqliteState),
 	Plugin(StateInner),
 }
 
 impl BackendState {
+	/// Open the backend configured for the project.
 	pub fn new(mashin_dir: &MashinDir) -> Result<Self> {
-		Ok(Self::Local(FileState::new(mashin_dir.state_folder_path())?))
+		let config = ProjectConfig::load(mashin_dir)?;
+		Self::from_config(mashin_dir, &config.backend)
+	}
+
+	/// Open the backend of an existing project, eg; a stack reference. The
+	/// local backends fail if the state doesn't exist instead of creating it.
+	pub fn open_existing(mashin_dir: &MashinDir) -> Result<Self> {
+		let config = ProjectConfig::load(mashin_dir)?.backend;
+
+		Ok(match config {
+			BackendConfig::Local => Self::Local(FileState::open(mashin_dir.state_folder_path())?),
+			#[cfg(feature = "sqlite")]
+			BackendConfig::Sqlite =>
+				Self::Sqlite(SqliteState::open_read_only(mashin_dir.state_db_file_path())?),
+			config => Self::from_config(mashin_dir, &config)?,
+		})
+	}
+
+	/// Acquire the state lock, it is released when the guard is dropped.
+	///
+	/// Retry until the `timeout` (default to the timeout of the backend config)
+	/// if the state is already locked.
+	pub fn lock_guard(
+		backend: &Rc<RefCell<Self>>,
+		timeout: Option<Duration>,
+	) -> Result<StateLockGuard> {
+		let lock = LockInfo::current();
+		let timeout = timeout.unwrap_or_else(|| backend.borrow().lock_timeout());
+		let started = Instant::now();
+		let mut is_waiting = false;
+
+		loop {
+			let holder = match backend.borrow().try_lock(&lock)? {
+				Some(holder) => holder,
+				None => break,
+			};
+
+			if started.elapsed() >= timeout {
+				bail!(
+					"the state is locked by {holder}, run `mashin state unlock --force` if the lock is stale"
+				);
+			}
+
+			if !is_waiting {
+				log::info!("    Waiting for the state lock held by {holder}");
+				is_waiting = true;
+			}
+			thread::sleep(LOCK_RETRY_INTERVAL);
+		}
+
+		Ok(StateLockGuard { backend: backend.clone(), lock })
+	}
+
+	/// Replace the backend of a state locked with `lock`, eg; by the backend
+	/// selected within the script. The lock is acquired on the new backend
+	/// before it is released from the current one, so the [`StateLockGuard`]
+	/// releases it from the new backend.
+	pub fn replace(&mut self, new_backend: Self, maybe_lock: Option<&LockInfo>) -> Result<()> {
+		if let Some(lock) = maybe_lock {
+			if let Some(holder) = new_backend.try_lock(lock)? {
+				bail!(
+					"the state is locked by {holder}, run `mashin state unlock --force` if the lock is stale"
+				);
+			}
+
+			if let Err(err) = self.unlock(lock) {
+				new_backend.unlock(lock)?;
+				return Err(err)
+			}
+		}
+
+		*self = new_backend;
+		Ok(())
+	}
+
+	/// Open the backend for the workspace of `mashin_dir`, the remote
+	/// backends store the other workspaces under `workspaces/{name}`.
+	pub fn from_config(mashin_dir: &MashinDir, config: &BackendConfig) -> Result<Self> {
+		let workspace = mashin_dir.workspace();
+		let is_default_workspace = workspace == DEFAULT_WORKSPACE;
+
+		Ok(match config {
+			BackendConfig::Local => Self::Local(FileState::new(mashin_dir.state_folder_path())?),
+			BackendConfig::Http(config) if is_default_workspace =>
+				Self::Http(HttpState::new(config)?),
+			BackendConfig::Http(config) => Self::Http(HttpState::new(&HttpStateConfig {
+				url: format!("{}/workspaces/{workspace}", config.url.trim_end_matches('/')),
+				..config.clone()
+			})?),
+			BackendConfig::S3(config) if is_default_workspace => Self::S3(S3State::new(config)?),
+			BackendConfig::S3(config) => Self::S3(S3State::new(&S3StateConfig {
+				prefix: format!("{}workspaces/{workspace}/", config.prefix),
+				..config.clone()
+			})?),
+			#[cfg(feature = "sqlite")]
+			BackendConfig::Sqlite => Self::Sqlite(SqliteState::new(mashin_dir.state_db_file_path())?),
+			#[cfg(not(feature = "sqlite"))]
+			BackendConfig::Sqlite => bail!(
+				"the sqlite backend is not available, mashin is built without the `sqlite` feature"
+			),
+			BackendConfig::Plugin(config) => Self::Plugin(StateInner::open(config, workspace)?),
+		})
 	}
 	pub fn save(&self, urn: &Urn, state: &EncryptedState) -> Result<()> {
 		match self {
 			BackendState::Local(local) => local.save(urn, state),
-			BackendState::Plugin(_) => todo!(),
+			BackendState::Http(http) => http.save(urn, state),
+			BackendState::S3(s3) => s3.save(urn, state),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.save(urn, state),
+			BackendState::Plugin(plugin) => plugin.save(urn, state),
 		}
 	}
 
 	pub fn get(&self, urn: &Urn) -> Result<Option<EncryptedState>> {
 		match self {
 			BackendState::Local(local) => local.get(urn),
-			BackendState::Plugin(_) => todo!(),
+			BackendState::Http(http) => http.get(urn),
+			BackendState::S3(s3) => s3.get(urn),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.get(urn),
+			BackendState::Plugin(plugin) => plugin.get(urn),
 		}
 	}
 
 	pub fn resources(&self) -> Result<BTreeSet<Urn>> {
+		// entries managed by the engine are not resources
+		Ok(self.entries()?.into_iter().filter(|urn| urn.nid() != ENGINE_NID).collect())
+	}
+
+	/// All entries of the state, including the ones managed by the engine.
+	pub fn entries(&self) -> Result<BTreeSet<Urn>> {
 		match self {
 			BackendState::Local(local) => local.resources(),
-			BackendState::Plugin(_) => todo!(),
+			BackendState::Http(http) => http.resources(),
+			BackendState::S3(s3) => s3.resources(),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.resources(),
+			BackendState::Plugin(plugin) => plugin.resources(),
+		}
+	}
+
+	pub fn apply_batch(&self, operations: Vec<StateOperation>) -> Result<()> {
+		match self {
+			BackendState::Local(local) => local.apply_batch(operations),
+			BackendState::Http(http) => http.apply_batch(operations),
+			BackendState::S3(s3) => s3.apply_batch(operations),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.apply_batch(operations),
+			BackendState::Plugin(plugin) => plugin.apply_batch(operations),
 		}
 	}
 
 	pub fn delete(&self, urn: &Urn) -> Result<()> {
 		match self {
 			BackendState::Local(local) => local.delete(urn),
-			BackendState::Plugin(_) => todo!(),
+			BackendState::Http(http) => http.delete(urn),
+			BackendState::S3(s3) => s3.delete(urn),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.delete(urn),
+			BackendState::Plugin(plugin) => plugin.delete(urn),
+		}
+	}
+
+	pub fn try_lock(&self, lock: &LockInfo) -> Result<Option<LockInfo>> {
+		match self {
+			BackendState::Local(local) => local.try_lock(lock),
+			BackendState::Http(http) => http.try_lock(lock),
+			BackendState::S3(s3) => s3.try_lock(lock),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.try_lock(lock),
+			BackendState::Plugin(plugin) => plugin.try_lock(lock),
 		}
 	}
+
+	pub fn unlock(&self, lock: &LockInfo) -> Result<()> {
+		match self {
+			BackendState::Local(local) => local.unlock(lock),
+			BackendState::Http(http) => http.unlock(lock),
+			BackendState::S3(s3) => s3.unlock(lock),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.unlock(lock),
+			BackendState::Plugin(plugin) => plugin.unlock(lock),
+		}
+	}
+
+	pub fn force_unlock(&self) -> Result<()> {
+		match self {
+			BackendState::Local(local) => local.force_unlock(),
+			BackendState::Http(http) => http.force_unlock(),
+			BackendState::S3(s3) => s3.force_unlock(),
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.force_unlock(),
+			BackendState::Plugin(plugin) => plugin.force_unlock(),
+		}
+	}
+
+	/// Metadata stored beside an entry, only the SQLite backend stores them.
+	pub fn entry_metadata(&self, urn: &Urn) -> Result<Option<StateEntryMetadata>> {
+		match self {
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(sqlite) => sqlite.entry_metadata(urn),
+			_ =>
+				bail!("the state backend doesn't store the entry metadata, use the sqlite backend"),
+		}
+	}
+
+	/// Plaintext metadata of the project, see [`BackendConfig::shares_metadata`].
+	pub fn get_metadata(&self, name: &str) -> Result<Option<String>> {
+		match self {
+			BackendState::Http(http) => http.get_metadata(name),
+			BackendState::S3(s3) => s3.get_metadata(name),
+			_ => bail!("the state backend doesn't store the project metadata"),
+		}
+	}
+
+	pub fn save_metadata(&self, name: &str, value: &str) -> Result<()> {
+		match self {
+			BackendState::Http(http) => http.save_metadata(name, value),
+			BackendState::S3(s3) => s3.save_metadata(name, value),
+			_ => bail!("the state backend doesn't store the project metadata"),
+		}
+	}
+
+	pub fn delete_metadata(&self, name: &str) -> Result<()> {
+		match self {
+			BackendState::Http(http) => http.delete_metadata(name),
+			BackendState::S3(s3) => s3.delete_metadata(name),
+			_ => bail!("the state backend doesn't store the project metadata"),
+		}
+	}
+
+	/// Time to wait for the lock configured for the backend.
+	pub fn lock_timeout(&self) -> Duration {
+		match self {
+			BackendState::Http(http) => http.lock_timeout(),
+			BackendState::S3(s3) => s3.lock_timeout(),
+			BackendState::Plugin(plugin) => plugin.lock_timeout(),
+			BackendState::Local(_) => Duration::ZERO,
+			#[cfg(feature = "sqlite")]
+			BackendState::Sqlite(_) => Duration::ZERO,
+		}
+	}
+}
+
+/// Hold the state lock until dropped.
+pub struct StateLockGuard {
+	backend: Rc<RefCell<BackendState>>,
+	lock: LockInfo,
+}
+
+impl StateLockGuard {
+	/// Lock held by the guard, see [`BackendState::replace`].
+	pub fn lock(&self) -> &LockInfo {
+		&self.lock
+	}
+}
+
+impl Drop for StateLockGuard {
+	fn drop(&mut self) {
+		if let Err(err) = self.backend.borrow().unlock(&self.lock) {
+			log::error!("unable to release the state lock; {err}");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plugin_is_resolved_from_the_config() {
+		let config = BackendConfig::from_str(
+			r#"{ "type": "plugin", "path": "./libmissing_backend.so", "lockTimeout": 5 }"#,
+		)
+		.unwrap();
+		match &config {
+			BackendConfig::Plugin(plugin) => {
+				assert_eq!(plugin.path, "./libmissing_backend.so");
+				assert_eq!(plugin.lock_timeout, 5);
+			},
+			_ => panic!("expected a plugin backend"),
+		}
+
+		// the plugin is loaded by every command opening the state
+		let err = BackendState::from_config(&MashinDir::default(), &config)
+			.err()
+			.expect("missing cdylib");
+		assert!(err.to_string().starts_with("unable to load the state backend"));
+	}
 }
@@ -17,13 +17,15 @@ use crate::{
 	backend::BackendState,
 	config::Config,
 	mashin_dir::MashinDir,
-	state::{derive_key, StateDiff},
+	state::{derive_key, state_key, verify_key, Journal, JournalEntry, StackOutputs, StateDiff},
 	DynamicLibraryResource, RawState, Result, RuntimeCommand,
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use console::style;
 use deno_core::Resource;
-use mashin_sdk::{ResourceAction, Urn};
+use mashin_sdk::{LockInfo, ResourceAction, ResourceArgs, Urn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sodiumoxide::crypto::{pwhash::Salt, secretbox};
 use std::{
 	cell::RefCell,
@@ -31,6 +33,7 @@ use std::{
 	ffi::c_void,
 	ops::Deref,
 	rc::Rc,
+	str::FromStr,
 	sync::{
 		atomic::{AtomicU64, Ordering},
 		Arc,
@@ -38,7 +41,8 @@ use std::{
 };
 
 pub type RegisteredProviders = HashMap<String, RegisteredProvider>;
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct ExecutedResources {
 	resources: BTreeMap<String, ExecutedResource>,
 }
@@ -81,6 +85,28 @@ impl ExecutedResources {
 		self.resources.iter().filter_map(|(_, s)| s.required_change.clone()).collect()
 	}
 
+	/// URNs of the resources to delete, in reverse dependency order. The code
+	/// declares a resource after the ones it depends on, so it's deleted before
+	/// them; the resources not declared anymore are deleted first.
+	pub fn deletion_order(&self, declared_urns: &[Urn]) -> Result<Vec<Urn>> {
+		let mut urns = self
+			.resources
+			.iter()
+			.filter(|(_, resource)| resource.required_change == Some(ResourceAction::Delete))
+			.map(|(urn, _)| Urn::from_str(urn))
+			.collect::<Result<Vec<_>>>()?;
+
+		let rank = |urn: &Urn| {
+			declared_urns
+				.iter()
+				.position(|declared_urn| declared_urn == urn)
+				.map_or(0, |position| declared_urns.len() - position)
+		};
+		urns.sort_by_key(rank);
+
+		Ok(urns)
+	}
+
 	pub fn print_diff_plan(&self) {
 		let mut to_add = 0;
 		let mut to_update = 0;
@@ -144,7 +170,7 @@ pub struct RegisteredProvider {
 	pub ptr: *mut c_void,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct ExecutedResource {
 	// provider name
 	pub provider: String,
@@ -154,6 +180,15 @@ pub struct ExecutedResource {
 	pub required_change: Option<ResourceAction>,
 
 	pub diff: Option<StateDiff>,
+	/// Config of the resource once the change is applied
+	#[serde(default)]
+	pub config: Value,
+	/// Changed outside of Mashin, no action is required
+	#[serde(default)]
+	pub drifted: bool,
+	/// Paths of the sensitive values, see [`RawState::sensitive_paths`]
+	#[serde(default)]
+	pub sensitive_paths: Vec<String>,
 }
 
 impl ExecutedResource {
@@ -169,34 +204,81 @@ impl ExecutedResource {
 		// as we only need the diff state and the next action needed
 		let required_change = if current_state.is_null() {
 			Some(ResourceAction::Create)
-		} else if current_state.inner() == new_state.inner() {
+		} else if current_state.same_as(new_state) {
 			None
 		} else {
 			Some(ResourceAction::Update { diff: Rc::new(diff.provider_resource_diff()) })
 		};
 
-		ExecutedResource { provider: provider_name, diff: Some(diff), required_change }
+		ExecutedResource {
+			provider: provider_name,
+			diff: Some(diff),
+			required_change,
+			config: new_state.config(),
+			drifted: false,
+			sensitive_paths: new_state.sensitive_paths(),
+		}
+	}
+
+	/// Resource available within the state but not in the code anymore,
+	/// the diff is built from its prior state.
+	pub fn new_delete(provider_name: String, current_state: &RawState) -> Self {
+		let diff = RawState::default().compare_with(current_state);
+
+		ExecutedResource {
+			provider: provider_name,
+			diff: Some(diff),
+			required_change: Some(ResourceAction::Delete),
+			config: current_state.config(),
+			drifted: false,
+			sensitive_paths: current_state.sensitive_paths(),
+		}
+	}
+
+	/// Resource changed outside of Mashin, the diff is built from the stored
+	/// state to the refreshed one.
+	pub fn new_drift(
+		provider_name: String,
+		stored_state: &RawState,
+		refreshed_state: &RawState,
+	) -> Self {
+		let diff = refreshed_state.compare_with(stored_state);
+
+		ExecutedResource {
+			provider: provider_name,
+			diff: Some(diff),
+			required_change: None,
+			config: refreshed_state.config(),
+			drifted: !stored_state.same_as(refreshed_state),
+			sensitive_paths: refreshed_state.sensitive_paths(),
+		}
 	}
 
 	pub fn print_diff(&self, urn: &str) -> Result<()> {
-		let resource_action = self.required_change.clone().ok_or(anyhow!("no changes required"))?;
 		let resource_diff = self.diff.clone().ok_or(anyhow!("no resource diff"))?;
 
 		let total_changes = resource_diff.len();
 		let mut total_changes_processed = 0;
 
-		let arrow = match &resource_action {
-			ResourceAction::Update { .. } => style("-->").cyan().bold().to_string(),
-			ResourceAction::Create => style("-->").green().bold().to_string(),
-			ResourceAction::Delete => style("-->").red().bold().to_string(),
-			_ => "".to_string(),
+		// refreshed state, see `ExecutedResource::new_drift`
+		let (arrow, description) = if self.drifted {
+			(style("-->").yellow().bold().to_string(), "Changed outside of Mashin".to_string())
+		} else {
+			let resource_action =
+				self.required_change.clone().ok_or(anyhow!("no changes required"))?;
+			let arrow = match &resource_action {
+				ResourceAction::Update { .. } => style("-->").cyan().bold().to_string(),
+				ResourceAction::Create => style("-->").green().bold().to_string(),
+				ResourceAction::Delete => style("-->").red().bold().to_string(),
+				_ => "".to_string(),
+			};
+			(arrow, format!("Need to be {}", resource_action.action_past_str().to_lowercase()))
 		};
 
 		//    --> [aws:s3:bucket?=test1234atmos1000]: Need to be created
 		log::info!(
-			"   {arrow} [{}]: Need to be {}",
-			style(urn.replace("urn:provider:", "")).bold(),
-			resource_action.action_past_str().to_lowercase()
+			"   {arrow} [{}]: {description}",
+			style(urn.replace("urn:provider:", "")).bold()
 		);
 
 		for resource_diff in resource_diff.iter() {
@@ -217,9 +299,55 @@ impl ExecutedResource {
 	}
 }
 
+/// Result of a refresh, the out-of-band changes are kept apart from the
+/// changes of the code.
+#[derive(Debug, Default)]
+pub struct RefreshedResources {
+	/// Resources changed outside of Mashin
+	pub drifted: ExecutedResources,
+	/// Changes of the code, not applied by a refresh
+	pub code_changes: ExecutedResources,
+	/// Refreshed state of every resource read
+	pub states: BTreeMap<Urn, RawState>,
+}
+
+impl RefreshedResources {
+	/// Record a stored resource read back by its provider, with the config of
+	/// the code; `None` if the resource is not in the code anymore.
+	pub fn record(
+		&mut self,
+		urn: &Urn,
+		provider_name: String,
+		stored_state: &RawState,
+		refreshed_state: RawState,
+		maybe_code_config: Option<&Value>,
+	) {
+		let drift =
+			ExecutedResource::new_drift(provider_name.clone(), stored_state, &refreshed_state);
+		if drift.drifted {
+			self.drifted.insert(urn, drift);
+		}
+
+		let code_change = match maybe_code_config {
+			Some(code_config) => ExecutedResource::new(
+				provider_name,
+				&refreshed_state,
+				&refreshed_state.with_config(code_config),
+			),
+			None => ExecutedResource::new_delete(provider_name, &refreshed_state),
+		};
+		if code_change.required_change.is_some() {
+			self.code_changes.insert(urn, code_change);
+		}
+
+		self.states.insert(urn.clone(), refreshed_state);
+	}
+}
+
 #[derive(Default)]
 pub struct MashinBuilder<'a, T: Config> {
 	state_handler: Option<Rc<RefCell<BackendState>>>,
+	state_lock: Option<LockInfo>,
 	passphrase: Option<&'a [u8]>,
 	executed_resources: Option<Rc<RefCell<ExecutedResources>>>,
 	progress_manager: Option<Rc<T::ProgressManager>>,
@@ -233,6 +361,7 @@ impl<'a, T: Config> MashinBuilder<'a, T> {
 	pub fn new() -> Self {
 		MashinBuilder {
 			state_handler: None,
+			state_lock: None,
 			passphrase: None,
 			executed_resources: None,
 			progress_manager: None,
@@ -249,6 +378,11 @@ impl<'a, T: Config> MashinBuilder<'a, T> {
 		self
 	}
 
+	pub fn with_state_lock(&mut self, lock: &LockInfo) -> &mut Self {
+		self.state_lock = Some(lock.clone());
+		self
+	}
+
 	pub fn with_passphrase(&mut self, passphrase: &'a [u8]) -> &mut Self {
 		self.passphrase = Some(passphrase);
 		self
@@ -294,20 +428,21 @@ impl<'a, T: Config> MashinBuilder<'a, T> {
 
 	pub fn build(&self) -> Result<MashinEngine<T>> {
 		let mashin_dir = self.mashin_dir.clone().unwrap_or_default();
-		let salt = Salt(*self.salt.unwrap_or(&[
-			0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
-			24, 25, 26, 27, 28, 29, 30, 31,
-		]));
-		let key = derive_key(self.passphrase.unwrap_or_default(), salt)?;
+		let state_handler =
+			self.state_handler.clone().ok_or(anyhow!("State handler is required"))?;
+		let passphrase = self.passphrase.unwrap_or_default();
+		let key = match self.salt {
+			Some(salt) => derive_key(passphrase, Salt(*salt))?,
+			None => state_key(&mashin_dir, passphrase)?,
+		};
+		verify_key(&state_handler.borrow(), &key)?;
 
 		Ok(MashinEngine {
 			resources_count: Arc::new(AtomicU64::new(self.resources_count.unwrap_or_default())),
 			command: self.runtime_command.clone().unwrap_or(RuntimeCommand::Prepare),
 			mashin_dir,
-			state_handler: self
-				.state_handler
-				.clone()
-				.ok_or(anyhow!("State handler is required"))?,
+			state_handler,
+			state_lock: self.state_lock.clone(),
 			key,
 			executed_resources: self.executed_resources.clone().unwrap_or_default(),
 			progress_manager: self
@@ -316,6 +451,11 @@ impl<'a, T: Config> MashinBuilder<'a, T> {
 				.ok_or(anyhow!("Progress manager is required"))?,
 			http_client: self.http_client.clone().ok_or(anyhow!("HTTP Client is required"))?,
 			providers: Default::default(),
+			refreshed_resources: Default::default(),
+			declared_resources: Default::default(),
+			outputs: Default::default(),
+			stack_references: Default::default(),
+			passphrase: self.passphrase.unwrap_or_default().to_vec(),
 		})
 	}
 }
@@ -326,11 +466,22 @@ pub struct MashinEngine<T: Config> {
 	pub command: RuntimeCommand,
 	pub mashin_dir: MashinDir,
 	pub state_handler: Rc<RefCell<BackendState>>,
+	/// Lock held on the state for the whole run
+	pub state_lock: Option<LockInfo>,
 	pub key: secretbox::Key,
 	pub executed_resources: Rc<RefCell<ExecutedResources>>,
 	pub progress_manager: Rc<T::ProgressManager>,
 	pub http_client: Rc<T::HttpClient>,
 	pub providers: Rc<RefCell<RegisteredProviders>>,
+	pub refreshed_resources: Rc<RefCell<RefreshedResources>>,
+	/// Resources declared by the main module, in order
+	pub declared_resources: Rc<RefCell<Vec<Urn>>>,
+	/// Outputs exported by the main module
+	pub outputs: Rc<RefCell<StackOutputs>>,
+	/// Outputs of the other projects read by the main module
+	pub stack_references: Rc<RefCell<StackReferences>>,
+	/// Default passphrase of the stack references
+	passphrase: Vec<u8>,
 }
 
 impl<T: Config> Resource for MashinEngine<T> {} // Blank impl
@@ -344,6 +495,155 @@ impl<T: Config> MashinEngine<T> {
 		let current_value = self.resources_count();
 		self.resources_count.store(current_value.saturating_add(1), Ordering::Relaxed);
 	}
+
+	/// Returns the decrypted state of a resource, `null` if the resource is not
+	/// available within the state.
+	pub fn current_state(&self, urn: &Urn) -> Result<RawState> {
+		Ok(self
+			.state_handler
+			.borrow()
+			.get(urn)?
+			.map(|state| state.decrypt(&self.key))
+			.transpose()?
+			.unwrap_or_default())
+	}
+
+	/// Read the outputs of another project and keep them to be saved on apply,
+	/// see [`StackReferences::read`] for the passphrase used.
+	pub fn read_stack_reference(
+		&self,
+		path: &str,
+		workspace: &str,
+		maybe_passphrase: Option<&[u8]>,
+	) -> Result<StackOutputs> {
+		let outputs =
+			StackReferences::read(path, workspace, maybe_passphrase, self.passphrase.as_slice())?;
+		self.stack_references
+			.borrow_mut()
+			.insert(StackReferences::id(path, workspace), outputs.clone());
+
+		Ok(outputs)
+	}
+
+	/// Call the provider `delete` hook of a resource available within the state,
+	/// the resource is removed from the state only if the provider succeed.
+	///
+	/// Used by the apply, for the resources removed from the code, and by
+	/// destroy; the delete is journaled like the other mutations.
+	pub fn delete_resource(&self, urn: &Urn) -> Result<()> {
+		let provider_name = urn.as_provider()?;
+		let providers = self.providers.borrow();
+		let provider = providers
+			.get(&provider_name)
+			.ok_or(anyhow!("provider `{provider_name}` is not registered"))?;
+
+		let current_state = self.current_state(urn)?;
+		if current_state.is_null() {
+			bail!("resource `{urn}` not found in the state")
+		}
+
+		// the config used when the resource has been applied is stored within its state
+		let args = ResourceArgs {
+			action: Rc::new(ResourceAction::Delete),
+			urn: Rc::new(urn.clone()),
+			raw_config: Rc::new(current_state.config()),
+			raw_state: Rc::new(RefCell::new(current_state.into())),
+		};
+
+		let journal = Journal::new(&self.mashin_dir);
+		journal
+			.record(&JournalEntry::Intent { urn: urn.clone(), action: ResourceAction::Delete })?;
+		provider.dylib.call_resource(provider.ptr, &args)?;
+		journal.record(&JournalEntry::Result { urn: urn.clone(), state: None })?;
+
+		self.state_handler.borrow().delete(urn)?;
+		journal.record(&JournalEntry::Committed { urn: urn.clone() })
+	}
+
+	/// Read back a resource of an interrupted apply with the provider `get`
+	/// hook and save it within the state.
+	pub fn reconcile_resource(&self, urn: &Urn, code_config: &Value) -> Result<RawState> {
+		let stored_state = self.current_state(urn)?;
+		let state = self.read_resource(urn, code_config.clone(), &stored_state)?;
+
+		self.state_handler.borrow().save(urn, &state.encrypt(&self.key)?)?;
+		Journal::new(&self.mashin_dir).record(&JournalEntry::Committed { urn: urn.clone() })?;
+
+		Ok(state)
+	}
+
+	/// Read a resource once with the provider `get` hook, with the config
+	/// stored within its state. Any difference with the stored state is an
+	/// out-of-band change, the refreshed state is then compared with the config
+	/// of the code to get the changes not applied yet.
+	///
+	/// Returns the refreshed state.
+	pub fn refresh_resource(&self, urn: &Urn, code_config: &Value) -> Result<RawState> {
+		let provider_name = urn.as_provider()?;
+		let read_resource =
+			|config: Value, state: &RawState| self.read_resource(urn, config, state);
+
+		let stored_state = self.current_state(urn)?;
+		let mut refreshed_resources = self.refreshed_resources.borrow_mut();
+
+		if stored_state.is_null() {
+			// not created yet, nothing to refresh
+			let planned_state = read_resource(code_config.clone(), &stored_state)?;
+			refreshed_resources
+				.code_changes
+				.insert(urn, ExecutedResource::new(provider_name, &stored_state, &planned_state));
+			return Ok(planned_state)
+		}
+
+		let refreshed_state = read_resource(stored_state.config(), &stored_state)?;
+		refreshed_resources.record(
+			urn,
+			provider_name,
+			&stored_state,
+			refreshed_state.clone(),
+			Some(code_config),
+		);
+
+		Ok(refreshed_state)
+	}
+
+	/// Read a resource of the state removed from the code, its provider must
+	/// still be declared. See [`MashinEngine::refresh_resource`].
+	pub fn refresh_removed_resource(&self, urn: &Urn) -> Result<()> {
+		let stored_state = self.current_state(urn)?;
+		let refreshed_state = self.read_resource(urn, stored_state.config(), &stored_state)?;
+		self.refreshed_resources.borrow_mut().record(
+			urn,
+			urn.as_provider()?,
+			&stored_state,
+			refreshed_state,
+			None,
+		);
+
+		Ok(())
+	}
+
+	/// Whether the provider is declared by the main module.
+	pub fn has_provider(&self, provider_name: &str) -> bool {
+		self.providers.borrow().contains_key(provider_name)
+	}
+
+	// call the provider `get` hook, the state is not saved
+	fn read_resource(&self, urn: &Urn, config: Value, state: &RawState) -> Result<RawState> {
+		let provider_name = urn.as_provider()?;
+		let providers = self.providers.borrow();
+		let provider = providers
+			.get(&provider_name)
+			.ok_or(anyhow!("provider `{provider_name}` is not registered"))?;
+
+		let args = ResourceArgs {
+			action: Rc::new(ResourceAction::Get),
+			urn: Rc::new(urn.clone()),
+			raw_config: Rc::new(config),
+			raw_state: Rc::new(RefCell::new(state.into())),
+		};
+		Ok(provider.dylib.call_resource(provider.ptr, &args)?.inner().into())
+	}
 }
 
 impl<T: Config> Drop for MashinEngine<T> {
@@ -357,3 +657,136 @@ impl<T: Config> Drop for MashinEngine<T> {
 		self.providers.borrow_mut().iter().for_each(drop_provider)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn state(config: Value, size: u64) -> RawState {
+		json!({
+			"__config": { "__value": config, "__sensitive": false },
+			"size": { "__value": size, "__sensitive": false },
+		})
+		.into()
+	}
+
+	#[test]
+	fn resources_are_deleted_in_reverse_dependency_order() {
+		let urn =
+			|name: &str| Urn::from_str(&format!("urn:provider:aws:s3:bucket?={name}")).unwrap();
+		let stored = state(json!({ "size": 1 }), 1);
+		let mut executed_resources = ExecutedResources::default();
+		for name in ["bucket", "policy", "removed", "website"] {
+			executed_resources
+				.insert(&urn(name), ExecutedResource::new_delete("aws".into(), &stored));
+		}
+		executed_resources
+			.insert(&urn("kept"), ExecutedResource::new("aws".into(), &stored, &stored));
+
+		// the policy and the website use the bucket, the removed resource is
+		// only within the state
+		let declared_urns = [urn("bucket"), urn("kept"), urn("policy"), urn("website")];
+		assert_eq!(
+			executed_resources.deletion_order(&declared_urns).unwrap(),
+			vec![urn("removed"), urn("website"), urn("policy"), urn("bucket")]
+		);
+	}
+
+	#[test]
+	fn drift_is_not_an_action() {
+		let stored = state(json!({ "size": 1 }), 1);
+		let refreshed = state(json!({ "size": 1 }), 2);
+
+		let drift = ExecutedResource::new_drift("aws".into(), &stored, &refreshed);
+		assert!(drift.drifted);
+		assert_eq!(drift.required_change, None);
+
+		let drift = ExecutedResource::new_drift("aws".into(), &stored, &stored);
+		assert!(!drift.drifted);
+	}
+
+	#[test]
+	fn code_change_is_compared_with_the_refreshed_state() {
+		let refreshed = state(json!({ "size": 1 }), 2);
+
+		let planned = refreshed.with_config(&json!({ "size": 1 }));
+		assert_eq!(ExecutedResource::new("aws".into(), &refreshed, &planned).required_change, None);
+
+		let planned = refreshed.with_config(&json!({ "size": 3 }));
+		let code_change = ExecutedResource::new("aws".into(), &refreshed, &planned);
+		assert!(matches!(code_change.required_change, Some(ResourceAction::Update { .. })));
+		assert_eq!(code_change.config, json!({ "size": 3 }));
+		assert!(!code_change.drifted);
+	}
+
+	#[test]
+	fn pre_upgrade_state_plans_no_changes() {
+		// written before the state schema versioning
+		let stored = state(json!({ "size": 1 }), 1);
+		let mut current = state(json!({ "size": 1 }), 1).inner().clone();
+		current["__version"] = json!({ "__value": 1, "__sensitive": false });
+		let current = RawState::from(current);
+
+		let executed = ExecutedResource::new("aws".into(), &stored, &current);
+		assert_eq!(executed.required_change, None);
+		assert!(executed.diff.unwrap().iter().all(|diff| diff.is_eq()));
+		assert!(!ExecutedResource::new_drift("aws".into(), &stored, &current).drifted);
+	}
+
+	#[test]
+	fn refresh_keeps_drift_apart_from_code_changes() {
+		let urn =
+			|name: &str| Urn::from_str(&format!("urn:provider:aws:s3:bucket?={name}")).unwrap();
+		let stored = state(json!({ "size": 1 }), 1);
+		let mut refreshed_resources = RefreshedResources::default();
+
+		// changed outside of Mashin only
+		refreshed_resources.record(
+			&urn("drifted"),
+			"aws".into(),
+			&stored,
+			state(json!({ "size": 1 }), 2),
+			Some(&json!({ "size": 1 })),
+		);
+		// changed in the code only
+		refreshed_resources.record(
+			&urn("changed"),
+			"aws".into(),
+			&stored,
+			stored.clone(),
+			Some(&json!({ "size": 3 })),
+		);
+		// removed from the code and changed outside of Mashin
+		refreshed_resources.record(
+			&urn("removed"),
+			"aws".into(),
+			&stored,
+			state(json!({ "size": 1 }), 2),
+			None,
+		);
+
+		let urns = |resources: &ExecutedResources| resources.keys().cloned().collect::<Vec<_>>();
+		assert_eq!(
+			urns(&refreshed_resources.drifted),
+			vec![urn("drifted").to_string(), urn("removed").to_string()]
+		);
+		assert!(refreshed_resources
+			.drifted
+			.values()
+			.all(|drift| drift.required_change.is_none()));
+		assert_eq!(
+			urns(&refreshed_resources.code_changes),
+			vec![urn("changed").to_string(), urn("removed").to_string()]
+		);
+		assert!(matches!(
+			refreshed_resources.code_changes[&urn("changed").to_string()].required_change,
+			Some(ResourceAction::Update { .. })
+		));
+		assert_eq!(
+			refreshed_resources.code_changes[&urn("removed").to_string()].required_change,
+			Some(ResourceAction::Delete)
+		);
+		assert_eq!(refreshed_resources.states.len(), 3);
+	}
+}
@@ -19,7 +19,7 @@ use anyhow::anyhow;
 use deno_core::Resource;
 use dlopen::raw::Library;
 use mashin_sdk::{ResourceArgs, ResourceResult};
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use std::{borrow::Cow, collections::HashMap, ffi::c_void, mem, ptr, rc::Rc, slice};
 
@@ -35,6 +35,40 @@ impl Drop for DynamicLibraryResource {
 }
 
 impl DynamicLibraryResource {
+	/// Bind the `symbols` of the opened library.
+	pub fn new(lib: Library, symbols: HashMap<String, ForeignFunction>) -> Result<Self> {
+		let mut resource = Self { lib, symbols: HashMap::new() };
+
+		for (symbol_key, foreign_fn) in symbols {
+			let symbol = foreign_fn.name.as_deref().unwrap_or(&symbol_key);
+			// SAFETY: The obtained T symbol is the size of a pointer.
+			let fn_ptr = unsafe { resource.lib.symbol::<*const c_void>(symbol) }
+				.map_err(|err| anyhow!("Failed to register symbol {symbol}: {err}"))?;
+			let ptr = libffi::middle::CodePtr::from_ptr(fn_ptr as _);
+			let cif = libffi::middle::Cif::new(
+				foreign_fn
+					.parameters
+					.clone()
+					.into_iter()
+					.map(libffi::middle::Type::try_from)
+					.collect::<Result<Vec<_>, _>>()?,
+				foreign_fn.result.clone().try_into()?,
+			);
+
+			resource.symbols.insert(
+				symbol_key,
+				Box::new(Symbol {
+					cif,
+					ptr,
+					parameter_types: foreign_fn.parameters,
+					result_type: foreign_fn.result,
+				}),
+			);
+		}
+
+		Ok(resource)
+	}
+
 	pub fn call_new(&self, props: &Value) -> Result<*mut c_void> {
 		let symbol = self.symbols.get("new").ok_or(anyhow!("valid `drop` symbol"))?;
 
@@ -84,7 +118,18 @@ impl DynamicLibraryResource {
 		provider_ptr: *mut c_void,
 		args: &ResourceArgs,
 	) -> Result<ResourceResult> {
-		let symbol = self.symbols.get("run").ok_or(anyhow!("valid `run` symbol"))?;
+		self.call_json("run", provider_ptr, args)
+	}
+
+	/// Call the symbol with the JSON serialized `args`. The symbol returns a
+	/// pointer to the JSON serialized result, prefixed by its length (u32).
+	pub fn call_json<A, R>(&self, symbol_name: &str, ptr: *mut c_void, args: &A) -> Result<R>
+	where
+		A: Serialize,
+		R: DeserializeOwned,
+	{
+		let symbol =
+			self.symbols.get(symbol_name).ok_or(anyhow!("valid `{symbol_name}` symbol"))?;
 
 		let (args_ptr, args_length) = {
 			let json = serde_json::to_string(&args)?;
@@ -99,7 +144,7 @@ impl DynamicLibraryResource {
 			symbol.cif.call::<*const u8>(
 				symbol.ptr,
 				&[
-					NativeValue { pointer: provider_ptr }.as_arg(&NativeType::Pointer),
+					NativeValue { pointer: ptr }.as_arg(&NativeType::Pointer),
 					NativeValue { pointer: args_ptr as *mut c_void }.as_arg(&NativeType::Pointer),
 					NativeValue { usize_value: args_length }.as_arg(&NativeType::USize),
 				],
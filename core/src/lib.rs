@@ -13,27 +13,45 @@
  *                                                          *
 \* ---------------------------------------------------------*/
 
+#[cfg(feature = "sqlite")]
+pub use crate::state::SqliteState;
 pub use crate::{
-	backend::BackendState,
+	backend::{set_client_version, BackendConfig, BackendState},
 	client::{
-		ExecutedResource, ExecutedResources, MashinBuilder, MashinEngine, RegisteredProvider,
-		RegisteredProviders,
+		ExecutedResource, ExecutedResources, MashinBuilder, MashinEngine, RefreshedResources,
+		RegisteredProvider, RegisteredProviders,
 	},
 	config::Config,
 	ffi::{DynamicLibraryResource, ForeignFunction, NativeType, NativeValue, Symbol},
-	state::{EncryptedState, FileState, ProjectState, RawState, StateHandler},
+	plan::{Plan, PlanReport, PlanSummary, PlannedChange, PlannedResource, PLAN_SCHEMA_VERSION},
+	project_config::{KeyCommandOutput, ProjectConfig},
+	state::{
+		add_recipient, generate_identity, migrate as migrate_state, rekey as rekey_state,
+		remove_recipient, run_key_command, state_key, verify_key, EncryptedState, ExportedEntries,
+		FileState, HttpState, HttpStateConfig, Journal, JournalEntry, PendingOperation,
+		PluginStateConfig, ProjectState, RawState, Recipient, Recipients, S3State, S3StateConfig,
+		Snapshot, StackOutput, StackOutputs, StackReferences, StateEntryMetadata, StateExport,
+		StateHandler, StateInner, StateOperation,
+	},
 };
 use async_trait::async_trait;
 use deno_core::ModuleSpecifier;
 pub use mashin_sdk as sdk;
 pub(crate) use sdk::Result;
-use std::{collections::HashMap, fs::File, path::PathBuf, time::SystemTime};
+use std::{
+	collections::{BTreeSet, HashMap},
+	fs::File,
+	path::PathBuf,
+	time::SystemTime,
+};
 
 mod backend;
 mod client;
 mod config;
 mod ffi;
 pub mod mashin_dir;
+mod plan;
+mod project_config;
 mod state;
 
 #[macro_export]
@@ -46,12 +64,6 @@ macro_rules! log {
 	};
 }
 
-#[derive(Clone)]
-pub struct StateInner {
-	pub get_symbol: Symbol,
-	pub save_symbol: Symbol,
-}
-
 #[derive(PartialEq, Clone)]
 pub enum RuntimeCommand {
 	/// First run, mainly used to get the total count of resources
@@ -60,6 +72,12 @@ pub enum RuntimeCommand {
 	Read,
 	/// Apply changes
 	Apply,
+	/// Read all resources, with their stored config and with the config of the code
+	Refresh,
+	/// Read back the resources of an interrupted apply and save them within the state
+	Reconcile { urns: BTreeSet<sdk::Urn> },
+	/// Read an existing resource by its provider id and save it within the state
+	Import { urn: sdk::Urn, id: String },
 }
 
 pub type HeadersMap = HashMap<String, String>;
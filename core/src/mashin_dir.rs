@@ -14,11 +14,15 @@
 \* ---------------------------------------------------------*/
 
 use deno_core::resolve_path;
-use std::{env::current_dir, path::PathBuf};
+use std::{env::current_dir, fs, path::PathBuf};
+
+/// Workspace used when none is selected, its state lives at the root of `.mashin`
+pub const DEFAULT_WORKSPACE: &str = "default";
 
 #[derive(Debug, Clone, Default)]
 pub struct MashinDir {
 	root: PathBuf,
+	workspace: Option<String>,
 }
 
 impl MashinDir {
@@ -33,16 +37,61 @@ impl MashinDir {
 		};
 		assert!(root.is_absolute());
 
-		let mashin_dir = Self { root };
+		// the selected workspace, see `mashin workspace select`
+		let workspace = fs::read_to_string(root.join("workspace"))
+			.ok()
+			.map(|name| name.trim().to_string())
+			.filter(|name| !name.is_empty() && name != DEFAULT_WORKSPACE);
+
+		let mashin_dir = Self { root, workspace };
 		Ok(mashin_dir)
 	}
+	/// Same directory, with the state of another workspace.
+	pub fn with_workspace(&self, name: &str) -> Self {
+		let workspace = (name != DEFAULT_WORKSPACE).then(|| name.to_string());
+		Self { root: self.root.clone(), workspace }
+	}
+	pub fn workspace(&self) -> &str {
+		self.workspace.as_deref().unwrap_or(DEFAULT_WORKSPACE)
+	}
 	pub fn deps_folder_path(&self) -> PathBuf {
 		self.root.join("deps")
 	}
 	pub fn state_folder_path(&self) -> PathBuf {
-		self.root.join("state")
+		self.workspace_root().join("state")
+	}
+	pub fn state_db_file_path(&self) -> PathBuf {
+		self.workspace_root().join("state.db")
+	}
+	pub fn config_file_path(&self) -> PathBuf {
+		self.root.join("config.json")
+	}
+	pub fn snapshots_folder_path(&self) -> PathBuf {
+		self.workspace_root().join("snapshots")
+	}
+	pub fn journal_file_path(&self) -> PathBuf {
+		self.workspace_root().join("journal")
+	}
+	pub fn workspace_file_path(&self) -> PathBuf {
+		self.root.join("workspace")
+	}
+	pub fn workspaces_folder_path(&self) -> PathBuf {
+		self.root.join("workspaces")
+	}
+	pub fn recipients_file_path(&self) -> PathBuf {
+		self.workspace_root().join("recipients.json")
+	}
+	pub fn salt_file_path(&self) -> PathBuf {
+		self.root.join("salt")
 	}
 	pub fn upgrade_check_file_path(&self) -> PathBuf {
 		self.root.join("upgrade")
 	}
+	// the default workspace keeps the layout used before workspaces
+	fn workspace_root(&self) -> PathBuf {
+		match &self.workspace {
+			Some(name) => self.workspaces_folder_path().join(name),
+			None => self.root.clone(),
+		}
+	}
 }
@@ -0,0 +1,424 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::{backend::BackendState, ExecutedResources, Result};
+use anyhow::{anyhow, bail};
+use chrono::Utc;
+use mashin_sdk::ResourceAction;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sodiumoxide::crypto::secretbox;
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+/// Version of the plan format
+const PLAN_VERSION: u32 = 2;
+
+/// Changes reviewed with `mashin plan`, written in a `.mplan` file to be
+/// applied as is by `mashin apply`. The resources are not read again, each
+/// saved action is executed with the config it has been planned with.
+///
+/// The file holds the config of the resources in plaintext, sensitive values
+/// included.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+	pub version: u32,
+	/// RFC 3339 date of the plan
+	pub created_at: String,
+	pub mashin_version: String,
+	pub workspace: String,
+	/// Specifier of the main module
+	pub main_module: String,
+	/// Arguments of the main module, available with `Deno.args`
+	pub args: Vec<String>,
+	/// SHA-256 of the state entries when the plan was made, see
+	/// [`Plan::state_hash`]
+	pub state_hash: String,
+	/// Action and diff of each resource
+	pub resources: ExecutedResources,
+	/// Whether anything is applied, the outputs and the stack references
+	/// included
+	pub has_changes: bool,
+	/// Folder of policy modules checked against the plan, checked again on
+	/// apply
+	pub policy_dir: Option<PathBuf>,
+}
+
+impl Plan {
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(
+		mashin_version: &str,
+		workspace: &str,
+		main_module: &str,
+		args: Vec<String>,
+		state_hash: String,
+		resources: ExecutedResources,
+		has_changes: bool,
+		maybe_policy_dir: Option<&Path>,
+	) -> Self {
+		Self {
+			version: PLAN_VERSION,
+			created_at: Utc::now().to_rfc3339(),
+			mashin_version: mashin_version.to_string(),
+			workspace: workspace.to_string(),
+			main_module: main_module.to_string(),
+			args,
+			state_hash,
+			resources,
+			has_changes,
+			policy_dir: maybe_policy_dir.map(Path::to_path_buf),
+		}
+	}
+
+	/// Read a plan written by `mashin plan`.
+	pub fn load(path: &Path) -> Result<Self> {
+		let plan: Self = serde_json::from_slice(
+			&fs::read(path)
+				.map_err(|err| anyhow!("unable to read plan `{}`; {err}", path.display()))?,
+		)
+		.map_err(|err| anyhow!("invalid plan `{}`; {err}", path.display()))?;
+
+		if plan.version != PLAN_VERSION {
+			bail!("unsupported plan version {}, expected {PLAN_VERSION}", plan.version);
+		}
+
+		Ok(plan)
+	}
+
+	pub fn save(&self, path: &Path) -> Result<()> {
+		fs::write(path, serde_json::to_vec_pretty(self)?)
+			.map_err(|err| anyhow!("unable to write plan `{}`; {err}", path.display()))?;
+		Ok(())
+	}
+
+	/// SHA-256 of the decrypted entries within the state, the outputs and the
+	/// stack references included. The encrypted states can't be compared as a
+	/// new nonce is used on every save.
+	pub fn state_hash(backend: &BackendState, key: &secretbox::Key) -> Result<String> {
+		let mut context = digest::Context::new(&digest::SHA256);
+		for urn in backend.entries()? {
+			if let Some(state) = backend.get(&urn)? {
+				context.update(urn.as_ref());
+				context.update(&serde_json::to_vec(state.decrypt(key)?.inner())?);
+			}
+		}
+
+		Ok(context.finish().as_ref().iter().map(|byte| format!("{byte:02x}")).collect())
+	}
+
+	/// Make sure the state has not changed since the plan was made.
+	pub fn verify_state(&self, backend: &BackendState, key: &secretbox::Key) -> Result<()> {
+		if Self::state_hash(backend, key)? != self.state_hash {
+			bail!("the state has changed since the plan was made, run `mashin plan` again")
+		}
+
+		Ok(())
+	}
+}
+
+/// Version of the JSON plan schema, bumped on breaking changes only
+pub const PLAN_SCHEMA_VERSION: u32 = 1;
+
+/// Machine-readable plan, printed by `mashin run` and `mashin plan` with
+/// `--format json`. Only the resources with an action are listed and the
+/// sensitive values are redacted as `[sensitive]`.
+///
+/// The schema is documented in `docs/plan-json.md`, keep it in sync.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanReport {
+	pub schema_version: u32,
+	pub resources: Vec<PlannedResource>,
+	pub summary: PlanSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedResource {
+	pub urn: String,
+	pub provider: String,
+	pub action: String,
+	/// Config of the resource once the change is applied
+	pub config: Value,
+	pub changes: Vec<PlannedChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedChange {
+	/// Path of the changed value, eg; `config.acl`
+	pub path: String,
+	pub before: Option<Value>,
+	pub after: Option<Value>,
+}
+
+/// Total of resources by action
+#[derive(Debug, Default, Serialize)]
+pub struct PlanSummary {
+	pub create: usize,
+	pub update: usize,
+	pub delete: usize,
+}
+
+impl PlanReport {
+	pub fn new(executed_resources: &ExecutedResources) -> Self {
+		let mut summary = PlanSummary::default();
+		let mut resources = Vec::new();
+
+		for (urn, executed_resource) in executed_resources.iter() {
+			let action = match &executed_resource.required_change {
+				Some(action) => action,
+				None => continue,
+			};
+
+			match action {
+				ResourceAction::Create => summary.create += 1,
+				ResourceAction::Update { .. } => summary.update += 1,
+				ResourceAction::Delete => summary.delete += 1,
+				_ => {},
+			}
+
+			let sensitive_paths = &executed_resource.sensitive_paths;
+
+			// the diff is made from the previous state (rhs) to the new one (lhs)
+			let changes = executed_resource
+				.diff
+				.iter()
+				.flat_map(|diff| diff.iter())
+				.filter(|diff| !diff.is_eq())
+				.map(|diff| {
+					let path = diff.path().to_string();
+					let segments = path.split('.').map(str::to_string).collect::<Vec<_>>();
+					let redact = |value: &Option<Value>| {
+						value.as_ref().map(|value| redact(&segments, value, sensitive_paths))
+					};
+					PlannedChange { before: redact(diff.rhs()), after: redact(diff.lhs()), path }
+				})
+				.collect();
+
+			resources.push(PlannedResource {
+				urn: urn.clone(),
+				provider: executed_resource.provider.clone(),
+				action: action.action_present_str().to_lowercase(),
+				config: redact(&["config".to_string()], &executed_resource.config, sensitive_paths),
+				changes,
+			});
+		}
+
+		Self { schema_version: PLAN_SCHEMA_VERSION, resources, summary }
+	}
+}
+
+const SENSITIVE: &str = "[sensitive]";
+
+// the config is never marked as sensitive by itself, a config value is
+// sensitive when the state marks a value of the same name as sensitive
+// (`apiKey` for the `api_key` field, at any depth)
+fn same_field(config_key: &str, field: &str) -> bool {
+	let normalize = |key: &str| key.replace('_', "").to_lowercase();
+	normalize(config_key) == normalize(field)
+}
+
+fn is_sensitive(path: &[String], sensitive_paths: &[String]) -> bool {
+	let joined = path.join(".");
+	let is_within =
+		|sensitive: &String| joined == *sensitive || joined.starts_with(&format!("{sensitive}."));
+	if sensitive_paths.iter().any(is_within) {
+		return true
+	}
+
+	match (path.first(), path.last()) {
+		(Some(root), Some(key)) if root == "config" && path.len() > 1 =>
+			sensitive_paths.iter().any(|sensitive| {
+				let field = sensitive.rsplit('.').next().unwrap_or_default();
+				same_field(key, field)
+			}),
+		_ => false,
+	}
+}
+
+// redact the value at `path` of the state, and the sensitive values within
+fn redact(path: &[String], value: &Value, sensitive_paths: &[String]) -> Value {
+	if is_sensitive(path, sensitive_paths) {
+		return SENSITIVE.into()
+	}
+
+	let child = |key: String| [path, &[key]].concat();
+	match value {
+		Value::Object(values) => Value::Object(
+			values
+				.iter()
+				.map(|(key, value)| {
+					(key.clone(), redact(&child(key.clone()), value, sensitive_paths))
+				})
+				.collect(),
+		),
+		Value::Array(values) => Value::Array(
+			values
+				.iter()
+				.enumerate()
+				.map(|(idx, value)| redact(&child(format!("[{idx}]")), value, sensitive_paths))
+				.collect(),
+		),
+		value => value.clone(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{ExecutedResource, FileState, RawState};
+	use mashin_sdk::Urn;
+	use serde_json::json;
+	use std::str::FromStr;
+
+	#[test]
+	fn state_hash_covers_every_entry() {
+		let root = tempfile::tempdir().unwrap();
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+
+		let backend = BackendState::Local(FileState::new(root.path().join("state")).unwrap());
+		let resource = Urn::from_str("urn:provider:test:resource").unwrap();
+		backend
+			.save(&resource, &RawState::from(json!(1)).encrypt(&key).unwrap())
+			.unwrap();
+		let state_hash = Plan::state_hash(&backend, &key).unwrap();
+
+		// a new nonce doesn't change the hash
+		backend
+			.save(&resource, &RawState::from(json!(1)).encrypt(&key).unwrap())
+			.unwrap();
+		assert_eq!(Plan::state_hash(&backend, &key).unwrap(), state_hash);
+
+		// the outputs are covered, not only the resources
+		let outputs = Urn::from_str("urn:mashin:output:endpoint").unwrap();
+		backend
+			.save(&outputs, &RawState::from(json!("a")).encrypt(&key).unwrap())
+			.unwrap();
+		let plan = Plan::new(
+			"0.0.0",
+			"default",
+			"file:///main.ts",
+			Vec::new(),
+			Plan::state_hash(&backend, &key).unwrap(),
+			ExecutedResources::default(),
+			true,
+			Some(Path::new("policies")),
+		);
+		assert_ne!(plan.state_hash, state_hash);
+		plan.verify_state(&backend, &key).unwrap();
+
+		backend
+			.save(&outputs, &RawState::from(json!("b")).encrypt(&key).unwrap())
+			.unwrap();
+		assert!(plan.verify_state(&backend, &key).is_err());
+
+		// the policies are kept for the apply
+		let plan_path = root.path().join("plan.mplan");
+		plan.save(&plan_path).unwrap();
+		assert_eq!(Plan::load(&plan_path).unwrap().policy_dir, Some(PathBuf::from("policies")));
+	}
+
+	#[test]
+	fn sensitive_values_are_redacted() {
+		let state = |api_key: &str| -> RawState {
+			json!({
+				"__config": {
+					"__value": { "apiKey": api_key, "size": 1 },
+					"__sensitive": false,
+				},
+				"api_key": { "__value": api_key, "__sensitive": true },
+				"size": { "__value": 1, "__sensitive": false },
+			})
+			.into()
+		};
+		let urn = Urn::from_str("urn:provider:test:resource").unwrap();
+
+		let mut executed_resources = ExecutedResources::default();
+		executed_resources.insert(
+			&urn,
+			ExecutedResource::new("test".to_string(), &RawState::default(), &state("secret")),
+		);
+		let report = PlanReport::new(&executed_resources);
+		let resource = &report.resources[0];
+		assert_eq!(resource.config, json!({ "apiKey": "[sensitive]", "size": 1 }));
+		let created = resource.changes.iter().find(|change| change.path == "config").unwrap();
+		assert_eq!(created.after, Some(json!({ "apiKey": "[sensitive]", "size": 1 })));
+		assert!(!serde_json::to_string(&report).unwrap().contains("secret"));
+
+		executed_resources.insert(
+			&urn,
+			ExecutedResource::new("test".to_string(), &state("secret"), &state("rotated")),
+		);
+		let report = PlanReport::new(&executed_resources);
+		let resource = &report.resources[0];
+		assert_eq!(resource.action, "update");
+		assert_eq!(resource.config, json!({ "apiKey": "[sensitive]", "size": 1 }));
+		assert!(!resource.changes.is_empty());
+		for change in &resource.changes {
+			assert_eq!(change.before, Some(json!("[sensitive]")), "{}", change.path);
+			assert_eq!(change.after, Some(json!("[sensitive]")), "{}", change.path);
+		}
+		let report = serde_json::to_string(&report).unwrap();
+		assert!(!report.contains("secret") && !report.contains("rotated"));
+	}
+
+	#[test]
+	fn nested_sensitive_values_are_redacted() {
+		let state = |password: &str| -> RawState {
+			json!({
+				"__config": {
+					"__value": {
+						"credentials": { "user": "admin", "password": password },
+						"replicas": [{ "password": password }],
+					},
+					"__sensitive": false,
+				},
+				"password": { "__value": password, "__sensitive": true },
+				"endpoint": {
+					"__value": { "host": "db", "token": { "__value": password, "__sensitive": true } },
+					"__sensitive": false,
+				},
+			})
+			.into()
+		};
+		let mut sensitive_paths = state("secret").sensitive_paths();
+		sensitive_paths.sort();
+		assert_eq!(sensitive_paths, vec!["endpoint.token", "password"]);
+
+		let urn = Urn::from_str("urn:provider:test:resource").unwrap();
+		let mut executed_resources = ExecutedResources::default();
+		executed_resources.insert(
+			&urn,
+			ExecutedResource::new("test".to_string(), &state("secret"), &state("rotated")),
+		);
+		let report = PlanReport::new(&executed_resources);
+		let resource = &report.resources[0];
+		assert_eq!(
+			resource.config,
+			json!({
+				"credentials": { "user": "admin", "password": "[sensitive]" },
+				"replicas": [{ "password": "[sensitive]" }],
+			})
+		);
+		let paths = resource.changes.iter().map(|change| change.path.as_str()).collect::<Vec<_>>();
+		assert!(paths.contains(&"config.credentials.password"), "{paths:?}");
+		let report = serde_json::to_string(&report).unwrap();
+		assert!(!report.contains("secret") && !report.contains("rotated"), "{report}");
+	}
+}
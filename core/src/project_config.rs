@@ -0,0 +1,70 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::{backend::BackendConfig, mashin_dir::MashinDir, Result};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// Project settings, stored in `.mashin/config.json`.
+///
+/// ```json
+/// {
+///   "backend": {
+///     "type": "http",
+///     "url": "https://state.example.com/my-project",
+///     "lockTimeout": 60
+///   },
+///   "keyCommand": "vault-cli read mashin/prod"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectConfig {
+	/// Where the state is stored, default to the local state
+	#[serde(default)]
+	pub backend: BackendConfig,
+	/// Command printing the state secret on stdout, used instead of the
+	/// `MASHIN_PASSPHRASE` env variable
+	pub key_command: Option<String>,
+	/// What the key command prints, default to a passphrase
+	#[serde(default)]
+	pub key_command_output: KeyCommandOutput,
+}
+
+/// Secret printed by the key command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyCommandOutput {
+	/// Passphrase, the state key is derived with the project salt
+	#[default]
+	Passphrase,
+	/// State key itself, 32 bytes encoded in base64
+	Key,
+}
+
+impl ProjectConfig {
+	/// Load the project config, use the default config if the file doesn't exist.
+	pub fn load(mashin_dir: &MashinDir) -> Result<Self> {
+		let config_path = mashin_dir.config_file_path();
+		if !config_path.exists() {
+			return Ok(Self::default())
+		}
+
+		let raw_config = fs::read(&config_path)?;
+		serde_json::from_slice(&raw_config)
+			.map_err(|err| anyhow!("invalid project config `{}`; {err}", config_path.display()))
+	}
+}
@@ -17,6 +17,7 @@ use super::trim_sensitive::fold_json;
 use crate::Result;
 use console::style;
 use mashin_sdk::{ResourceDiff, KEY_VALUE};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{collections::HashSet, fmt, ops::Deref};
 
@@ -34,7 +35,7 @@ impl<T> Indexes for Vec<T> {
 	}
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Path {
 	Root,
 	Keys(Vec<Key>),
@@ -80,7 +81,8 @@ impl fmt::Display for Path {
 	}
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct StateDiff {
 	resources: Vec<StateResourceDiff>,
 }
@@ -99,7 +101,7 @@ impl StateDiff {
 	}
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct StateResourceDiff {
 	path: Path,
 	lhs: Option<Value>,
@@ -178,6 +180,27 @@ impl StateResourceDiff {
 			return Ok(Some(style(LINE).green().bold().to_string()))
 		}
 
+		if self.is_delete() {
+			let mut diff_print = self.rhs().clone().unwrap_or_default().to_string();
+
+			if let Some(old_state) = self.rhs() {
+				if old_state.is_object() {
+					diff_print = mashin_sdk::ext::serde_json::to_string_pretty(old_state)?
+						.split('\n')
+						.collect::<Vec<_>>()
+						.join("\n   |     - ");
+				}
+			}
+			log::info!(
+				"   {}     {} {}: {}",
+				style("|").red().bold(),
+				style("-").red().bold(),
+				style(self.path().to_string()).red().bold(),
+				style(diff_print).red().bold()
+			);
+			return Ok(Some(style(LINE).red().bold().to_string()))
+		}
+
 		if self.is_update() {
 			let mut diff_new = style(self.lhs().clone().unwrap_or_default().to_string())
 				.green()
@@ -252,7 +275,7 @@ struct DiffFolder<'a> {
 	acc: &'a mut Vec<StateResourceDiff>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Key {
 	Idx(usize),
 	Field(String),
@@ -305,10 +328,30 @@ macro_rules! direct_compare {
 }
 
 impl<'a> DiffFolder<'a> {
-	direct_compare!(on_null);
 	direct_compare!(on_bool);
 	direct_compare!(on_string);
 
+	fn on_null(&mut self, lhs: Value) {
+		// the resource is removed, we list all fields of the previous state
+		if self.path == Path::Root {
+			if let Some(rhs) = self.rhs.as_object() {
+				for (key, value) in rhs {
+					let path = self.path.append(Key::Field(key.clone()));
+					diff_with(Value::Null, value.clone(), path, self.acc);
+				}
+				return
+			}
+		}
+
+		if self.rhs != lhs {
+			self.acc.push(StateResourceDiff {
+				lhs: Some(lhs),
+				rhs: Some(self.rhs.clone()),
+				path: self.path.clone(),
+			});
+		}
+	}
+
 	fn on_number(&mut self, lhs: Value) {
 		let is_equal = self.rhs == lhs;
 		if !is_equal {
@@ -0,0 +1,172 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{
+	derive_key,
+	key::{is_derived_key, key_check_urn},
+	project_salt, EncryptedState, RawState, StateOperation,
+};
+use crate::{backend::BackendState, mashin_dir::MashinDir, Result};
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use mashin_sdk::Urn;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{pwhash::Salt, secretbox};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// Version of the export format
+const EXPORT_VERSION: u32 = 1;
+
+/// Portable copy of the whole state, used to move a project between
+/// machines or backends.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateExport {
+	pub version: u32,
+	/// RFC 3339 date of the export
+	pub created_at: String,
+	pub mashin_version: String,
+	#[serde(flatten)]
+	pub entries: ExportedEntries,
+}
+
+/// Entries of the export, keyed by URN.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum ExportedEntries {
+	/// Entries as stored within the backend. The project salt is required to
+	/// derive the key on another machine.
+	Encrypted { salt: String, entries: BTreeMap<String, EncryptedState> },
+	/// Decrypted entries, sensitive values are written in clear.
+	Plaintext { entries: BTreeMap<String, RawState> },
+}
+
+impl StateExport {
+	/// Export every entry of the state, the key is only required for a
+	/// plaintext export.
+	pub fn create(
+		mashin_dir: &MashinDir,
+		backend: &BackendState,
+		key: Option<&secretbox::Key>,
+		mashin_version: &str,
+	) -> Result<Self> {
+		let mut entries = BTreeMap::new();
+		for urn in backend.entries()? {
+			if let Some(state) = backend.get(&urn)? {
+				entries.insert(urn.to_string(), state);
+			}
+		}
+
+		let entries = match key {
+			Some(key) => ExportedEntries::Plaintext {
+				entries: entries
+					.into_iter()
+					.map(|(urn, state)| Ok((urn, state.decrypt(key)?)))
+					.collect::<Result<_>>()?,
+			},
+			None if !is_derived_key(mashin_dir)? => bail!(
+				"the state key is not derived from a passphrase, use a plaintext export to move it"
+			),
+			None => ExportedEntries::Encrypted {
+				salt: general_purpose::STANDARD.encode(project_salt(mashin_dir)?.0),
+				entries,
+			},
+		};
+
+		Ok(Self {
+			version: EXPORT_VERSION,
+			created_at: Utc::now().to_rfc3339(),
+			mashin_version: mashin_version.to_string(),
+			entries,
+		})
+	}
+
+	pub fn from_slice(raw_export: &[u8]) -> Result<Self> {
+		let export: Self = serde_json::from_slice(raw_export)
+			.map_err(|err| anyhow!("invalid state export; {err}"))?;
+		if export.version > EXPORT_VERSION {
+			bail!(
+				"state export version {} is not supported, upgrade mashin to import it",
+				export.version
+			)
+		}
+		Ok(export)
+	}
+
+	/// Write the entries into the backend, encrypted with the project key.
+	/// Existing entries are only overwritten with `force`.
+	///
+	/// Returns the number of entries imported.
+	pub fn import(
+		&self,
+		backend: &BackendState,
+		passphrase: &[u8],
+		key: &secretbox::Key,
+		force: bool,
+	) -> Result<usize> {
+		let entries = match &self.entries {
+			ExportedEntries::Plaintext { entries } => entries
+				.iter()
+				.map(|(urn, state)| Ok((urn, state.encrypt(key)?)))
+				.collect::<Result<Vec<_>>>()?,
+			ExportedEntries::Encrypted { salt, entries } => {
+				let salt = general_purpose::STANDARD
+					.decode(salt)
+					.ok()
+					.and_then(|salt| Salt::from_slice(&salt))
+					.ok_or(anyhow!("invalid salt in the state export"))?;
+				let export_key = derive_key(passphrase, salt)?;
+				entries
+					.iter()
+					.map(|(urn, state)| {
+						let state = state.decrypt(&export_key).map_err(|_| {
+							anyhow!("invalid passphrase, unable to decrypt the state export")
+						})?;
+						Ok((urn, state.encrypt(key)?))
+					})
+					.collect::<Result<Vec<_>>>()?
+			},
+		};
+
+		// the key check is bound to the project key, the backend has its own
+		let key_check_urn = key_check_urn()?;
+		let mut operations = Vec::new();
+		let mut existing_urns = Vec::new();
+		for (urn, state) in entries {
+			let urn = Urn::from_str(urn)
+				.map_err(|err| anyhow!("invalid urn `{urn}` in export; {err}"))?;
+			if urn == key_check_urn {
+				continue
+			}
+			if backend.get(&urn)?.is_some() {
+				existing_urns.push(urn.as_display());
+			}
+			operations.push(StateOperation::Save(urn, state));
+		}
+
+		if !force && !existing_urns.is_empty() {
+			bail!(
+				"{} already exist in the state, use `--force` to overwrite them",
+				existing_urns.join(", ")
+			)
+		}
+
+		let total_entries = operations.len();
+		backend.apply_batch(operations)?;
+
+		Ok(total_entries)
+	}
+}
@@ -15,10 +15,14 @@
 
 use crate::{
 	sdk::{
-		ext::{anyhow::bail, async_trait::async_trait, serde_json},
-		Result, Urn,
+		ext::{
+			anyhow::{anyhow, bail},
+			async_trait::async_trait,
+			serde_json,
+		},
+		LockInfo, Result, Urn,
 	},
-	EncryptedState, StateHandler,
+	EncryptedState, StateHandler, StateOperation,
 };
 use rkv::{
 	backend::{SafeMode, SafeModeEnvironment},
@@ -27,6 +31,7 @@ use rkv::{
 use std::{
 	collections::BTreeSet,
 	fs,
+	io::ErrorKind,
 	path::PathBuf,
 	sync::{Arc, RwLock},
 };
@@ -53,6 +58,38 @@ impl FileState {
 		let db = manager.get_or_create(db_path.as_path(), Rkv::new::<SafeMode>).unwrap();
 		Ok(Self { db, path: db_path })
 	}
+
+	/// Open an existing state, nothing is created.
+	pub fn open(db_path: PathBuf) -> Result<Self> {
+		if !db_path.is_dir() {
+			bail!("no state found within `{}`", db_path.display());
+		}
+
+		let mut manager = Manager::<SafeModeEnvironment>::singleton().write().unwrap();
+		let db = manager.get_or_create(db_path.as_path(), Rkv::new::<SafeMode>).unwrap();
+		Ok(Self { db, path: db_path })
+	}
+
+	/// Lock file beside the state folder, eg; `.mashin/state.lock`
+	fn lock_file_path(&self) -> PathBuf {
+		self.path.with_extension("lock")
+	}
+
+	/// Read the state from disk again. The safe mode env is loaded in memory
+	/// when opened and each commit rewrites the whole file, so a run waiting
+	/// for the lock would overwrite the changes of the lock holder.
+	fn reload(&self) -> Result<()> {
+		let mut env = self.db.write().or_else(|_| bail!("unable to get env"))?;
+		*env = Rkv::new::<SafeMode>(self.path.as_path())?;
+		Ok(())
+	}
+
+	fn lock_holder(&self) -> Result<LockInfo> {
+		let lock_path = self.lock_file_path();
+		let raw_lock = fs::read(&lock_path)?;
+		serde_json::from_slice(&raw_lock)
+			.map_err(|err| anyhow!("invalid lock file `{}`; {err}", lock_path.display()))
+	}
 }
 
 #[async_trait]
@@ -109,4 +146,128 @@ impl StateHandler for FileState {
 
 		writer.commit().map_err(Into::into)
 	}
+	fn apply_batch(&self, operations: Vec<StateOperation>) -> Result<()> {
+		let env = self.db.read().or_else(|_| bail!("unable to get env"))?;
+		let store = env.open_single("state", StoreOptions::create())?;
+		let mut writer = env.write()?;
+
+		// the transaction is aborted if the writer is dropped before the commit
+		for operation in operations {
+			match operation {
+				StateOperation::Save(urn, state) => {
+					let raw_json = serde_json::to_string(&state)?;
+					store.put(&mut writer, &urn, &rkv::Value::Str(&raw_json))?;
+				},
+				StateOperation::Delete(urn) => store.delete(&mut writer, &urn)?,
+			}
+		}
+
+		writer.commit().map_err(Into::into)
+	}
+
+	fn try_lock(&self, lock: &LockInfo) -> Result<Option<LockInfo>> {
+		let lock_path = self.lock_file_path();
+
+		// the lock is written to a temporary file then linked, the link fails if
+		// the lock already exists and a reader never sees a partial lock file
+		let tmp_path = self.path.with_extension(format!("lock.{}.tmp", lock.id));
+		fs::write(&tmp_path, serde_json::to_vec(lock)?)?;
+		let linked = fs::hard_link(&tmp_path, &lock_path);
+		fs::remove_file(&tmp_path)?;
+
+		match linked {
+			Ok(()) => {
+				self.reload()?;
+				Ok(None)
+			},
+			Err(err) if err.kind() == ErrorKind::AlreadyExists => self.lock_holder().map(Some),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	fn unlock(&self, lock: &LockInfo) -> Result<()> {
+		if !self.lock_file_path().exists() {
+			return Ok(())
+		}
+
+		let holder = self.lock_holder()?;
+		if holder.id != lock.id {
+			bail!("the state lock is held by {holder}");
+		}
+
+		fs::remove_file(self.lock_file_path()).map_err(Into::into)
+	}
+
+	fn force_unlock(&self) -> Result<()> {
+		match fs::remove_file(self.lock_file_path()) {
+			Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::RawState;
+	use serde_json::json;
+	use sodiumoxide::crypto::secretbox;
+	use std::str::FromStr;
+
+	#[test]
+	fn lock_holder_reads_the_state_written_while_waiting() {
+		let root = tempfile::tempdir().unwrap();
+		let db_path = root.path().join("state");
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+
+		// loaded in memory before the lock is acquired
+		let waiting = FileState::new(db_path.clone()).unwrap();
+
+		// written by the lock holder, another process
+		let holder_urn = Urn::from_str("urn:provider:test:holder").unwrap();
+		let holder = Rkv::new::<SafeMode>(db_path.as_path()).unwrap();
+		let store = holder.open_single("state", StoreOptions::create()).unwrap();
+		let mut writer = holder.write().unwrap();
+		let raw_json =
+			serde_json::to_string(&RawState::from(json!(1)).encrypt(&key).unwrap()).unwrap();
+		store.put(&mut writer, &holder_urn, &rkv::Value::Str(&raw_json)).unwrap();
+		writer.commit().unwrap();
+
+		let lock = LockInfo::current();
+		assert!(waiting.try_lock(&lock).unwrap().is_none());
+		let waiting_urn = Urn::from_str("urn:provider:test:waiting").unwrap();
+		waiting
+			.save(&waiting_urn, &RawState::from(json!(2)).encrypt(&key).unwrap())
+			.unwrap();
+		waiting.unlock(&lock).unwrap();
+
+		let on_disk = Rkv::new::<SafeMode>(db_path.as_path()).unwrap();
+		let store = on_disk.open_single("state", StoreOptions::create()).unwrap();
+		let reader = on_disk.read().unwrap();
+		assert!(store.get(&reader, &holder_urn).unwrap().is_some());
+		assert!(store.get(&reader, &waiting_urn).unwrap().is_some());
+	}
+
+	#[test]
+	fn lock_file_is_complete_once_visible() {
+		let root = tempfile::tempdir().unwrap();
+		let state = FileState::new(root.path().join("state")).unwrap();
+
+		let lock = LockInfo::current();
+		assert!(state.try_lock(&lock).unwrap().is_none());
+		let holder = state.try_lock(&LockInfo::current()).unwrap().expect("locked");
+		assert_eq!(holder.id, lock.id);
+
+		// only the lock file is left beside the state
+		let mut files = fs::read_dir(root.path())
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+			.collect::<Vec<_>>();
+		files.sort();
+		assert_eq!(files, vec!["state", "state.lock"]);
+
+		state.unlock(&lock).unwrap();
+		assert!(!state.lock_file_path().exists());
+	}
 }
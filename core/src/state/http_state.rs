@@ -0,0 +1,473 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{EncryptedState, StateHandler};
+use crate::Result;
+use anyhow::{anyhow, bail};
+use mashin_sdk::{LockInfo, Urn};
+use reqwest::{
+	blocking::{Client, RequestBuilder},
+	header::{HeaderMap, HeaderName, HeaderValue},
+	Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, env, thread, time::Duration};
+use url::Url;
+
+/// Overwrite the `authToken` of the project config, prevent storing secrets in the project.
+pub const HTTP_AUTH_TOKEN_ENV: &str = "MASHIN_HTTP_AUTH_TOKEN";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpStateConfig {
+	/// Base URL of the state server, eg; `https://state.example.com/my-project`
+	pub url: String,
+	/// Header used to authenticate the requests
+	#[serde(default = "default_auth_header")]
+	pub auth_header: String,
+	/// Value of the auth header, eg; `Bearer xxx`
+	pub auth_token: Option<String>,
+	/// How many seconds to wait for the lock, fail immediately by default
+	#[serde(default)]
+	pub lock_timeout: u64,
+}
+
+fn default_auth_header() -> String {
+	"Authorization".to_string()
+}
+
+/// Remote state shared through a REST server. The states are encrypted
+/// before they leave the engine, the server only stores opaque strings.
+///
+/// - `GET    {url}/state`       list all URNs (JSON array)
+/// - `GET    {url}/state/{urn}` get the state, `404` when not found
+/// - `PUT    {url}/state/{urn}` save the state (JSON string)
+/// - `DELETE {url}/state/{urn}` delete the state
+/// - `LOCK   {url}/lock`        acquire the lock, responds `409` or `423` with the
+///                              current holder when already locked
+/// - `UNLOCK {url}/lock`        release the lock, `?force=true` whoever holds it
+/// - `GET    {url}/metadata/{name}` get the project metadata, `404` when not found
+/// - `PUT    {url}/metadata/{name}` save the project metadata (JSON string)
+/// - `DELETE {url}/metadata/{name}` delete the project metadata
+///
+/// `{urn}` is a single path segment, percent-encoded (RFC 3986) and decoded by
+/// the server: at least `?`, `#`, `/`, `%` and the spaces are encoded, the
+/// other characters of the URN may be sent as-is. For example the state of
+/// `urn:provider:aws:s3:bucket?=logs` is at
+/// `{url}/state/urn:provider:aws:s3:bucket%3F=logs`. The URNs listed by
+/// `GET {url}/state` are not encoded.
+///
+/// The salt, the recipients and the snapshots are stored as metadata, everyone
+/// sharing `.mashin/config.json` is able to decrypt the remote state and its
+/// history.
+pub struct HttpState {
+	url: Url,
+	client: Client,
+	lock_timeout: Duration,
+}
+
+impl std::fmt::Debug for HttpState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("HttpState").field("url", &self.url.as_str()).finish()
+	}
+}
+
+impl HttpState {
+	pub fn new(config: &HttpStateConfig) -> Result<Self> {
+		let url = Url::parse(&config.url)?;
+		if url.cannot_be_a_base() {
+			bail!("invalid state backend url `{}`", config.url);
+		}
+
+		let mut headers = HeaderMap::new();
+		if let Some(token) = env::var(HTTP_AUTH_TOKEN_ENV).ok().or(config.auth_token.clone()) {
+			let mut value = HeaderValue::from_str(&token)?;
+			value.set_sensitive(true);
+			headers.insert(HeaderName::from_bytes(config.auth_header.as_bytes())?, value);
+		}
+
+		let client = off_runtime(|| {
+			Client::builder()
+				.user_agent(format!("mashin_core/{}", env!("CARGO_PKG_VERSION")))
+				.default_headers(headers)
+				.build()
+				.map_err(Into::into)
+		})?;
+
+		Ok(Self { url, client, lock_timeout: Duration::from_secs(config.lock_timeout) })
+	}
+
+	/// Default time to wait for the lock.
+	pub fn lock_timeout(&self) -> Duration {
+		self.lock_timeout
+	}
+
+	fn endpoint(&self, segments: &[&str]) -> Url {
+		let mut url = self.url.clone();
+		url.path_segments_mut()
+			.expect("checked on init")
+			.pop_if_empty()
+			.extend(segments);
+		url
+	}
+
+	/// Project metadata, eg; the salt, see `Metadata`.
+	pub fn get_metadata(&self, name: &str) -> Result<Option<String>> {
+		let response = self.send(self.client.get(self.endpoint(&["metadata", name])))?;
+		if response.0 == StatusCode::NOT_FOUND {
+			return Ok(None)
+		}
+
+		Ok(Some(serde_json::from_str(&ensure_success(response)?)?))
+	}
+
+	pub fn save_metadata(&self, name: &str, value: &str) -> Result<()> {
+		let request = self.client.put(self.endpoint(&["metadata", name])).json(value);
+		ensure_success(self.send(request)?).map(|_| ())
+	}
+
+	pub fn delete_metadata(&self, name: &str) -> Result<()> {
+		let response = self.send(self.client.delete(self.endpoint(&["metadata", name])))?;
+		if response.0 == StatusCode::NOT_FOUND {
+			return Ok(())
+		}
+
+		ensure_success(response).map(|_| ())
+	}
+
+	/// Send the request and read the response body.
+	fn send(&self, request: RequestBuilder) -> Result<(StatusCode, String)> {
+		off_runtime(|| {
+			let response = request.send()?;
+			Ok((response.status(), response.text()?))
+		})
+	}
+}
+
+/// `reqwest::blocking` panics within the async runtime of the engine,
+/// the requests are sent from a dedicated thread.
+pub(crate) fn off_runtime<R, F>(f: F) -> Result<R>
+where
+	R: Send,
+	F: FnOnce() -> Result<R> + Send,
+{
+	thread::scope(|scope| {
+		scope.spawn(f).join().map_err(|_| anyhow!("state backend thread panicked"))?
+	})
+}
+
+fn ensure_success((status, body): (StatusCode, String)) -> Result<String> {
+	if !status.is_success() {
+		bail!("state backend responded with `{status}`; {body}");
+	}
+	Ok(body)
+}
+
+impl StateHandler for HttpState {
+	fn get(&self, urn: &Urn) -> Result<Option<EncryptedState>> {
+		let response = self.send(self.client.get(self.endpoint(&["state", urn.as_str()])))?;
+		if response.0 == StatusCode::NOT_FOUND {
+			return Ok(None)
+		}
+
+		Ok(Some(serde_json::from_str(&ensure_success(response)?)?))
+	}
+
+	fn save(&self, urn: &Urn, state: &EncryptedState) -> Result<()> {
+		let request = self.client.put(self.endpoint(&["state", urn.as_str()])).json(state);
+		ensure_success(self.send(request)?).map(|_| ())
+	}
+
+	fn delete(&self, urn: &Urn) -> Result<()> {
+		let response = self.send(self.client.delete(self.endpoint(&["state", urn.as_str()])))?;
+		if response.0 == StatusCode::NOT_FOUND {
+			return Ok(())
+		}
+
+		ensure_success(response).map(|_| ())
+	}
+
+	fn resources(&self) -> Result<BTreeSet<Urn>> {
+		let response = self.send(self.client.get(self.endpoint(&["state"])))?;
+		Ok(serde_json::from_str(&ensure_success(response)?)?)
+	}
+
+	fn try_lock(&self, lock: &LockInfo) -> Result<Option<LockInfo>> {
+		let request = self
+			.client
+			.request(Method::from_bytes(b"LOCK")?, self.endpoint(&["lock"]))
+			.json(lock);
+
+		match self.send(request)? {
+			(StatusCode::CONFLICT | StatusCode::LOCKED, holder) =>
+				Ok(Some(serde_json::from_str(&holder)?)),
+			response => ensure_success(response).map(|_| None),
+		}
+	}
+
+	fn unlock(&self, lock: &LockInfo) -> Result<()> {
+		let request = self
+			.client
+			.request(Method::from_bytes(b"UNLOCK")?, self.endpoint(&["lock"]))
+			.json(lock);
+		ensure_success(self.send(request)?).map(|_| ())
+	}
+
+	fn force_unlock(&self) -> Result<()> {
+		let mut url = self.endpoint(&["lock"]);
+		url.set_query(Some("force=true"));
+		let request = self.client.request(Method::from_bytes(b"UNLOCK")?, url);
+		ensure_success(self.send(request)?).map(|_| ())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		backend::BackendState, mashin_dir::MashinDir, state::key::project_salt, RawState, Snapshot,
+	};
+	use sodiumoxide::crypto::secretbox;
+	use std::{
+		collections::HashMap,
+		fs,
+		io::{BufRead, BufReader, Read, Write},
+		net::TcpListener,
+		str::FromStr,
+		sync::{Arc, Mutex},
+	};
+
+	/// In-memory state server implementing the routes of [`HttpState`].
+	fn serve() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/project", listener.local_addr().unwrap());
+		let objects = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = stream.unwrap();
+				let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+				let mut request_line = String::new();
+				reader.read_line(&mut request_line).unwrap();
+				let mut parts = request_line.split_whitespace();
+				let method = parts.next().unwrap_or_default().to_string();
+				let target = parts.next().unwrap_or_default().to_string();
+
+				let mut content_length = 0;
+				loop {
+					let mut header = String::new();
+					reader.read_line(&mut header).unwrap();
+					if header.trim().is_empty() {
+						break
+					}
+					if let Some((name, value)) = header.split_once(':') {
+						if name.eq_ignore_ascii_case("content-length") {
+							content_length = value.trim().parse().unwrap();
+						}
+					}
+				}
+				let mut body = vec![0; content_length];
+				reader.read_exact(&mut body).unwrap();
+				let body = String::from_utf8(body).unwrap();
+
+				let (status, response) = handle(&objects, &method, &target, body);
+				let _ = write!(
+					stream,
+					"HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{response}",
+					response.len()
+				);
+			}
+		});
+
+		url
+	}
+
+	fn handle(
+		objects: &Mutex<HashMap<String, String>>,
+		method: &str,
+		target: &str,
+		body: String,
+	) -> (&'static str, String) {
+		let mut objects = objects.lock().unwrap();
+		let (path, query) = target.split_once('?').unwrap_or((target, ""));
+		let key = percent_decode(path.trim_start_matches("/project/"));
+
+		match (method, key.as_str()) {
+			("GET", "state") => {
+				let urns =
+					objects.keys().filter_map(|key| key.strip_prefix("state/")).collect::<Vec<_>>();
+				("200 OK", serde_json::to_string(&urns).unwrap())
+			},
+			("LOCK", "lock") => match objects.get("lock") {
+				Some(holder) => ("423 Locked", holder.clone()),
+				None => {
+					objects.insert(key, body);
+					("200 OK", String::new())
+				},
+			},
+			("UNLOCK", "lock") => {
+				let is_holder = |holder: &String| {
+					let holder = serde_json::from_str::<LockInfo>(holder).unwrap();
+					serde_json::from_str::<LockInfo>(&body)
+						.map_or(false, |lock| lock.id == holder.id)
+				};
+				match objects.get("lock") {
+					Some(holder) if query != "force=true" && !is_holder(holder) =>
+						("409 Conflict", holder.clone()),
+					_ => {
+						objects.remove("lock");
+						("200 OK", String::new())
+					},
+				}
+			},
+			("GET", _) => match objects.get(&key) {
+				Some(value) => ("200 OK", value.clone()),
+				None => ("404 Not Found", String::new()),
+			},
+			("PUT", _) => {
+				objects.insert(key, body);
+				("200 OK", String::new())
+			},
+			("DELETE", _) => match objects.remove(&key) {
+				Some(_) => ("200 OK", String::new()),
+				None => ("404 Not Found", String::new()),
+			},
+			_ => ("405 Method Not Allowed", String::new()),
+		}
+	}
+
+	fn percent_decode(path: &str) -> String {
+		let mut bytes = path.bytes();
+		let mut decoded = Vec::new();
+		while let Some(byte) = bytes.next() {
+			if byte == b'%' {
+				let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+				let hex = std::str::from_utf8(&hex).unwrap();
+				decoded.push(u8::from_str_radix(hex, 16).unwrap());
+			} else {
+				decoded.push(byte);
+			}
+		}
+		String::from_utf8(decoded).unwrap()
+	}
+
+	fn http_state(url: &str) -> HttpState {
+		HttpState::new(&HttpStateConfig {
+			url: url.to_string(),
+			auth_header: default_auth_header(),
+			auth_token: None,
+			lock_timeout: 0,
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn get_save_delete_and_list() {
+		sodiumoxide::init().unwrap();
+		let state = http_state(&serve());
+		let key = secretbox::gen_key();
+		let urn = Urn::from_str("urn:provider:aws:s3:bucket?=logs").unwrap();
+		assert_eq!(
+			state.endpoint(&["state", urn.as_str()]).path(),
+			"/project/state/urn:provider:aws:s3:bucket%3F=logs"
+		);
+
+		assert!(state.get(&urn).unwrap().is_none());
+		assert!(state.resources().unwrap().is_empty());
+
+		let raw_state = RawState::from(serde_json::json!({ "name": "bucket" }));
+		state.save(&urn, &raw_state.encrypt(&key).unwrap()).unwrap();
+		let saved_state = state.get(&urn).unwrap().unwrap().decrypt(&key).unwrap();
+		assert_eq!(saved_state.inner(), raw_state.inner());
+		assert_eq!(state.resources().unwrap(), BTreeSet::from([urn.clone()]));
+
+		state.delete(&urn).unwrap();
+		assert!(state.get(&urn).unwrap().is_none());
+		// deleting a missing state is not an error
+		state.delete(&urn).unwrap();
+	}
+
+	#[test]
+	fn lock_is_exclusive() {
+		let url = serve();
+		let (first, second) = (http_state(&url), http_state(&url));
+		let first_lock = LockInfo { id: "first".to_string(), ..LockInfo::current() };
+		let second_lock = LockInfo { id: "second".to_string(), ..LockInfo::current() };
+
+		assert_eq!(first.try_lock(&first_lock).unwrap(), None);
+		assert_eq!(second.try_lock(&second_lock).unwrap(), Some(first_lock.clone()));
+		// only the holder releases the lock
+		assert!(second.unlock(&second_lock).is_err());
+
+		first.unlock(&first_lock).unwrap();
+		assert_eq!(second.try_lock(&second_lock).unwrap(), None);
+
+		first.force_unlock().unwrap();
+		assert_eq!(first.try_lock(&first_lock).unwrap(), None);
+	}
+
+	#[test]
+	fn salt_is_shared_through_the_backend() {
+		let url = serve();
+		let config = serde_json::json!({ "backend": { "type": "http", "url": url } });
+
+		// two teammates sharing only the project config
+		let project = || {
+			let root = tempfile::tempdir().unwrap();
+			fs::write(root.path().join("config.json"), config.to_string()).unwrap();
+			let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+			(root, mashin_dir)
+		};
+		let (_alice_root, alice) = project();
+		let (_bob_root, bob) = project();
+
+		let salt = project_salt(&alice).unwrap();
+		assert_eq!(project_salt(&bob).unwrap().0, salt.0);
+		assert!(!alice.salt_file_path().exists());
+	}
+
+	#[test]
+	fn snapshots_are_shared_through_the_backend() {
+		sodiumoxide::init().unwrap();
+		let config = serde_json::json!({ "backend": { "type": "http", "url": serve() } });
+		let project = || {
+			let root = tempfile::tempdir().unwrap();
+			fs::write(root.path().join("config.json"), config.to_string()).unwrap();
+			let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+			(root, mashin_dir)
+		};
+		let (_alice_root, alice) = project();
+		let (_bob_root, bob) = project();
+		let key = secretbox::gen_key();
+		let urn = Urn::from_str("urn:provider:aws:s3:bucket?=logs").unwrap();
+
+		let backend = BackendState::new(&alice).unwrap();
+		let raw_state = RawState::from(serde_json::json!({ "name": "bucket" }));
+		backend.save(&urn, &raw_state.encrypt(&key).unwrap()).unwrap();
+		Snapshot::create(&alice, &backend, &key, "0.1.0", "main.ts").unwrap();
+		assert!(!alice.snapshots_folder_path().exists());
+
+		// the next snapshot follows the shared history
+		let snapshot = Snapshot::create(&bob, &backend, &key, "0.1.0", "main.ts").unwrap();
+		assert_eq!(snapshot.id, 2);
+		let ids = Snapshot::list(&alice).unwrap().iter().map(|s| s.id).collect::<Vec<_>>();
+		assert_eq!(ids, vec![1, 2]);
+
+		let entries = Snapshot::load(&alice, 2).unwrap().entries(&key).unwrap();
+		assert_eq!(entries.keys().collect::<Vec<_>>(), vec![&urn]);
+		assert_eq!(entries[&urn].inner(), raw_state.inner());
+	}
+}
@@ -0,0 +1,261 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{EncryptedState, StateOperation};
+use crate::{backend::BackendState, mashin_dir::MashinDir, Result};
+use anyhow::anyhow;
+use mashin_sdk::{ResourceAction, Urn};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::BTreeMap,
+	fs::{self, OpenOptions},
+	io::Write,
+	path::PathBuf,
+};
+
+/// Event of the apply journal, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum JournalEntry {
+	/// Written before the provider is called
+	Intent { urn: Urn, action: ResourceAction },
+	/// Written once the provider succeeded, without state if the resource has
+	/// been deleted
+	Result { urn: Urn, state: Option<EncryptedState> },
+	/// Written once the state is saved
+	Committed { urn: Urn },
+}
+
+/// Operation of an interrupted apply, not committed within the state.
+#[derive(Debug)]
+pub struct PendingOperation {
+	pub action: ResourceAction,
+	/// Outcome of the provider, `None` if the provider did not return
+	pub result: Option<Option<EncryptedState>>,
+}
+
+/// Write-ahead journal of the apply, stored in `.mashin/journal`.
+///
+/// Every operation is recorded before the provider is called and once the
+/// state is saved, so an interrupted apply can be reconciled on the next run.
+pub struct Journal {
+	path: PathBuf,
+}
+
+impl Journal {
+	pub fn new(mashin_dir: &MashinDir) -> Self {
+		Self { path: mashin_dir.journal_file_path() }
+	}
+
+	/// Append the entry, the journal is synced to the disk before returning.
+	pub fn record(&self, entry: &JournalEntry) -> Result<()> {
+		if let Some(parent) = self.path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		let mut line = serde_json::to_vec(entry)?;
+		line.push(b'\n');
+		file.write_all(&line)?;
+		file.sync_data()?;
+		Ok(())
+	}
+
+	/// Operations started but not committed within the state.
+	pub fn pending(&self) -> Result<BTreeMap<Urn, PendingOperation>> {
+		if !self.path.exists() {
+			return Ok(BTreeMap::new())
+		}
+
+		let mut pending = BTreeMap::new();
+		for line in fs::read_to_string(&self.path)?.lines() {
+			// the last line may be truncated by the crash
+			let entry = match serde_json::from_str::<JournalEntry>(line) {
+				Ok(entry) => entry,
+				Err(err) => {
+					log::warn!("    Skipping an invalid journal entry; {err}");
+					continue
+				},
+			};
+
+			match entry {
+				JournalEntry::Intent { urn, action } => {
+					pending.insert(urn, PendingOperation { action, result: None });
+				},
+				JournalEntry::Result { urn, state } => {
+					let operation = pending
+						.get_mut(&urn)
+						.ok_or_else(|| anyhow!("no intent recorded for `{urn}` in the journal"))?;
+					operation.result = Some(state);
+				},
+				JournalEntry::Committed { urn } => {
+					pending.remove(&urn);
+				},
+			}
+		}
+
+		Ok(pending)
+	}
+
+	/// Save the outcome recorded for the pending operations. Returns the
+	/// operations without outcome, they have to be read back from the provider.
+	pub fn reconcile_results(
+		&self,
+		backend: &BackendState,
+	) -> Result<BTreeMap<Urn, PendingOperation>> {
+		let mut operations = Vec::new();
+		let mut unresolved = BTreeMap::new();
+
+		for (urn, operation) in self.pending()? {
+			match operation.result {
+				Some(Some(state)) => operations.push(StateOperation::Save(urn, state)),
+				Some(None) => operations.push(StateOperation::Delete(urn)),
+				None => {
+					unresolved.insert(urn, operation);
+				},
+			}
+		}
+
+		backend.apply_batch(operations)?;
+		Ok(unresolved)
+	}
+
+	/// Remove the journal once all operations are committed.
+	pub fn clear(&self) -> Result<()> {
+		if self.path.exists() {
+			fs::remove_file(&self.path)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{FileState, RawState};
+	use mashin_sdk::ResourceDiff;
+	use serde_json::json;
+	use sodiumoxide::crypto::secretbox;
+	use std::{rc::Rc, str::FromStr};
+
+	fn urn(name: &str) -> Urn {
+		Urn::from_str(&format!("urn:provider:test:resource?={name}")).unwrap()
+	}
+
+	#[test]
+	fn pending_operations_are_the_uncommitted_ones() {
+		let root = tempfile::tempdir().unwrap();
+		let journal = Journal::new(&MashinDir::new(Some(root.path().to_path_buf())).unwrap());
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let state = RawState::from(json!({ "size": 1 })).encrypt(&key).unwrap();
+
+		for (name, action) in [
+			("committed", ResourceAction::Create),
+			("created", ResourceAction::Create),
+			("deleted", ResourceAction::Delete),
+			("updating", ResourceAction::Update { diff: Rc::new(ResourceDiff::new(vec![])) }),
+		] {
+			journal.record(&JournalEntry::Intent { urn: urn(name), action }).unwrap();
+		}
+		journal
+			.record(&JournalEntry::Result { urn: urn("committed"), state: Some(state.clone()) })
+			.unwrap();
+		journal.record(&JournalEntry::Committed { urn: urn("committed") }).unwrap();
+		journal
+			.record(&JournalEntry::Result { urn: urn("created"), state: Some(state) })
+			.unwrap();
+		journal
+			.record(&JournalEntry::Result { urn: urn("deleted"), state: None })
+			.unwrap();
+
+		// the crash may truncate the last entry
+		let mut file = OpenOptions::new().append(true).open(&journal.path).unwrap();
+		file.write_all(br#"{"event":"committed","urn":"#).unwrap();
+
+		let pending = journal.pending().unwrap();
+		assert_eq!(
+			pending.keys().cloned().collect::<Vec<_>>(),
+			vec![urn("created"), urn("deleted"), urn("updating")]
+		);
+		assert!(matches!(pending[&urn("created")].result, Some(Some(_))));
+		assert!(matches!(pending[&urn("deleted")].result, Some(None)));
+		assert_eq!(pending[&urn("deleted")].action, ResourceAction::Delete);
+		assert!(pending[&urn("updating")].result.is_none());
+	}
+
+	#[test]
+	fn result_without_intent_is_rejected() {
+		let root = tempfile::tempdir().unwrap();
+		let journal = Journal::new(&MashinDir::new(Some(root.path().to_path_buf())).unwrap());
+
+		journal
+			.record(&JournalEntry::Result { urn: urn("orphan"), state: None })
+			.unwrap();
+		let err = journal.pending().unwrap_err();
+		assert!(err.to_string().starts_with("no intent recorded"), "{err}");
+	}
+
+	#[test]
+	fn recorded_results_are_saved() {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		let journal = Journal::new(&mashin_dir);
+		let backend = BackendState::Local(FileState::new(mashin_dir.state_folder_path()).unwrap());
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let state = |size: u64| RawState::from(json!({ "size": size })).encrypt(&key).unwrap();
+
+		// deleted by the provider, then interrupted before the state is saved
+		backend.save(&urn("deleted"), &state(1)).unwrap();
+		journal
+			.record(&JournalEntry::Intent { urn: urn("deleted"), action: ResourceAction::Delete })
+			.unwrap();
+		journal
+			.record(&JournalEntry::Result { urn: urn("deleted"), state: None })
+			.unwrap();
+
+		// interrupted during the provider delete
+		backend.save(&urn("deleting"), &state(1)).unwrap();
+		journal
+			.record(&JournalEntry::Intent { urn: urn("deleting"), action: ResourceAction::Delete })
+			.unwrap();
+
+		journal
+			.record(&JournalEntry::Intent { urn: urn("created"), action: ResourceAction::Create })
+			.unwrap();
+		journal
+			.record(&JournalEntry::Result { urn: urn("created"), state: Some(state(2)) })
+			.unwrap();
+
+		journal
+			.record(&JournalEntry::Intent { urn: urn("creating"), action: ResourceAction::Create })
+			.unwrap();
+
+		let unresolved = journal.reconcile_results(&backend).unwrap();
+		assert_eq!(
+			unresolved.keys().cloned().collect::<Vec<_>>(),
+			vec![urn("creating"), urn("deleting")]
+		);
+		assert_eq!(
+			backend.entries().unwrap().into_iter().collect::<Vec<_>>(),
+			vec![urn("created"), urn("deleting")]
+		);
+		let created = backend.get(&urn("created")).unwrap().unwrap().decrypt(&key).unwrap();
+		assert_eq!(created.inner(), &json!({ "size": 2 }));
+
+		journal.clear().unwrap();
+		assert!(journal.pending().unwrap().is_empty());
+	}
+}
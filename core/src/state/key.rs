@@ -0,0 +1,406 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{derive_key, metadata::Metadata, Recipients, Snapshot, ENGINE_NID};
+use crate::{
+	backend::BackendState,
+	mashin_dir::{MashinDir, DEFAULT_WORKSPACE},
+	project_config::KeyCommandOutput,
+	EncryptedState, FileState, ProjectConfig, RawState, Result, StateHandler, StateOperation,
+};
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose, Engine as _};
+use mashin_sdk::{ext::serde_json::json, Urn};
+use once_cell::sync::Lazy;
+use sodiumoxide::crypto::{
+	pwhash::{self, Salt},
+	secretbox,
+};
+use std::{
+	collections::HashMap,
+	process::{Command, Stdio},
+	str::FromStr,
+	sync::Mutex,
+};
+
+/// Salt used by the projects initialized before the salt was generated randomly.
+const LEGACY_SALT: [u8; pwhash::SALTBYTES] = [
+	0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+	26, 27, 28, 29, 30, 31,
+];
+
+/// Known value encrypted within the state, used to validate the passphrase.
+const KEY_CHECK_VALUE: &str = "mashin";
+
+/// Secrets printed by the key commands, by command
+static KEY_COMMAND_OUTPUTS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(Default::default);
+
+/// Load the project salt stored beside the state, a new random salt is
+/// generated when the project is initialized. See [`Metadata`].
+pub(crate) fn project_salt(mashin_dir: &MashinDir) -> Result<Salt> {
+	if let Some(raw_salt) = Metadata::Salt.read(mashin_dir)? {
+		return Salt::from_slice(&raw_salt).ok_or(anyhow!("invalid project salt"))
+	}
+
+	let salt = if has_legacy_state(mashin_dir)? {
+		// keep the existing state readable
+		Salt(LEGACY_SALT)
+	} else {
+		sodiumoxide::init().map_err(|_| anyhow!("unable to initialize libsodium"))?;
+		pwhash::gen_salt()
+	};
+
+	Metadata::Salt.write(mashin_dir, &salt.0)?;
+	Ok(salt)
+}
+
+// salt of a project opened read-only, no salt is generated
+fn stored_salt(mashin_dir: &MashinDir) -> Result<Salt> {
+	match Metadata::Salt.peek(mashin_dir)? {
+		Some(raw_salt) => Salt::from_slice(&raw_salt).ok_or(anyhow!("invalid project salt")),
+		None if has_legacy_state(mashin_dir)? => Ok(Salt(LEGACY_SALT)),
+		None => bail!("the project has no salt"),
+	}
+}
+
+/// Whether the project holds a state encrypted before the salt was generated
+/// randomly. The salt is shared by all workspaces, only the default one can
+/// hold a legacy state, within the local backend.
+///
+/// The state folder is created as soon as the backend is opened, the entries
+/// are checked instead.
+fn has_legacy_state(mashin_dir: &MashinDir) -> Result<bool> {
+	let state_folder_path = mashin_dir.with_workspace(DEFAULT_WORKSPACE).state_folder_path();
+	if !state_folder_path.exists() {
+		return Ok(false)
+	}
+
+	Ok(!FileState::new(state_folder_path)?.resources()?.is_empty())
+}
+
+/// Derive the state key from the passphrase and the project salt.
+///
+/// When the state is encrypted for recipients, the passphrase is the identity
+/// of one of them and the data key is unwrapped with it.
+pub fn state_key(mashin_dir: &MashinDir, passphrase: &[u8]) -> Result<secretbox::Key> {
+	resolve_key(mashin_dir, passphrase, false)
+}
+
+/// Key of a project opened read-only, eg; a stack reference. Nothing is
+/// written, the project must already have a salt or a legacy state.
+pub(crate) fn existing_state_key(
+	mashin_dir: &MashinDir,
+	passphrase: &[u8],
+) -> Result<secretbox::Key> {
+	resolve_key(mashin_dir, passphrase, true)
+}
+
+fn resolve_key(
+	mashin_dir: &MashinDir,
+	passphrase: &[u8],
+	read_only: bool,
+) -> Result<secretbox::Key> {
+	let maybe_recipients =
+		if read_only { Recipients::peek(mashin_dir)? } else { Recipients::load(mashin_dir)? };
+	if let Some(recipients) = maybe_recipients {
+		return recipients.data_key(passphrase)
+	}
+
+	// the key command prints the state key itself
+	if ProjectConfig::load(mashin_dir)?.key_command_output == KeyCommandOutput::Key {
+		return general_purpose::STANDARD
+			.decode(passphrase)
+			.ok()
+			.and_then(|key| secretbox::Key::from_slice(&key))
+			.ok_or(anyhow!("invalid state key, expected 32 bytes encoded in base64"))
+	}
+
+	let salt = if read_only { stored_salt(mashin_dir)? } else { project_salt(mashin_dir)? };
+	derive_key(passphrase, salt)
+}
+
+/// Whether the state key is derived from a passphrase, instead of being
+/// unwrapped by a recipient or printed by the key command.
+pub(crate) fn is_derived_key(mashin_dir: &MashinDir) -> Result<bool> {
+	Ok(Recipients::load(mashin_dir)?.is_none() &&
+		ProjectConfig::load(mashin_dir)?.key_command_output == KeyCommandOutput::Passphrase)
+}
+
+/// Run the key command of the project and return the secret printed on
+/// stdout. A command is run once, its result is cached for the whole run.
+pub fn run_key_command(command: &str) -> Result<String> {
+	let mut outputs = KEY_COMMAND_OUTPUTS
+		.lock()
+		.map_err(|_| anyhow!("unable to read the key command cache"))?;
+	if let Some(secret) = outputs.get(command) {
+		return Ok(secret.clone())
+	}
+
+	#[cfg(not(windows))]
+	let mut key_command = Command::new("sh");
+	#[cfg(not(windows))]
+	key_command.arg("-c").arg(command);
+	#[cfg(windows)]
+	let mut key_command = Command::new("cmd");
+	#[cfg(windows)]
+	key_command.arg("/C").arg(command);
+
+	// the command may prompt the user, eg; to login to the vault
+	let output = key_command
+		.stdin(Stdio::inherit())
+		.stderr(Stdio::inherit())
+		.output()
+		.map_err(|err| anyhow!("unable to run the key command `{command}`; {err}"))?;
+
+	if !output.status.success() {
+		bail!("the key command `{command}` failed with {}", output.status);
+	}
+
+	let secret = String::from_utf8(output.stdout)
+		.map_err(|_| anyhow!("the key command `{command}` printed an invalid secret"))?
+		.trim_end_matches(&['\n', '\r'][..])
+		.to_string();
+	if secret.is_empty() {
+		bail!("the key command `{command}` printed an empty secret");
+	}
+
+	outputs.insert(command.to_string(), secret.clone());
+	Ok(secret)
+}
+
+/// Engine entry holding the known value encrypted with the project key.
+pub(crate) fn key_check_urn() -> Result<Urn> {
+	Urn::from_str(&format!("urn:{ENGINE_NID}:keycheck"))
+}
+
+/// Make sure the key is able to decrypt the project state. The key check
+/// entry is written within the state on the first run.
+pub fn verify_key(backend: &BackendState, key: &secretbox::Key) -> Result<()> {
+	let key_check_urn = key_check_urn()?;
+	let key_check_value = json!(KEY_CHECK_VALUE);
+
+	let is_valid = match backend.get(&key_check_urn)? {
+		Some(key_check) => key_check
+			.decrypt(key)
+			.map(|key_check| key_check.inner() == &key_check_value)
+			.unwrap_or(false),
+		None => {
+			// existing state created before the key check was introduced
+			let maybe_first_resource = match backend.resources()?.into_iter().next() {
+				Some(urn) => backend.get(&urn)?,
+				None => None,
+			};
+			let is_valid = maybe_first_resource.map_or(true, |state| state.decrypt(key).is_ok());
+
+			if is_valid {
+				backend.save(&key_check_urn, &RawState::from(key_check_value).encrypt(key)?)?;
+			}
+
+			is_valid
+		},
+	};
+
+	if !is_valid {
+		bail!("invalid passphrase, unable to decrypt the state of this project")
+	}
+
+	Ok(())
+}
+
+/// Same as [`verify_key`] without writing the key check, used to open the
+/// state of another project read-only.
+pub(crate) fn check_key(backend: &BackendState, key: &secretbox::Key) -> Result<()> {
+	let is_valid = match backend.get(&key_check_urn()?)? {
+		Some(key_check) => key_check
+			.decrypt(key)
+			.map(|key_check| key_check.inner() == &json!(KEY_CHECK_VALUE))
+			.unwrap_or(false),
+		None => false,
+	};
+
+	if !is_valid {
+		bail!("invalid passphrase, unable to decrypt the state of this project")
+	}
+
+	Ok(())
+}
+
+/// Re-encrypt every entry of the state with a key derived from the new
+/// passphrase, see [`reencrypt`].
+///
+/// Returns the number of entries re-encrypted.
+pub fn rekey(
+	mashin_dir: &MashinDir,
+	backend: &BackendState,
+	passphrase: &[u8],
+	new_passphrase: &[u8],
+) -> Result<usize> {
+	if Recipients::load(mashin_dir)?.is_some() {
+		bail!("the state is encrypted for recipients, manage them with `mashin state recipients`");
+	}
+	if !is_derived_key(mashin_dir)? {
+		bail!("the state key is printed by the key command, rotate it within your key provider");
+	}
+
+	let salt = project_salt(mashin_dir)?;
+	let key = derive_key(passphrase, salt)?;
+	let new_key = derive_key(new_passphrase, salt)?;
+
+	// the key check is re-encrypted last, when it already matches the new
+	// passphrase an interrupted rotation is resumed
+	if check_key(backend, &new_key).is_err() {
+		verify_key(backend, &key)?;
+	}
+
+	reencrypt(mashin_dir, backend, &key, &new_key)
+}
+
+/// Re-encrypt every entry of the state and the snapshots with `new_key`.
+///
+/// Everything is re-encrypted in memory first, nothing is written if one of
+/// the entries can't be decrypted. The entries are then written within a
+/// single batch, atomic with the local backends; the remote backends write
+/// them one by one and the snapshots are replaced one by one. An interrupted
+/// rotation is resumed by running it again with the same keys, the entries
+/// already encrypted with `new_key` are skipped.
+pub(crate) fn reencrypt(
+	mashin_dir: &MashinDir,
+	backend: &BackendState,
+	key: &secretbox::Key,
+	new_key: &secretbox::Key,
+) -> Result<usize> {
+	let key_check_urn = key_check_urn()?;
+	let mut operations = Vec::new();
+	let mut key_check = None;
+	for urn in backend.entries()? {
+		let maybe_new_state = match backend.get(&urn)? {
+			Some(state) => rotate(&state, key, new_key)
+				.map_err(|err| anyhow!("unable to re-encrypt `{urn}`; {err}"))?,
+			None => None,
+		};
+
+		if let Some(new_state) = maybe_new_state {
+			if urn == key_check_urn {
+				key_check = Some(StateOperation::Save(urn, new_state));
+			} else {
+				operations.push(StateOperation::Save(urn, new_state));
+			}
+		}
+	}
+	operations.extend(key_check);
+
+	let mut snapshots = Vec::new();
+	for mut snapshot in Snapshot::list(mashin_dir)? {
+		if snapshot.rekey(key, new_key)? {
+			snapshots.push(snapshot);
+		}
+	}
+
+	let total_entries = operations.len();
+	backend.apply_batch(operations)?;
+
+	// keep the history readable with the new key
+	for snapshot in snapshots {
+		snapshot.write(mashin_dir)?;
+	}
+
+	Ok(total_entries)
+}
+
+/// Encrypt the state with `new_key`, `None` when it is already encrypted with
+/// it by an interrupted rotation.
+pub(crate) fn rotate(
+	state: &EncryptedState,
+	key: &secretbox::Key,
+	new_key: &secretbox::Key,
+) -> Result<Option<EncryptedState>> {
+	match state.decrypt(key) {
+		// a fresh nonce is generated on every encryption
+		Ok(raw_state) => raw_state.encrypt(new_key).map(Some),
+		Err(_) if state.decrypt(new_key).is_ok() => Ok(None),
+		Err(err) => Err(err),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	use tempfile::TempDir;
+
+	fn temp_mashin_dir() -> (TempDir, MashinDir) {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).expect("valid mashin dir");
+		(root, mashin_dir)
+	}
+
+	#[test]
+	fn fresh_project_gets_a_random_salt() {
+		let (_root, mashin_dir) = temp_mashin_dir();
+
+		// the backend is opened before the key is resolved
+		FileState::new(mashin_dir.state_folder_path()).unwrap();
+		let salt = project_salt(&mashin_dir).unwrap();
+
+		assert_ne!(salt.0, LEGACY_SALT);
+		assert_eq!(fs::read(mashin_dir.salt_file_path()).unwrap(), salt.0);
+		// the stored salt is reused on the next runs
+		assert_eq!(project_salt(&mashin_dir).unwrap().0, salt.0);
+	}
+
+	#[test]
+	fn legacy_state_keeps_the_legacy_salt() {
+		let (_root, mashin_dir) = temp_mashin_dir();
+
+		sodiumoxide::init().unwrap();
+		let legacy_key = derive_key(b"passphrase", Salt(LEGACY_SALT)).unwrap();
+		let state = RawState::from(json!({ "name": "legacy" })).encrypt(&legacy_key).unwrap();
+		FileState::new(mashin_dir.state_folder_path())
+			.unwrap()
+			.save(&Urn::from_str("urn:provider:test:legacy").unwrap(), &state)
+			.unwrap();
+
+		assert_eq!(project_salt(&mashin_dir).unwrap().0, LEGACY_SALT);
+	}
+
+	#[test]
+	fn interrupted_rotation_is_resumed() {
+		let (_root, mashin_dir) = temp_mashin_dir();
+		sodiumoxide::init().unwrap();
+		let salt = project_salt(&mashin_dir).unwrap();
+		let key = derive_key(b"old", salt).unwrap();
+		let new_key = derive_key(b"new", salt).unwrap();
+
+		let backend = BackendState::Local(FileState::new(mashin_dir.state_folder_path()).unwrap());
+		verify_key(&backend, &key).unwrap();
+		let first = Urn::from_str("urn:provider:test:first").unwrap();
+		let second = Urn::from_str("urn:provider:test:second").unwrap();
+		backend.save(&first, &RawState::from(json!(1)).encrypt(&key).unwrap()).unwrap();
+		// already re-encrypted before the interruption
+		backend
+			.save(&second, &RawState::from(json!(2)).encrypt(&new_key).unwrap())
+			.unwrap();
+
+		assert_eq!(rekey(&mashin_dir, &backend, b"old", b"new").unwrap(), 2);
+		check_key(&backend, &new_key).unwrap();
+		for (urn, value) in [(first, json!(1)), (second, json!(2))] {
+			let state = backend.get(&urn).unwrap().unwrap().decrypt(&new_key).unwrap();
+			assert_eq!(state.inner(), &value);
+		}
+
+		// nothing left to re-encrypt, the rotation is complete
+		assert_eq!(rekey(&mashin_dir, &backend, b"old", b"new").unwrap(), 0);
+	}
+}
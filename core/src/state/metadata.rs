@@ -0,0 +1,135 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::{backend::BackendState, mashin_dir::MashinDir, ProjectConfig, Result};
+use anyhow::anyhow;
+use base64::{engine::general_purpose, Engine as _};
+use std::{fs, path::PathBuf};
+
+/// Plaintext metadata required to decrypt the state.
+///
+/// The remote backends store them beside the state, base64 encoded, so
+/// everyone using the same backend derives the same key. The local backends
+/// keep them within `.mashin/`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Metadata {
+	/// Salt of the key derived from the passphrase, see `project_salt`
+	Salt,
+	/// Data key sealed for every recipient, see `Recipients`
+	Recipients,
+	/// Recipients staged by an interrupted `add_recipient`, with the data key
+	/// sealed with the passphrase
+	PendingRecipients,
+}
+
+impl Metadata {
+	fn name(self) -> &'static str {
+		match self {
+			Metadata::Salt => "salt",
+			Metadata::Recipients => "recipients.json",
+			Metadata::PendingRecipients => "recipients.pending.json",
+		}
+	}
+
+	fn local_path(self, mashin_dir: &MashinDir) -> PathBuf {
+		match self {
+			Metadata::Salt => mashin_dir.salt_file_path(),
+			Metadata::Recipients => mashin_dir.recipients_file_path(),
+			Metadata::PendingRecipients =>
+				mashin_dir.recipients_file_path().with_extension("pending.json"),
+		}
+	}
+
+	/// Read the metadata, `None` when the project doesn't have it yet.
+	///
+	/// The projects switched to a remote backend before the metadata was
+	/// shared still have them within `.mashin/`, the local copy is then
+	/// uploaded so the next teammates find it.
+	pub(crate) fn read(self, mashin_dir: &MashinDir) -> Result<Option<Vec<u8>>> {
+		self.read_with(mashin_dir, true)
+	}
+
+	/// Read the metadata of a project opened read-only, the local copy is not
+	/// uploaded.
+	pub(crate) fn peek(self, mashin_dir: &MashinDir) -> Result<Option<Vec<u8>>> {
+		self.read_with(mashin_dir, false)
+	}
+
+	fn read_with(self, mashin_dir: &MashinDir, upload: bool) -> Result<Option<Vec<u8>>> {
+		let local_path = self.local_path(mashin_dir);
+		let read_local = || -> Result<Option<Vec<u8>>> {
+			Ok(local_path.exists().then(|| fs::read(&local_path)).transpose()?)
+		};
+
+		let backend = match shared_backend(mashin_dir)? {
+			Some(backend) => backend,
+			None => return read_local(),
+		};
+
+		match backend.get_metadata(self.name())? {
+			Some(value) => general_purpose::STANDARD.decode(value).map(Some).map_err(|err| {
+				anyhow!("invalid `{}` stored in the state backend; {err}", self.name())
+			}),
+			None => {
+				let maybe_value = read_local()?;
+				if let (true, Some(value)) = (upload, &maybe_value) {
+					backend.save_metadata(self.name(), &general_purpose::STANDARD.encode(value))?;
+				}
+				Ok(maybe_value)
+			},
+		}
+	}
+
+	/// Write the metadata within the backend, or `.mashin/` for the local
+	/// backends.
+	pub(crate) fn write(self, mashin_dir: &MashinDir, value: &[u8]) -> Result<()> {
+		if let Some(backend) = shared_backend(mashin_dir)? {
+			return backend.save_metadata(self.name(), &general_purpose::STANDARD.encode(value))
+		}
+
+		let local_path = self.local_path(mashin_dir);
+		if let Some(parent) = local_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		// written beside, then renamed over the previous value
+		let staged_path = local_path.with_extension("tmp");
+		fs::write(&staged_path, value)?;
+		fs::rename(staged_path, local_path).map_err(Into::into)
+	}
+
+	/// Remove the metadata, from the backend and from `.mashin/`.
+	pub(crate) fn remove(self, mashin_dir: &MashinDir) -> Result<()> {
+		if let Some(backend) = shared_backend(mashin_dir)? {
+			backend.delete_metadata(self.name())?;
+		}
+
+		let local_path = self.local_path(mashin_dir);
+		if local_path.exists() {
+			fs::remove_file(local_path)?;
+		}
+		Ok(())
+	}
+}
+
+/// Backend of the project when it shares the metadata, the local backends
+/// are not opened.
+pub(crate) fn shared_backend(mashin_dir: &MashinDir) -> Result<Option<BackendState>> {
+	let config = ProjectConfig::load(mashin_dir)?.backend;
+	if !config.shares_metadata() {
+		return Ok(None)
+	}
+
+	BackendState::from_config(mashin_dir, &config).map(Some)
+}
@@ -0,0 +1,190 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::key::key_check_urn;
+use crate::{backend::BackendState, Result, StateOperation};
+use anyhow::{anyhow, bail};
+use sodiumoxide::crypto::secretbox;
+
+/// Copy every entry of the `source` backend to the `target` backend, entry
+/// by entry. Both backends must be readable with the same key, each copied
+/// entry is read back from the target and compared with the source.
+///
+/// The target must be empty unless `overwrite` is set, the source is only
+/// cleared with `delete_source` once all entries are verified.
+///
+/// Returns the number of entries migrated.
+pub fn migrate(
+	source: &BackendState,
+	target: &BackendState,
+	key: &secretbox::Key,
+	overwrite: bool,
+	delete_source: bool,
+) -> Result<usize> {
+	let target_resources = target.resources()?;
+	if !overwrite && !target_resources.is_empty() {
+		bail!(
+			"the target backend already contains {} resources, use `--force` to overwrite them",
+			target_resources.len()
+		)
+	}
+
+	let key_check_urn = key_check_urn()?;
+	let source_entries = source.entries()?;
+
+	for urn in &source_entries {
+		let state = source
+			.get(urn)?
+			.ok_or_else(|| anyhow!("`{}` disappeared from the source backend", urn.as_display()))?;
+		let raw_state = state.decrypt(key).map_err(|_| {
+			anyhow!("unable to decrypt `{}` from the source backend", urn.as_display())
+		})?;
+
+		target.save(urn, &state)?;
+
+		let migrated_state = target
+			.get(urn)?
+			.ok_or_else(|| anyhow!("`{}` not found in the target backend", urn.as_display()))?
+			.decrypt(key)
+			.map_err(|_| {
+				anyhow!("unable to decrypt `{}` from the target backend", urn.as_display())
+			})?;
+		if migrated_state.inner() != raw_state.inner() {
+			bail!("`{}` differs between the source and the target backend", urn.as_display())
+		}
+	}
+
+	// the target may already own a key check when the source has none
+	let target_entries = target
+		.entries()?
+		.into_iter()
+		.filter(|urn| *urn != key_check_urn || source_entries.contains(urn))
+		.count();
+	if !overwrite && target_entries != source_entries.len() {
+		bail!(
+			"the target backend contains {target_entries} entries, {} expected",
+			source_entries.len()
+		)
+	}
+
+	if delete_source {
+		source.apply_batch(source_entries.iter().cloned().map(StateOperation::Delete).collect())?;
+	}
+
+	Ok(source_entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{FileState, RawState};
+	use mashin_sdk::Urn;
+	use serde_json::json;
+	use std::{collections::BTreeSet, str::FromStr};
+	use tempfile::TempDir;
+
+	fn urn(name: &str) -> Urn {
+		Urn::from_str(&format!("urn:provider:test:resource?={name}")).unwrap()
+	}
+
+	fn backend() -> (TempDir, BackendState) {
+		let root = tempfile::tempdir().unwrap();
+		let backend = BackendState::Local(FileState::new(root.path().join("state")).unwrap());
+		(root, backend)
+	}
+
+	fn save(backend: &BackendState, key: &secretbox::Key, urn: &Urn, size: u64) {
+		backend
+			.save(urn, &RawState::from(json!({ "size": size })).encrypt(key).unwrap())
+			.unwrap();
+	}
+
+	fn size(backend: &BackendState, key: &secretbox::Key, urn: &Urn) -> u64 {
+		backend.get(urn).unwrap().unwrap().decrypt(key).unwrap().inner()["size"]
+			.as_u64()
+			.unwrap()
+	}
+
+	#[test]
+	fn entries_are_copied_and_verified() {
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let (_source_root, source) = backend();
+		let (_target_root, target) = backend();
+		save(&source, &key, &key_check_urn().unwrap(), 0);
+		save(&source, &key, &urn("logs"), 1);
+		save(&source, &key, &urn("assets"), 2);
+
+		assert_eq!(migrate(&source, &target, &key, false, false).unwrap(), 3);
+		assert_eq!(target.entries().unwrap(), source.entries().unwrap());
+		assert_eq!(size(&target, &key, &urn("logs")), 1);
+		assert_eq!(size(&target, &key, &urn("assets")), 2);
+		assert_eq!(source.entries().unwrap().len(), 3);
+
+		// the resources of the target are only overwritten with `force`
+		save(&source, &key, &urn("logs"), 3);
+		let err = migrate(&source, &target, &key, false, false).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"the target backend already contains 2 resources, use `--force` to overwrite them"
+		);
+		assert_eq!(size(&target, &key, &urn("logs")), 1);
+
+		assert_eq!(migrate(&source, &target, &key, true, true).unwrap(), 3);
+		assert_eq!(size(&target, &key, &urn("logs")), 3);
+		assert!(source.entries().unwrap().is_empty());
+	}
+
+	#[test]
+	fn unexpected_target_entries_keep_the_source() {
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let (_source_root, source) = backend();
+		let (_target_root, target) = backend();
+		save(&source, &key, &urn("logs"), 1);
+
+		// engine entries are not resources, they are only found by the count
+		let engine_entry = Urn::from_str("urn:mashin:outputs").unwrap();
+		save(&target, &key, &engine_entry, 0);
+
+		let err = migrate(&source, &target, &key, false, true).unwrap_err();
+		assert_eq!(err.to_string(), "the target backend contains 2 entries, 1 expected");
+		assert_eq!(source.entries().unwrap(), BTreeSet::from([urn("logs")]));
+	}
+
+	#[test]
+	fn failed_migration_keeps_the_source() {
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let (_source_root, source) = backend();
+		let (_target_root, target) = backend();
+		save(&source, &key, &urn("assets"), 1);
+		save(&source, &key, &urn("logs"), 2);
+		// entries are migrated in order, the last one can't be decrypted
+		save(&source, &secretbox::gen_key(), &urn("queue"), 3);
+
+		let err = migrate(&source, &target, &key, false, true).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"unable to decrypt `test:resource?=queue` from the source backend"
+		);
+		assert_eq!(
+			source.entries().unwrap(),
+			BTreeSet::from([urn("assets"), urn("logs"), urn("queue")])
+		);
+		assert_eq!(size(&source, &key, &urn("logs")), 2);
+		assert_eq!(target.entries().unwrap(), BTreeSet::from([urn("assets"), urn("logs")]));
+	}
+}
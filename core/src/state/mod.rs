@@ -14,11 +14,45 @@ pub use diff::{diff, StateDiff};
  *                                                          *
 \* ---------------------------------------------------------*/
 
+pub use export::{ExportedEntries, StateExport};
 pub use file_state::FileState;
+pub use http_state::{HttpState, HttpStateConfig, HTTP_AUTH_TOKEN_ENV};
+pub use journal::{Journal, JournalEntry, PendingOperation};
+pub(crate) use key::project_salt;
+pub use key::{rekey, run_key_command, state_key, verify_key};
+pub use migrate::migrate;
+pub use output::{StackOutput, StackOutputs};
+pub use plugin_state::{PluginStateConfig, StateInner};
+pub use recipients::{add_recipient, generate_identity, remove_recipient, Recipient, Recipients};
+pub use reference::StackReferences;
+pub use s3_state::{S3State, S3StateConfig};
+pub use snapshot::Snapshot;
+#[cfg(feature = "sqlite")]
+pub use sqlite_state::SqliteState;
 pub(crate) use state_handler::derive_key;
-pub use state_handler::{EncryptedState, ProjectState, RawState, StateHandler};
+pub use state_handler::{
+	EncryptedState, ProjectState, RawState, StateEntryMetadata, StateHandler, StateOperation,
+};
 
 mod diff;
+mod export;
 mod file_state;
+mod http_state;
+mod journal;
+mod key;
+mod metadata;
+mod migrate;
+mod output;
+mod plugin_state;
+mod recipients;
+mod reference;
+mod s3_state;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite_state;
 mod state_handler;
 mod trim_sensitive;
+
+/// URN namespace reserved for the entries managed by the engine itself,
+/// they are never considered as resources.
+pub const ENGINE_NID: &str = "mashin";
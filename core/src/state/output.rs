@@ -0,0 +1,209 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::ENGINE_NID;
+use crate::{backend::BackendState, RawState, Result, StateOperation};
+use anyhow::{anyhow, bail};
+use console::style;
+use mashin_sdk::Urn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sodiumoxide::crypto::secretbox;
+use std::{collections::BTreeMap, ops::Deref, str::FromStr};
+
+/// Prefix of the NSS of the outputs, eg; `urn:mashin:output:endpoint`
+const OUTPUT_NSS_PREFIX: &str = "output:";
+
+/// Value exported by the main module, stored encrypted beside the resources.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackOutput {
+	#[serde(rename = "__value")]
+	pub value: Value,
+	#[serde(rename = "__sensitive", default)]
+	pub sensitive: bool,
+}
+
+impl StackOutput {
+	/// Value to display to the user, masked when sensitive unless
+	/// `show_sensitive` is set.
+	pub fn display_value(&self, show_sensitive: bool) -> Value {
+		if self.sensitive && !show_sensitive {
+			Value::String("[sensitive]".to_string())
+		} else {
+			self.value.clone()
+		}
+	}
+}
+
+/// Named outputs of the stack.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StackOutputs {
+	outputs: BTreeMap<String, StackOutput>,
+}
+
+impl Deref for StackOutputs {
+	type Target = BTreeMap<String, StackOutput>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.outputs
+	}
+}
+
+impl StackOutputs {
+	/// URN of the state entry holding the output.
+	pub fn urn(name: &str) -> Result<Urn> {
+		Urn::from_str(&format!("urn:{ENGINE_NID}:{OUTPUT_NSS_PREFIX}{name}"))
+			.map_err(|err| anyhow!("invalid output name `{name}`; {err}"))
+	}
+
+	/// Load all the outputs stored within the state.
+	pub fn load(backend: &BackendState, key: &secretbox::Key) -> Result<Self> {
+		let mut outputs = Self::default();
+
+		for urn in backend.entries()? {
+			let name = match Self::name(&urn) {
+				Some(name) => name,
+				None => continue,
+			};
+
+			if let Some(state) = backend.get(&urn)? {
+				let output = serde_json::from_value(state.decrypt(key)?.inner().clone())?;
+				outputs.outputs.insert(name.to_string(), output);
+			}
+		}
+
+		Ok(outputs)
+	}
+
+	/// Add an output exported by the main module, the names are unique.
+	pub fn insert(&mut self, name: &str, output: StackOutput) -> Result<()> {
+		// make sure the name can be stored within the state
+		Self::urn(name)?;
+
+		if self.outputs.insert(name.to_string(), output).is_some() {
+			bail!("output `{name}` is exported more than once");
+		}
+
+		Ok(())
+	}
+
+	/// Replace the outputs stored within the state, the outputs not exported
+	/// anymore are removed.
+	pub fn save(&self, backend: &BackendState, key: &secretbox::Key) -> Result<()> {
+		let mut operations = Vec::new();
+
+		for urn in backend.entries()? {
+			if Self::name(&urn).map_or(false, |name| !self.outputs.contains_key(name)) {
+				operations.push(StateOperation::Delete(urn));
+			}
+		}
+
+		for (name, output) in &self.outputs {
+			let state = RawState::from(serde_json::to_value(output)?);
+			operations.push(StateOperation::Save(Self::urn(name)?, state.encrypt(key)?));
+		}
+
+		backend.apply_batch(operations)
+	}
+
+	/// Print the outputs changed since the `previous` ones, returns `false`
+	/// when there is no change.
+	pub fn print_changes(&self, previous: &Self) -> bool {
+		if self == previous {
+			return false
+		}
+
+		log::info!("\n    Changes to outputs:");
+		self.print_changed_outputs(previous, "");
+
+		true
+	}
+
+	/// Print a line for every output changed since the `previous` ones, the
+	/// names are prefixed with `prefix`.
+	pub(crate) fn print_changed_outputs(&self, previous: &Self, prefix: &str) {
+		for (name, output) in &self.outputs {
+			let symbol = match previous.get(name) {
+				None => style("+").green().bold(),
+				Some(previous_output) if previous_output != output => style("*").cyan().bold(),
+				Some(_) => continue,
+			};
+			log::info!("      {symbol} {prefix}{name} = {}", output.display_value(false));
+		}
+
+		for name in previous.keys().filter(|name| !self.outputs.contains_key(*name)) {
+			log::info!("      {} {prefix}{name}", style("-").red().bold());
+		}
+	}
+
+	fn name(urn: &Urn) -> Option<&str> {
+		if urn.nid() != ENGINE_NID {
+			return None
+		}
+		urn.nss().strip_prefix(OUTPUT_NSS_PREFIX)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::FileState;
+	use serde_json::json;
+
+	fn output(value: Value, sensitive: bool) -> StackOutput {
+		StackOutput { value, sensitive }
+	}
+
+	#[test]
+	fn sensitive_outputs_are_masked() {
+		let password = output(json!("hunter2"), true);
+		assert_eq!(password.display_value(false), json!("[sensitive]"));
+		assert_eq!(password.display_value(true), json!("hunter2"));
+
+		let endpoint = output(json!({ "host": "db.local" }), false);
+		assert_eq!(endpoint.display_value(false), json!({ "host": "db.local" }));
+	}
+
+	#[test]
+	fn outputs_are_replaced_and_cleared() {
+		let root = tempfile::tempdir().unwrap();
+		let backend = BackendState::Local(FileState::new(root.path().join("state")).unwrap());
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let bucket = Urn::from_str("urn:provider:aws:s3:bucket?=logs").unwrap();
+		backend
+			.save(&bucket, &RawState::from(json!({ "size": 1 })).encrypt(&key).unwrap())
+			.unwrap();
+
+		let mut outputs = StackOutputs::default();
+		outputs.insert("endpoint", output(json!("db.local"), false)).unwrap();
+		outputs.insert("password", output(json!("hunter2"), true)).unwrap();
+		assert!(outputs.insert("password", output(json!("hunter3"), true)).is_err());
+		outputs.save(&backend, &key).unwrap();
+		assert_eq!(StackOutputs::load(&backend, &key).unwrap(), outputs);
+
+		// the outputs not exported anymore are removed
+		let mut next_outputs = StackOutputs::default();
+		next_outputs.insert("endpoint", output(json!("db.remote"), false)).unwrap();
+		next_outputs.save(&backend, &key).unwrap();
+		assert_eq!(StackOutputs::load(&backend, &key).unwrap(), next_outputs);
+
+		// cleared once the stack is destroyed, the resources are kept
+		StackOutputs::default().save(&backend, &key).unwrap();
+		assert!(StackOutputs::load(&backend, &key).unwrap().is_empty());
+		assert_eq!(backend.entries().unwrap().into_iter().collect::<Vec<_>>(), vec![bucket]);
+	}
+}
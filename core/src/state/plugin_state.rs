@@ -0,0 +1,166 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{EncryptedState, StateHandler};
+use crate::{DynamicLibraryResource, ForeignFunction, NativeType, Result};
+use anyhow::anyhow;
+use deno_core::Resource;
+use dlopen::raw::Library;
+use mashin_sdk::{LockInfo, Urn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+	borrow::Cow,
+	collections::{BTreeSet, HashMap},
+	ffi::c_void,
+	time::Duration,
+};
+
+/// Symbols generated by `mashin_sdk::construct_backend!`, except `drop`
+const BACKEND_SYMBOLS: [&str; 8] =
+	["new", "get", "save", "delete", "list", "lock", "unlock", "force_unlock"];
+
+/// State backend plugin selected in the project config.
+///
+/// ```json
+/// { "type": "plugin", "path": "./libmy_backend.so", "props": { "bucket": "my-state" } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginStateConfig {
+	/// Path of the cdylib, relative to the current directory
+	pub path: String,
+	/// Props given to the backend, the engine adds the selected `workspace`
+	#[serde(default)]
+	pub props: Value,
+	/// How many seconds to wait for the lock, fail immediately by default
+	#[serde(default)]
+	pub lock_timeout: u64,
+}
+
+/// State backend loaded from a cdylib built with `mashin_sdk::construct_backend!`.
+pub struct StateInner {
+	pub dylib: DynamicLibraryResource,
+	/// Pointer to the backend initialized by the `new` symbol
+	pub ptr: *mut c_void,
+	lock_timeout: Duration,
+}
+
+impl StateInner {
+	/// Load the backend configured for the project, before the state is
+	/// locked, so every command uses it.
+	pub fn open(config: &PluginStateConfig, workspace: &str) -> Result<Self> {
+		let lib = Library::open(&config.path)
+			.map_err(|err| anyhow!("unable to load the state backend `{}`; {err}", config.path))?;
+
+		let mut symbols = BACKEND_SYMBOLS
+			.iter()
+			.map(|name| {
+				let foreign_fn = ForeignFunction {
+					name: None,
+					parameters: vec![NativeType::Pointer, NativeType::Pointer, NativeType::USize],
+					result: NativeType::Pointer,
+				};
+				(name.to_string(), foreign_fn)
+			})
+			.collect::<HashMap<_, _>>();
+		symbols.insert(
+			"drop".to_string(),
+			ForeignFunction {
+				name: None,
+				parameters: vec![NativeType::Pointer],
+				result: NativeType::Void,
+			},
+		);
+
+		let mut props = config.props.clone();
+		if let Value::Object(props) = &mut props {
+			props.insert("workspace".to_string(), json!(workspace));
+		} else if props.is_null() {
+			props = json!({ "workspace": workspace });
+		}
+
+		let mut backend = Self::new(DynamicLibraryResource::new(lib, symbols)?, &props)?;
+		backend.lock_timeout = Duration::from_secs(config.lock_timeout);
+		Ok(backend)
+	}
+
+	/// The `new` symbol returns the address of the backend, or the error
+	/// raised while initializing it.
+	pub fn new(dylib: DynamicLibraryResource, props: &Value) -> Result<Self> {
+		let logger_ptr = Box::into_raw(Box::new(log::logger())) as *mut c_void;
+		let address = dylib
+			.call_json::<_, std::result::Result<usize, String>>("new", logger_ptr, props)?
+			.map_err(|err| anyhow!("unable to initialize the state backend; {err}"))?;
+
+		Ok(Self { dylib, ptr: address as *mut c_void, lock_timeout: Duration::ZERO })
+	}
+
+	/// Default time to wait for the lock.
+	pub fn lock_timeout(&self) -> Duration {
+		self.lock_timeout
+	}
+
+	/// The backend returns a `Result<R, String>` for every call.
+	fn call<A: Serialize, R: DeserializeOwned>(&self, symbol_name: &str, args: &A) -> Result<R> {
+		self.dylib
+			.call_json::<A, std::result::Result<R, String>>(symbol_name, self.ptr, args)?
+			.map_err(|err| anyhow!("state backend `{symbol_name}` failed; {err}"))
+	}
+}
+
+impl StateHandler for StateInner {
+	fn save(&self, urn: &Urn, state: &EncryptedState) -> Result<()> {
+		self.call("save", &json!({ "urn": urn, "state": state }))
+	}
+
+	fn get(&self, urn: &Urn) -> Result<Option<EncryptedState>> {
+		self.call("get", urn)
+	}
+
+	fn delete(&self, urn: &Urn) -> Result<()> {
+		self.call("delete", urn)
+	}
+
+	fn resources(&self) -> Result<BTreeSet<Urn>> {
+		self.call("list", &Value::Null)
+	}
+
+	fn try_lock(&self, lock: &LockInfo) -> Result<Option<LockInfo>> {
+		self.call("lock", lock)
+	}
+
+	fn unlock(&self, lock: &LockInfo) -> Result<()> {
+		self.call("unlock", lock)
+	}
+
+	fn force_unlock(&self) -> Result<()> {
+		self.call("force_unlock", &Value::Null)
+	}
+}
+
+impl Resource for StateInner {
+	fn name(&self) -> Cow<str> {
+		"stateBackend".into()
+	}
+}
+
+impl Drop for StateInner {
+	fn drop(&mut self) {
+		if let Err(err) = self.dylib.call_drop(self.ptr) {
+			log::error!("unable to drop state backend; {err}");
+		}
+	}
+}
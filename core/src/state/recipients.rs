@@ -0,0 +1,331 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{
+	key::{check_key, reencrypt, state_key},
+	metadata::Metadata,
+	verify_key,
+};
+use crate::{backend::BackendState, mashin_dir::MashinDir, EncryptedState, RawState, Result};
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sodiumoxide::crypto::{box_, sealedbox, secretbox};
+use std::{cell::RefCell, rc::Rc};
+
+/// Version of the recipients file
+const RECIPIENTS_VERSION: u32 = 1;
+
+/// Prefix of the private key of a recipient, as written by
+/// `mashin state recipients keygen`.
+const IDENTITY_PREFIX: &str = "MASHIN-IDENTITY-";
+
+/// Member of the team able to decrypt the state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recipient {
+	pub name: String,
+	/// X25519 public key, base64 encoded
+	pub public_key: String,
+	/// Data key sealed for the public key, base64 encoded
+	wrapped_key: String,
+}
+
+/// The state is encrypted with a random data key, sealed for every recipient
+/// within `.mashin/recipients.json`. Each recipient unwraps it with their
+/// own private key, the passphrase is not used anymore.
+///
+/// Adding or removing a recipient only re-wraps the data key, the entries of
+/// the state are not re-encrypted. A removed recipient who kept a copy of the
+/// data key can still decrypt the state.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recipients {
+	version: u32,
+	recipients: Vec<Recipient>,
+}
+
+impl Recipients {
+	/// Load the recipients of the workspace, `None` when the state is
+	/// encrypted with a passphrase.
+	pub fn load(mashin_dir: &MashinDir) -> Result<Option<Self>> {
+		Self::parse(Metadata::Recipients.read(mashin_dir)?)
+	}
+
+	/// Load the recipients of a project opened read-only.
+	pub(crate) fn peek(mashin_dir: &MashinDir) -> Result<Option<Self>> {
+		Self::parse(Metadata::Recipients.peek(mashin_dir)?)
+	}
+
+	fn parse(maybe_raw_recipients: Option<Vec<u8>>) -> Result<Option<Self>> {
+		let raw_recipients = match maybe_raw_recipients {
+			Some(raw_recipients) => raw_recipients,
+			None => return Ok(None),
+		};
+
+		let recipients: Self = serde_json::from_slice(&raw_recipients)
+			.map_err(|err| anyhow!("invalid recipients; {err}"))?;
+		if recipients.version > RECIPIENTS_VERSION {
+			bail!("recipients version {} is not supported, upgrade mashin", recipients.version)
+		}
+
+		Ok(Some(recipients))
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Recipient> {
+		self.recipients.iter()
+	}
+
+	/// Unwrap the data key with the private key of one of the recipients.
+	pub fn data_key(&self, identity: &[u8]) -> Result<secretbox::Key> {
+		let (public_key, secret_key) = parse_identity(identity)?;
+		let encoded_public_key = general_purpose::STANDARD.encode(public_key.0);
+
+		let recipient = self
+			.recipients
+			.iter()
+			.find(|recipient| recipient.public_key == encoded_public_key)
+			.ok_or(anyhow!("this identity is not a recipient of the state"))?;
+
+		let wrapped_key = general_purpose::STANDARD.decode(&recipient.wrapped_key)?;
+		sealedbox::open(&wrapped_key, &public_key, &secret_key)
+			.ok()
+			.and_then(|data_key| secretbox::Key::from_slice(&data_key))
+			.ok_or(anyhow!("unable to unwrap the data key of `{}`", recipient.name))
+	}
+
+	/// Seal the data key for a new recipient.
+	fn add(&mut self, name: &str, public_key: &str, data_key: &secretbox::Key) -> Result<()> {
+		if self.recipients.iter().any(|recipient| recipient.name == name) {
+			bail!("recipient `{name}` already exists");
+		}
+
+		let decoded_public_key = general_purpose::STANDARD
+			.decode(public_key.trim())
+			.ok()
+			.and_then(|public_key| box_::PublicKey::from_slice(&public_key))
+			.ok_or(anyhow!("invalid public key `{public_key}`"))?;
+
+		self.recipients.push(Recipient {
+			name: name.to_string(),
+			public_key: general_purpose::STANDARD.encode(decoded_public_key.0),
+			wrapped_key: general_purpose::STANDARD
+				.encode(sealedbox::seal(&data_key.0, &decoded_public_key)),
+		});
+
+		Ok(())
+	}
+
+	fn write(&self, mashin_dir: &MashinDir) -> Result<()> {
+		Metadata::Recipients.write(mashin_dir, &serde_json::to_vec_pretty(self)?)
+	}
+}
+
+/// Generate the private key of a recipient, returns the identity to keep
+/// secret and its public key.
+pub fn generate_identity() -> Result<(String, String)> {
+	sodiumoxide::init().map_err(|_| anyhow!("unable to initialize libsodium"))?;
+	let (public_key, secret_key) = box_::gen_keypair();
+
+	Ok((
+		format!("{IDENTITY_PREFIX}{}", general_purpose::STANDARD.encode(secret_key.0)),
+		general_purpose::STANDARD.encode(public_key.0),
+	))
+}
+
+fn parse_identity(identity: &[u8]) -> Result<(box_::PublicKey, box_::SecretKey)> {
+	let secret_key = std::str::from_utf8(identity)
+		.ok()
+		.and_then(|identity| identity.trim().strip_prefix(IDENTITY_PREFIX))
+		.and_then(|secret_key| general_purpose::STANDARD.decode(secret_key).ok())
+		.and_then(|secret_key| box_::SecretKey::from_slice(&secret_key))
+		.ok_or(anyhow!("invalid identity, the state is encrypted for recipients"))?;
+
+	Ok((secret_key.public_key(), secret_key))
+}
+
+/// Recipients of the first `add_recipient`, staged before the entries are
+/// re-encrypted. The data key is sealed with the passphrase, so an
+/// interrupted run is resumed with the same passphrase.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingRecipients {
+	recipients: Recipients,
+	data_key: EncryptedState,
+}
+
+impl PendingRecipients {
+	fn load(
+		mashin_dir: &MashinDir,
+		key: &secretbox::Key,
+	) -> Result<Option<(Recipients, secretbox::Key)>> {
+		let raw_pending = match Metadata::PendingRecipients.read(mashin_dir)? {
+			Some(raw_pending) => raw_pending,
+			None => return Ok(None),
+		};
+
+		let pending: Self = serde_json::from_slice(&raw_pending)
+			.map_err(|err| anyhow!("invalid pending recipients; {err}"))?;
+		let data_key = pending
+			.data_key
+			.decrypt(key)
+			.ok()
+			.and_then(|data_key| data_key.inner().as_str().map(str::to_string))
+			.and_then(|data_key| general_purpose::STANDARD.decode(data_key).ok())
+			.and_then(|data_key| secretbox::Key::from_slice(&data_key))
+			.ok_or(anyhow!("unable to resume the pending recipients, invalid passphrase"))?;
+
+		Ok(Some((pending.recipients, data_key)))
+	}
+
+	fn write(
+		mashin_dir: &MashinDir,
+		recipients: Recipients,
+		key: &secretbox::Key,
+		data_key: &secretbox::Key,
+	) -> Result<Recipients> {
+		let sealed_data_key =
+			RawState::from(json!(general_purpose::STANDARD.encode(data_key.0))).encrypt(key)?;
+		let pending = Self { recipients, data_key: sealed_data_key };
+		Metadata::PendingRecipients.write(mashin_dir, &serde_json::to_vec_pretty(&pending)?)?;
+
+		Ok(pending.recipients)
+	}
+}
+
+/// Allow a new recipient to decrypt the state.
+///
+/// The first recipient switches the state from the passphrase to a random
+/// data key, every entry is re-encrypted once. The `passphrase` is the
+/// current passphrase, or the identity of a recipient once switched.
+///
+/// The recipients are staged before the entries are re-encrypted and only
+/// replace the passphrase once everything is re-encrypted, an interrupted run
+/// is resumed by running it again.
+pub fn add_recipient(
+	mashin_dir: &MashinDir,
+	passphrase: &[u8],
+	name: &str,
+	public_key: &str,
+) -> Result<()> {
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+	let backend = backend.borrow();
+
+	let key = state_key(mashin_dir, passphrase)?;
+
+	if let Some(mut recipients) = Recipients::load(mashin_dir)? {
+		verify_key(&backend, &key)?;
+		recipients.add(name, public_key, &key)?;
+		recipients.write(mashin_dir)?;
+		// left by a run interrupted once the recipients were written
+		return Metadata::PendingRecipients.remove(mashin_dir)
+	}
+
+	let (mut recipients, data_key) = match PendingRecipients::load(mashin_dir, &key)? {
+		Some(pending) => pending,
+		None => {
+			verify_key(&backend, &key)?;
+			sodiumoxide::init().map_err(|_| anyhow!("unable to initialize libsodium"))?;
+			let recipients = Recipients { version: RECIPIENTS_VERSION, recipients: Vec::new() };
+			(recipients, secretbox::gen_key())
+		},
+	};
+	if recipients.iter().all(|recipient| recipient.name != name) {
+		recipients.add(name, public_key, &data_key)?;
+	}
+	let recipients = PendingRecipients::write(mashin_dir, recipients, &key, &data_key)?;
+
+	// the key check is re-encrypted last, when it already matches the data
+	// key an interrupted run is resumed
+	if check_key(&backend, &data_key).is_err() {
+		verify_key(&backend, &key)?;
+	}
+	reencrypt(mashin_dir, &backend, &key, &data_key).map_err(|err| {
+		anyhow!("unable to re-encrypt the state, run the command again to resume; {err}")
+	})?;
+
+	recipients.write(mashin_dir)?;
+	Metadata::PendingRecipients.remove(mashin_dir)
+}
+
+/// Remove a recipient, the data key is not rotated.
+pub fn remove_recipient(mashin_dir: &MashinDir, name: &str) -> Result<()> {
+	let backend = Rc::new(RefCell::new(BackendState::new(mashin_dir)?));
+	let _state_lock = BackendState::lock_guard(&backend, None)?;
+
+	let mut recipients = Recipients::load(mashin_dir)?
+		.ok_or(anyhow!("the state is encrypted with a passphrase, there is no recipient"))?;
+
+	let total_recipients = recipients.recipients.len();
+	recipients.recipients.retain(|recipient| recipient.name != name);
+
+	if recipients.recipients.len() == total_recipients {
+		bail!("recipient `{name}` not found");
+	}
+	if recipients.recipients.is_empty() {
+		bail!("`{name}` is the last recipient, the state would not be readable anymore");
+	}
+
+	recipients.write(mashin_dir)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{state::derive_key, FileState, StateHandler};
+	use mashin_sdk::Urn;
+	use sodiumoxide::crypto::pwhash;
+	use std::str::FromStr;
+
+	#[test]
+	fn interrupted_first_recipient_is_resumed() {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		sodiumoxide::init().unwrap();
+
+		let key = state_key(&mashin_dir, b"passphrase").unwrap();
+		let backend = BackendState::Local(FileState::new(mashin_dir.state_folder_path()).unwrap());
+		verify_key(&backend, &key).unwrap();
+		let resource = Urn::from_str("urn:provider:test:resource").unwrap();
+		backend
+			.save(&resource, &RawState::from(json!(1)).encrypt(&key).unwrap())
+			.unwrap();
+		// unreadable with the passphrase, the re-encryption fails
+		let broken = Urn::from_str("urn:provider:test:broken").unwrap();
+		let other_key = derive_key(b"other", pwhash::gen_salt()).unwrap();
+		backend
+			.save(&broken, &RawState::from(json!(2)).encrypt(&other_key).unwrap())
+			.unwrap();
+
+		let (identity, public_key) = generate_identity().unwrap();
+		assert!(add_recipient(&mashin_dir, b"passphrase", "alice", &public_key).is_err());
+
+		// the passphrase keeps working, the recipients are only staged
+		assert!(!mashin_dir.recipients_file_path().exists());
+		assert!(Metadata::PendingRecipients.read(&mashin_dir).unwrap().is_some());
+		verify_key(&backend, &state_key(&mashin_dir, b"passphrase").unwrap()).unwrap();
+
+		backend.delete(&broken).unwrap();
+		add_recipient(&mashin_dir, b"passphrase", "alice", &public_key).unwrap();
+
+		assert!(Metadata::PendingRecipients.read(&mashin_dir).unwrap().is_none());
+		let data_key = state_key(&mashin_dir, identity.as_bytes()).unwrap();
+		verify_key(&backend, &data_key).unwrap();
+		let state = backend.get(&resource).unwrap().unwrap().decrypt(&data_key).unwrap();
+		assert_eq!(state.inner(), &json!(1));
+	}
+}
@@ -0,0 +1,194 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{
+	key::{check_key, existing_state_key, run_key_command},
+	StackOutputs, ENGINE_NID,
+};
+use crate::{
+	backend::BackendState, mashin_dir::MashinDir, ProjectConfig, RawState, Result, StateOperation,
+};
+use anyhow::{anyhow, bail};
+use mashin_sdk::Urn;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::secretbox;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	env::current_dir,
+	ops::Deref,
+	str::FromStr,
+};
+
+/// Outputs of the other projects read by the main module, keyed by
+/// `{path}#{workspace}`. They are saved on apply, so the next plans show when
+/// an upstream value changed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StackReferences {
+	references: BTreeMap<String, StackOutputs>,
+}
+
+impl Deref for StackReferences {
+	type Target = BTreeMap<String, StackOutputs>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.references
+	}
+}
+
+impl StackReferences {
+	/// Identifier of a reference, as written in the main module.
+	pub fn id(path: &str, workspace: &str) -> String {
+		format!("{path}#{workspace}")
+	}
+
+	/// Open the state of another project read-only and return its outputs.
+	///
+	/// The `path` of its `.mashin` directory is relative to the current
+	/// directory. Its state is decrypted with the given passphrase, or the
+	/// output of its own `key_command`, then `default_passphrase`.
+	pub fn read(
+		path: &str,
+		workspace: &str,
+		maybe_passphrase: Option<&[u8]>,
+		default_passphrase: &[u8],
+	) -> Result<StackOutputs> {
+		if !workspace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+			bail!("invalid workspace name `{workspace}`");
+		}
+
+		let root = current_dir()?.join(path);
+		if !root.is_dir() {
+			bail!("stack reference `{path}` not found");
+		}
+
+		let mashin_dir = MashinDir::new(Some(root.canonicalize()?))?.with_workspace(workspace);
+		let id = Self::id(path, workspace);
+
+		// opening the project must not write anything within it
+		let backend = BackendState::open_existing(&mashin_dir)
+			.map_err(|err| anyhow!("stack reference `{id}`; {err}"))?;
+		if backend.entries()?.is_empty() {
+			bail!("stack reference `{id}` has no state, run it at least once");
+		}
+
+		let key_command_output =
+			match (maybe_passphrase, ProjectConfig::load(&mashin_dir)?.key_command) {
+				(None, Some(key_command)) => Some(
+					run_key_command(&key_command)
+						.map_err(|err| anyhow!("stack reference `{id}`; {err}"))?,
+				),
+				_ => None,
+			};
+		let passphrase = maybe_passphrase
+			.or(key_command_output.as_ref().map(String::as_bytes))
+			.unwrap_or(default_passphrase);
+
+		let key = existing_state_key(&mashin_dir, passphrase)
+			.map_err(|err| anyhow!("stack reference `{id}`; {err}"))?;
+		check_key(&backend, &key).map_err(|err| anyhow!("stack reference `{id}`; {err}"))?;
+
+		StackOutputs::load(&backend, &key)
+	}
+
+	/// URN of the state entry holding the references.
+	pub fn urn() -> Result<Urn> {
+		Urn::from_str(&format!("urn:{ENGINE_NID}:references"))
+	}
+
+	/// Load the references saved by the last apply.
+	pub fn load(backend: &BackendState, key: &secretbox::Key) -> Result<Self> {
+		match backend.get(&Self::urn()?)? {
+			Some(state) => Ok(serde_json::from_value(state.decrypt(key)?.inner().clone())?),
+			None => Ok(Self::default()),
+		}
+	}
+
+	/// Keep the outputs read from a reference.
+	pub fn insert(&mut self, id: String, outputs: StackOutputs) {
+		self.references.insert(id, outputs);
+	}
+
+	/// Replace the references saved within the state.
+	pub fn save(&self, backend: &BackendState, key: &secretbox::Key) -> Result<()> {
+		let operation = if self.references.is_empty() {
+			StateOperation::Delete(Self::urn()?)
+		} else {
+			let state = RawState::from(serde_json::to_value(self)?);
+			StateOperation::Save(Self::urn()?, state.encrypt(key)?)
+		};
+
+		backend.apply_batch(vec![operation])
+	}
+
+	/// Print the upstream values changed since the `previous` references,
+	/// returns `false` when there is no change.
+	pub fn print_changes(&self, previous: &Self) -> bool {
+		if self == previous {
+			return false
+		}
+
+		log::info!("\n    Changes from stack references:");
+
+		let empty_outputs = StackOutputs::default();
+		let all_ids = self.references.keys().chain(previous.keys()).collect::<BTreeSet<_>>();
+
+		for id in all_ids {
+			let outputs = self.get(id).unwrap_or(&empty_outputs);
+			let previous_outputs = previous.get(id).unwrap_or(&empty_outputs);
+			outputs.print_changed_outputs(previous_outputs, &format!("{id}: "));
+		}
+
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mashin_dir::DEFAULT_WORKSPACE;
+	use std::fs;
+
+	#[test]
+	fn missing_reference_is_not_created() {
+		let root = tempfile::tempdir().unwrap();
+		let path = root.path().to_str().unwrap();
+
+		let err = StackReferences::read(path, DEFAULT_WORKSPACE, None, b"passphrase").unwrap_err();
+		assert!(err.to_string().contains("no state"), "{err}");
+		assert!(StackReferences::read(path, "staging", None, b"passphrase").is_err());
+
+		// nothing is written within the referenced project
+		assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn reference_is_decrypted_with_its_key_command() {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		fs::write(mashin_dir.config_file_path(), r#"{ "keyCommand": "echo referenced" }"#).unwrap();
+
+		sodiumoxide::init().unwrap();
+		let key = crate::state_key(&mashin_dir, b"referenced").unwrap();
+		let backend = BackendState::new(&mashin_dir).unwrap();
+		crate::verify_key(&backend, &key).unwrap();
+		let path = root.path().to_str().unwrap();
+
+		// the passphrase of the current project is not used
+		StackReferences::read(path, DEFAULT_WORKSPACE, None, b"current").unwrap();
+		// unless another one is given
+		assert!(StackReferences::read(path, DEFAULT_WORKSPACE, Some(b"other"), b"current").is_err());
+	}
+}
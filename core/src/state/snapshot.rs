@@ -0,0 +1,366 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{
+	key::rotate, metadata::shared_backend, EncryptedState, RawState, StateOperation, ENGINE_NID,
+};
+use crate::{
+	backend::BackendState, mashin_dir::MashinDir, ExecutedResource, ExecutedResources, Result,
+};
+use anyhow::{anyhow, bail};
+use chrono::Utc;
+use mashin_sdk::Urn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sodiumoxide::crypto::secretbox;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs::{self, OpenOptions},
+	io::Write,
+	path::PathBuf,
+	str::FromStr,
+};
+
+/// Version of the snapshot format
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Metadata listing the snapshots stored within a remote backend
+const SNAPSHOT_IDS: &str = "snapshots.json";
+
+/// Immutable copy of the whole state, written after every successful apply.
+///
+/// The remote backends store the snapshots beside the state, like the salt,
+/// so the history is shared by everyone using the backend. The other backends
+/// keep them in `.mashin/snapshots/{id}.json`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+	pub version: u32,
+	pub id: u64,
+	/// RFC 3339 date of the snapshot
+	pub created_at: String,
+	pub mashin_version: String,
+	pub main_module: String,
+	/// All entries of the state (`{ urn: state }`), encrypted as a whole
+	state: EncryptedState,
+}
+
+impl Snapshot {
+	/// Write a new snapshot of the current state.
+	pub fn create(
+		mashin_dir: &MashinDir,
+		backend: &BackendState,
+		key: &secretbox::Key,
+		mashin_version: &str,
+		main_module: &str,
+	) -> Result<Self> {
+		let mut entries = Map::new();
+		for urn in backend.entries()? {
+			if let Some(state) = backend.get(&urn)? {
+				entries.insert(urn.to_string(), state.decrypt(key)?.into());
+			}
+		}
+
+		let store = SnapshotStore::open(mashin_dir)?;
+		let id = store.ids()?.last().map_or(1, |id| id + 1);
+		let snapshot = Self {
+			version: SNAPSHOT_VERSION,
+			id,
+			created_at: Utc::now().to_rfc3339(),
+			mashin_version: mashin_version.to_string(),
+			main_module: main_module.to_string(),
+			state: RawState::from(Value::Object(entries)).encrypt(key)?,
+		};
+		store.create(&snapshot)?;
+
+		Ok(snapshot)
+	}
+
+	/// All snapshots of the project, ordered by id.
+	pub fn list(mashin_dir: &MashinDir) -> Result<Vec<Self>> {
+		let store = SnapshotStore::open(mashin_dir)?;
+		store
+			.ids()?
+			.into_iter()
+			.map(|id| store.read(id)?.ok_or_else(|| anyhow!("snapshot `{id}` not found")))
+			.collect()
+	}
+
+	pub fn load(mashin_dir: &MashinDir, id: u64) -> Result<Self> {
+		SnapshotStore::open(mashin_dir)?
+			.read(id)?
+			.ok_or_else(|| anyhow!("snapshot `{id}` not found"))
+	}
+
+	/// Decrypted entries of the snapshot.
+	pub fn entries(&self, key: &secretbox::Key) -> Result<BTreeMap<Urn, RawState>> {
+		let state = self.state.decrypt(key)?;
+		let entries = state.inner().as_object().ok_or(anyhow!("invalid snapshot `{}`", self.id))?;
+
+		entries
+			.iter()
+			.map(|(urn, state)| Ok((Urn::from_str(urn)?, RawState::from(state.clone()))))
+			.collect()
+	}
+
+	/// Changes of the resources from `self` to `other`.
+	pub fn diff(&self, other: &Self, key: &secretbox::Key) -> Result<ExecutedResources> {
+		let from = self.entries(key)?;
+		let to = other.entries(key)?;
+		let all_urns = from
+			.keys()
+			.chain(to.keys())
+			.filter(|urn| urn.nid() != ENGINE_NID)
+			.collect::<BTreeSet<_>>();
+
+		let mut executed_resources = ExecutedResources::default();
+		for urn in all_urns {
+			let provider_name = urn.as_provider()?;
+			let executed_resource = match (from.get(urn), to.get(urn)) {
+				(Some(from), Some(to)) => ExecutedResource::new(provider_name, from, to),
+				(None, Some(to)) => ExecutedResource::new(provider_name, &RawState::default(), to),
+				(Some(from), None) => ExecutedResource::new_delete(provider_name, from),
+				(None, None) => unreachable!("at least one of the snapshots should have the urn"),
+			};
+			executed_resources.insert(urn, executed_resource);
+		}
+
+		Ok(executed_resources)
+	}
+
+	/// Replace the whole state with the snapshot, the infrastructure is not
+	/// modified. Returns the number of entries restored.
+	pub fn restore(&self, backend: &BackendState, key: &secretbox::Key) -> Result<usize> {
+		let entries = self.entries(key)?;
+
+		let mut operations = backend
+			.entries()?
+			.into_iter()
+			.filter(|urn| !entries.contains_key(urn))
+			.map(StateOperation::Delete)
+			.collect::<Vec<_>>();
+		for (urn, state) in &entries {
+			operations.push(StateOperation::Save(urn.clone(), state.encrypt(key)?));
+		}
+
+		backend.apply_batch(operations)?;
+		Ok(entries.len())
+	}
+
+	/// Re-encrypt the snapshot with a new key in memory, `false` when it is
+	/// already encrypted with it. See [`Snapshot::write`].
+	pub(crate) fn rekey(&mut self, key: &secretbox::Key, new_key: &secretbox::Key) -> Result<bool> {
+		match rotate(&self.state, key, new_key)
+			.map_err(|err| anyhow!("unable to re-encrypt snapshot `{}`; {err}", self.id))?
+		{
+			Some(state) => {
+				self.state = state;
+				Ok(true)
+			},
+			None => Ok(false),
+		}
+	}
+
+	/// Replace the snapshot once re-encrypted, the only time a snapshot is
+	/// rewritten.
+	pub(crate) fn write(&self, mashin_dir: &MashinDir) -> Result<()> {
+		SnapshotStore::open(mashin_dir)?.replace(self)
+	}
+}
+
+/// Where the snapshots of the project are kept.
+enum SnapshotStore {
+	/// `.mashin/snapshots/{id}.json`
+	Local(PathBuf),
+	/// `snapshot.{id}.json` metadata of the remote backend, listed in
+	/// `snapshots.json`
+	Shared(BackendState),
+}
+
+impl SnapshotStore {
+	fn open(mashin_dir: &MashinDir) -> Result<Self> {
+		Ok(match shared_backend(mashin_dir)? {
+			Some(backend) => Self::Shared(backend),
+			None => Self::Local(mashin_dir.snapshots_folder_path()),
+		})
+	}
+
+	/// Ids of the snapshots, ordered.
+	fn ids(&self) -> Result<Vec<u64>> {
+		let mut ids = Vec::new();
+		match self {
+			Self::Local(path) if !path.exists() => {},
+			Self::Local(path) =>
+				for entry in fs::read_dir(path)? {
+					let path = entry?.path();
+					if path.extension().map_or(false, |extension| extension == "json") {
+						ids.extend(
+							path.file_stem()
+								.and_then(|id| id.to_str())
+								.and_then(|id| id.parse::<u64>().ok()),
+						);
+					}
+				},
+			Self::Shared(backend) =>
+				if let Some(raw_ids) = backend.get_metadata(SNAPSHOT_IDS)? {
+					ids = serde_json::from_str(&raw_ids).map_err(|err| {
+						anyhow!("invalid `{SNAPSHOT_IDS}` stored in the state backend; {err}")
+					})?;
+				},
+		}
+		ids.sort_unstable();
+
+		Ok(ids)
+	}
+
+	fn read(&self, id: u64) -> Result<Option<Snapshot>> {
+		let raw_snapshot = match self {
+			Self::Local(path) => {
+				let path = path.join(format!("{id}.json"));
+				if !path.exists() {
+					return Ok(None)
+				}
+				fs::read(path)?
+			},
+			Self::Shared(backend) => match backend.get_metadata(&Self::metadata_name(id))? {
+				Some(raw_snapshot) => raw_snapshot.into_bytes(),
+				None => return Ok(None),
+			},
+		};
+
+		serde_json::from_slice(&raw_snapshot)
+			.map(Some)
+			.map_err(|err| anyhow!("invalid snapshot `{id}`; {err}"))
+	}
+
+	/// Write a new snapshot, an existing one is never overwritten.
+	fn create(&self, snapshot: &Snapshot) -> Result<()> {
+		match self {
+			Self::Local(path) => {
+				fs::create_dir_all(path)?;
+				let mut file = OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.open(path.join(format!("{}.json", snapshot.id)))?;
+				file.write_all(&serde_json::to_vec_pretty(snapshot)?)?;
+			},
+			Self::Shared(backend) => {
+				let mut ids = self.ids()?;
+				if ids.contains(&snapshot.id) {
+					bail!("snapshot `{}` already exists", snapshot.id)
+				}
+				backend.save_metadata(
+					&Self::metadata_name(snapshot.id),
+					&serde_json::to_string_pretty(snapshot)?,
+				)?;
+				// only listed once written
+				ids.push(snapshot.id);
+				backend.save_metadata(SNAPSHOT_IDS, &serde_json::to_string(&ids)?)?;
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Replace an existing snapshot, it's never left half-written.
+	fn replace(&self, snapshot: &Snapshot) -> Result<()> {
+		match self {
+			// written beside, then renamed over the previous one
+			Self::Local(path) => {
+				let path = path.join(format!("{}.json", snapshot.id));
+				let staged_path = path.with_extension("json.tmp");
+				fs::write(&staged_path, serde_json::to_vec_pretty(snapshot)?)?;
+				fs::rename(staged_path, path).map_err(Into::into)
+			},
+			Self::Shared(backend) => backend.save_metadata(
+				&Self::metadata_name(snapshot.id),
+				&serde_json::to_string_pretty(snapshot)?,
+			),
+		}
+	}
+
+	fn metadata_name(id: u64) -> String {
+		format!("snapshot.{id}.json")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::FileState;
+	use mashin_sdk::ResourceAction;
+	use serde_json::json;
+
+	fn urn(name: &str) -> Urn {
+		Urn::from_str(&format!("urn:provider:test:resource?={name}")).unwrap()
+	}
+
+	fn save(backend: &BackendState, key: &secretbox::Key, urn: &Urn, size: u64) {
+		let state = RawState::from(json!({ "size": { "__value": size, "__sensitive": false } }));
+		backend.save(urn, &state.encrypt(key).unwrap()).unwrap();
+	}
+
+	fn size(backend: &BackendState, key: &secretbox::Key, urn: &Urn) -> u64 {
+		let state = backend.get(urn).unwrap().unwrap().decrypt(key).unwrap();
+		state.inner()["size"]["__value"].as_u64().unwrap()
+	}
+
+	#[test]
+	fn snapshots_are_diffed_and_restored() {
+		let root = tempfile::tempdir().unwrap();
+		let mashin_dir = MashinDir::new(Some(root.path().to_path_buf())).unwrap();
+		let backend = BackendState::Local(FileState::new(mashin_dir.state_folder_path()).unwrap());
+		sodiumoxide::init().unwrap();
+		let key = secretbox::gen_key();
+		let outputs = Urn::from_str(&format!("urn:{ENGINE_NID}:outputs")).unwrap();
+
+		save(&backend, &key, &urn("logs"), 1);
+		save(&backend, &key, &urn("assets"), 1);
+		save(&backend, &key, &outputs, 1);
+		let first = Snapshot::create(&mashin_dir, &backend, &key, "0.1.0", "main.ts").unwrap();
+
+		save(&backend, &key, &urn("logs"), 2);
+		backend.delete(&urn("assets")).unwrap();
+		save(&backend, &key, &urn("queue"), 1);
+		save(&backend, &key, &outputs, 2);
+		let second = Snapshot::create(&mashin_dir, &backend, &key, "0.1.0", "main.ts").unwrap();
+
+		let ids = Snapshot::list(&mashin_dir).unwrap().iter().map(|s| s.id).collect::<Vec<_>>();
+		assert_eq!(ids, vec![first.id, second.id]);
+		assert_eq!(ids, vec![1, 2]);
+		let err = Snapshot::load(&mashin_dir, 3).unwrap_err();
+		assert_eq!(err.to_string(), "snapshot `3` not found");
+
+		// the engine entries are not resources
+		let diff = Snapshot::load(&mashin_dir, 1).unwrap().diff(&second, &key).unwrap();
+		assert_eq!(
+			diff.keys().cloned().collect::<Vec<_>>(),
+			[urn("assets"), urn("logs"), urn("queue")].map(|urn| urn.to_string())
+		);
+		let action = |urn: &Urn| diff[&urn.to_string()].required_change.clone();
+		assert_eq!(action(&urn("assets")), Some(ResourceAction::Delete));
+		assert!(matches!(action(&urn("logs")), Some(ResourceAction::Update { .. })));
+		assert_eq!(action(&urn("queue")), Some(ResourceAction::Create));
+
+		assert_eq!(first.restore(&backend, &key).unwrap(), 3);
+		assert_eq!(
+			backend.entries().unwrap(),
+			BTreeSet::from([urn("assets"), urn("logs"), outputs.clone()])
+		);
+		assert_eq!(size(&backend, &key, &urn("logs")), 1);
+		assert_eq!(size(&backend, &key, &urn("assets")), 1);
+		assert_eq!(size(&backend, &key, &outputs), 1);
+	}
+}
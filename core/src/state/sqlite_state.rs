@@ -0,0 +1,244 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use super::{EncryptedState, StateEntryMetadata, StateHandler, StateOperation, ENGINE_NID};
+use crate::{backend::client_version, Result};
+use anyhow::{anyhow, bail};
+use chrono::Utc;
+use mashin_sdk::{LockInfo, Urn};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
+use std::{collections::BTreeSet, fs, path::PathBuf, str::FromStr};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS state (
+	urn TEXT PRIMARY KEY NOT NULL,
+	state TEXT NOT NULL,
+	provider TEXT,
+	resource_type TEXT,
+	updated_at TEXT NOT NULL,
+	mashin_version TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS state_provider ON state (provider, resource_type);
+CREATE TABLE IF NOT EXISTS lock (
+	id INTEGER PRIMARY KEY CHECK (id = 0),
+	holder TEXT NOT NULL
+);
+"#;
+
+/// Local state stored within a SQLite database, eg; `.mashin/state.db`.
+///
+/// Each entry keeps its provider, resource type, last update and the version
+/// of the engine as plain columns, the state itself stays encrypted.
+pub struct SqliteState {
+	conn: Connection,
+}
+
+impl SqliteState {
+	pub fn new(db_path: PathBuf) -> Result<Self> {
+		if let Some(parent) = db_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+
+		let conn = Connection::open(&db_path)
+			.map_err(|err| anyhow!("unable to open `{}`; {err}", db_path.display()))?;
+		conn.execute_batch(SCHEMA)?;
+
+		Ok(Self { conn })
+	}
+
+	/// Open an existing database read-only, nothing is created.
+	pub fn open_read_only(db_path: PathBuf) -> Result<Self> {
+		if !db_path.is_file() {
+			bail!("no state found within `{}`", db_path.display());
+		}
+
+		let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+			.map_err(|err| anyhow!("unable to open `{}`; {err}", db_path.display()))?;
+
+		Ok(Self { conn })
+	}
+
+	/// Metadata stored beside an entry, `None` if the entry doesn't exist.
+	pub fn entry_metadata(&self, urn: &Urn) -> Result<Option<StateEntryMetadata>> {
+		self.conn
+			.query_row(
+				"SELECT provider, resource_type, updated_at, mashin_version FROM state WHERE urn = ?1",
+				[urn.as_str()],
+				|row| {
+					Ok(StateEntryMetadata {
+						urn: urn.clone(),
+						provider: row.get(0)?,
+						resource_type: row.get(1)?,
+						updated_at: row.get(2)?,
+						mashin_version: row.get(3)?,
+					})
+				},
+			)
+			.optional()
+			.map_err(Into::into)
+	}
+
+	fn save_within(transaction: &Transaction, urn: &Urn, state: &EncryptedState) -> Result<()> {
+		// eg; `urn:provider:aws:s3:bucket?=name` is the `s3:bucket` of `aws`
+		let (provider, resource_type) = match urn.nss().split_once(':') {
+			_ if urn.nid() == ENGINE_NID => (None, None),
+			Some((provider, resource_type)) => (Some(provider), Some(resource_type)),
+			None => (Some(urn.nss()), None),
+		};
+
+		transaction.execute(
+			"INSERT INTO state (urn, state, provider, resource_type, updated_at, mashin_version)
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+			ON CONFLICT (urn) DO UPDATE SET
+				state = excluded.state,
+				provider = excluded.provider,
+				resource_type = excluded.resource_type,
+				updated_at = excluded.updated_at,
+				mashin_version = excluded.mashin_version",
+			params![
+				urn.as_str(),
+				serde_json::to_string(state)?,
+				provider,
+				resource_type,
+				Utc::now().to_rfc3339(),
+				client_version(),
+			],
+		)?;
+
+		Ok(())
+	}
+
+	fn lock_holder(&self) -> Result<Option<LockInfo>> {
+		let raw_lock = self
+			.conn
+			.query_row("SELECT holder FROM lock WHERE id = 0", [], |row| row.get::<_, String>(0))
+			.optional()?;
+
+		raw_lock
+			.map(|raw_lock| {
+				serde_json::from_str(&raw_lock).map_err(|err| anyhow!("invalid state lock; {err}"))
+			})
+			.transpose()
+	}
+}
+
+impl StateHandler for SqliteState {
+	fn get(&self, urn: &Urn) -> Result<Option<EncryptedState>> {
+		let raw_state = self
+			.conn
+			.query_row("SELECT state FROM state WHERE urn = ?1", [urn.as_str()], |row| {
+				row.get::<_, String>(0)
+			})
+			.optional()?;
+
+		raw_state
+			.map(|raw_state| serde_json::from_str(&raw_state).map_err(Into::into))
+			.transpose()
+	}
+
+	fn save(&self, urn: &Urn, state: &EncryptedState) -> Result<()> {
+		let transaction = self.conn.unchecked_transaction()?;
+		Self::save_within(&transaction, urn, state)?;
+		transaction.commit().map_err(Into::into)
+	}
+
+	fn resources(&self) -> Result<BTreeSet<Urn>> {
+		let mut statement = self.conn.prepare("SELECT urn FROM state")?;
+		let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+
+		rows.map(|urn| Urn::from_str(&urn?)).collect()
+	}
+
+	fn delete(&self, urn: &Urn) -> Result<()> {
+		self.conn.execute("DELETE FROM state WHERE urn = ?1", [urn.as_str()])?;
+		Ok(())
+	}
+
+	fn apply_batch(&self, operations: Vec<StateOperation>) -> Result<()> {
+		// the transaction is rolled back if it is dropped before the commit
+		let transaction = self.conn.unchecked_transaction()?;
+
+		for operation in operations {
+			match operation {
+				StateOperation::Save(urn, state) => Self::save_within(&transaction, &urn, &state)?,
+				StateOperation::Delete(urn) => {
+					transaction.execute("DELETE FROM state WHERE urn = ?1", [urn.as_str()])?;
+				},
+			}
+		}
+
+		transaction.commit().map_err(Into::into)
+	}
+
+	fn try_lock(&self, lock: &LockInfo) -> Result<Option<LockInfo>> {
+		let is_locked = self.conn.execute(
+			"INSERT INTO lock (id, holder) VALUES (0, ?1) ON CONFLICT (id) DO NOTHING",
+			[serde_json::to_string(lock)?],
+		)? == 0;
+
+		if !is_locked {
+			return Ok(None)
+		}
+
+		self.lock_holder()?
+			.map(Some)
+			.ok_or(anyhow!("the state lock has been released, try again"))
+	}
+
+	fn unlock(&self, lock: &LockInfo) -> Result<()> {
+		let holder = match self.lock_holder()? {
+			Some(holder) => holder,
+			None => return Ok(()),
+		};
+
+		if holder.id != lock.id {
+			bail!("the state lock is held by {holder}");
+		}
+
+		self.force_unlock()
+	}
+
+	fn force_unlock(&self) -> Result<()> {
+		self.conn.execute("DELETE FROM lock WHERE id = 0", [])?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::set_client_version, RawState};
+	use serde_json::json;
+	use sodiumoxide::crypto::secretbox;
+
+	#[test]
+	fn entry_metadata_records_the_client_version() {
+		let root = tempfile::tempdir().unwrap();
+		set_client_version("1.2.3-test");
+
+		let sqlite = SqliteState::new(root.path().join("state.db")).unwrap();
+		let urn = Urn::from_str("urn:provider:aws:s3:bucket?=test").unwrap();
+		let state = RawState::from(json!({ "name": "test" })).encrypt(&secretbox::gen_key());
+		sqlite.save(&urn, &state.unwrap()).unwrap();
+
+		let metadata = sqlite.entry_metadata(&urn).unwrap().unwrap();
+		assert_eq!(metadata.provider.as_deref(), Some("aws"));
+		assert_eq!(metadata.resource_type.as_deref(), Some("s3:bucket"));
+		assert_eq!(metadata.mashin_version, "1.2.3-test");
+
+		let missing = Urn::from_str("urn:provider:aws:s3:bucket?=missing").unwrap();
+		assert!(sqlite.entry_metadata(&missing).unwrap().is_none());
+	}
+}
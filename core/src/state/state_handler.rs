@@ -23,9 +23,9 @@ use mashin_sdk::{
 			de::{self, Visitor},
 			Deserialize, Deserializer, Serialize, Serializer,
 		},
-		serde_json::Value,
+		serde_json::{json, Value},
 	},
-	Urn,
+	LockInfo, Urn, KEY_CONFIG, KEY_NAME, KEY_SENSITIVE, KEY_URN, KEY_VALUE, KEY_VERSION,
 };
 use sodiumoxide::crypto::{pwhash, secretbox};
 use std::{collections::BTreeSet, fmt};
@@ -130,10 +130,116 @@ impl RawState {
 		trim_sensitive::fold_json(&self.0, None)
 	}
 
+	/// Folded value to display to the user, sensitive fields are masked
+	/// unless `show_sensitive` is set.
+	pub fn display_value(&self, show_sensitive: bool) -> Value {
+		if show_sensitive {
+			trim_sensitive::reveal_json(&self.0)
+		} else {
+			trim_sensitive::fold_json(&self.0, Some("[sensitive]"))
+		}
+	}
+
+	/// Config used when the resource has been applied, `null` if unknown.
+	pub fn config(&self) -> Value {
+		self.0
+			.get(KEY_CONFIG)
+			.and_then(|config| config.get(KEY_VALUE))
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	/// Paths of the values stored as sensitive, named like the paths of the
+	/// diff, eg; `config.credentials`.
+	pub fn sensitive_paths(&self) -> Vec<String> {
+		fn collect(value: &Value, path: &mut Vec<String>, paths: &mut Vec<String>) {
+			match value {
+				Value::Object(fields) => {
+					if fields.get(KEY_SENSITIVE).and_then(Value::as_bool).unwrap_or(false) {
+						paths.push(path.join("."));
+						return
+					}
+
+					for (key, value) in fields {
+						match key.as_str() {
+							KEY_VALUE => collect(value, path, paths),
+							key if key == KEY_SENSITIVE || key == KEY_VERSION => {},
+							key => {
+								path.push(
+									if key == KEY_CONFIG { "config" } else { key }.to_string(),
+								);
+								collect(value, path, paths);
+								path.pop();
+							},
+						}
+					}
+				},
+				Value::Array(values) =>
+					for (idx, value) in values.iter().enumerate() {
+						path.push(format!("[{idx}]"));
+						collect(value, path, paths);
+						path.pop();
+					},
+				_ => {},
+			}
+		}
+
+		let mut paths = Vec::new();
+		collect(&self.0, &mut Vec::new(), &mut paths);
+		paths
+	}
+
+	/// Same state, applied with `config`. The sensitivity of the stored config
+	/// is kept.
+	pub fn with_config(&self, config: &Value) -> Self {
+		let mut state = self.0.clone();
+		if let Some(fields) = state.as_object_mut() {
+			let sensitive = fields
+				.get(KEY_CONFIG)
+				.and_then(|config| config.get(KEY_SENSITIVE))
+				.cloned()
+				.unwrap_or(Value::Bool(false));
+			fields.insert(
+				KEY_CONFIG.to_string(),
+				json!({ KEY_VALUE: config, KEY_SENSITIVE: sensitive }),
+			);
+		}
+		Self(state)
+	}
+
+	/// Point the state to a new URN, the resource name follows the URN
+	/// q-component.
+	pub fn set_urn(&mut self, urn: &Urn) {
+		if let Some(state) = self.0.as_object_mut() {
+			state.insert(
+				KEY_URN.to_string(),
+				json!({ KEY_VALUE: urn.to_string(), KEY_SENSITIVE: false }),
+			);
+			if let Some(name) = urn.q_component() {
+				state
+					.insert(KEY_NAME.to_string(), json!({ KEY_VALUE: name, KEY_SENSITIVE: false }));
+			}
+		}
+	}
+
 	pub fn compare_with(&self, b: &Self) -> StateDiff {
 		diff(self.inner().clone(), b.inner().clone())
 	}
 
+	/// Compare two states, the state schema version is left out as the states
+	/// written before an upgrade are only migrated when read.
+	pub fn same_as(&self, other: &Self) -> bool {
+		let without_version = |state: &Value| match state {
+			Value::Object(fields) => {
+				let mut fields = fields.clone();
+				fields.remove(KEY_VERSION);
+				Value::Object(fields)
+			},
+			state => state.clone(),
+		};
+		without_version(&self.0) == without_version(&other.0)
+	}
+
 	pub fn inner(&self) -> &serde_json::Value {
 		&self.0
 	}
@@ -173,11 +279,56 @@ impl From<serde_json::Value> for RawState {
 	}
 }
 
+/// Metadata stored beside a state entry by the backends supporting it, it can
+/// be queried without the passphrase.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateEntryMetadata {
+	pub urn: Urn,
+	/// Provider of the resource, `None` for the entries of the engine
+	pub provider: Option<String>,
+	/// Resource type within the provider, eg; `s3:bucket`
+	pub resource_type: Option<String>,
+	/// RFC 3339 date of the last write
+	pub updated_at: String,
+	/// Version of the client which wrote the entry
+	pub mashin_version: String,
+}
+
+/// Write operation applied within a batch.
+pub enum StateOperation {
+	Save(Urn, EncryptedState),
+	Delete(Urn),
+}
+
 pub trait StateHandler {
 	fn save(&self, urn: &Urn, state: &EncryptedState) -> Result<()>;
 	fn get(&self, urn: &Urn) -> Result<Option<EncryptedState>>;
 	fn delete(&self, urn: &Urn) -> Result<()>;
 	fn resources(&self) -> Result<BTreeSet<Urn>>;
+	/// Try to acquire the state lock, returns the current holder if the state
+	/// is already locked. Handlers without locking support can ignore it.
+	fn try_lock(&self, _lock: &LockInfo) -> Result<Option<LockInfo>> {
+		Ok(None)
+	}
+	/// Release the state lock, if it is held by `lock`.
+	fn unlock(&self, _lock: &LockInfo) -> Result<()> {
+		Ok(())
+	}
+	/// Release the state lock, whoever holds it.
+	fn force_unlock(&self) -> Result<()> {
+		Ok(())
+	}
+	/// Apply all operations at once. The default implementation applies them
+	/// one by one, handlers supporting transactions should overwrite it.
+	fn apply_batch(&self, operations: Vec<StateOperation>) -> Result<()> {
+		for operation in operations {
+			match operation {
+				StateOperation::Save(urn, state) => self.save(&urn, &state)?,
+				StateOperation::Delete(urn) => self.delete(&urn)?,
+			}
+		}
+		Ok(())
+	}
 }
 
 pub(crate) fn derive_key(passphrase: &[u8], salt: pwhash::Salt) -> Result<secretbox::Key> {
@@ -18,19 +18,29 @@ use mashin_sdk::KEY_NAME;
 use serde_json::Value;
 
 pub fn fold_json(json_obj: &Value, replace_sensitive: Option<&str>) -> Value {
+	fold_json_with(json_obj, replace_sensitive, false)
+}
+
+// fold the json and keep the sensitive values as-is
+pub fn reveal_json(json_obj: &Value) -> Value {
+	fold_json_with(json_obj, None, true)
+}
+
+fn fold_json_with(json_obj: &Value, replace_sensitive: Option<&str>, reveal: bool) -> Value {
 	if let Value::Object(map) = json_obj {
 		let mut new_obj = serde_json::Map::new();
 		let mut should_skip_sensitive = false;
 
 		if let Some(sensitive_val) = map.get(KEY_SENSITIVE) {
-			should_skip_sensitive = sensitive_val.as_bool().unwrap_or(false);
+			should_skip_sensitive = !reveal && sensitive_val.as_bool().unwrap_or(false);
 
 			if should_skip_sensitive {
 				if let Some(replace_sensitive) = replace_sensitive {
 					if let Some(key_val) = map.get(KEY_VALUE) {
 						if key_val.is_object() {
-							replace_secrets_string(&mut key_val.clone(), replace_sensitive);
-							return key_val.clone()
+							let mut key_val = key_val.clone();
+							replace_secrets_string(&mut key_val, replace_sensitive);
+							return key_val
 						} else {
 							return replace_sensitive.into()
 						}
@@ -65,7 +75,7 @@ pub fn fold_json(json_obj: &Value, replace_sensitive: Option<&str>) -> Value {
 				}
 
 				if value.is_object() {
-					let processed_value = fold_json(value, replace_sensitive);
+					let processed_value = fold_json_with(value, replace_sensitive, reveal);
 					if !processed_value.is_null() {
 						new_obj.insert(key.clone(), processed_value);
 					}
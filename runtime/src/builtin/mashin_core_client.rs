@@ -14,22 +14,24 @@
 \* ---------------------------------------------------------*/
 
 use deno_core::{
-	error::{generic_error, type_error},
+	error::type_error,
 	serde_json::{self, Value},
 	ModuleSpecifier, OpState, ResourceId,
 };
 use dlopen::raw::Library;
 use mashin_core::{
+	mashin_dir::DEFAULT_WORKSPACE,
 	sdk::{ext::anyhow::anyhow, ResourceAction, ResourceArgs, Result, Urn},
-	Config, DynamicLibraryResource, ExecutedResource, ForeignFunction, HttpCache, HttpClient,
-	MashinEngine, ProgressManager, RegisteredProvider, RuntimeCommand, Symbol,
+	verify_key, BackendState, Config, DynamicLibraryResource, ExecutedResource, ForeignFunction,
+	HttpCache, HttpClient, Journal, JournalEntry, MashinEngine, ProgressManager, RawState,
+	RegisteredProvider, RuntimeCommand, StackOutput, StateInner,
 };
 use serde::Deserialize;
 use std::{
 	cell::RefCell,
 	collections::HashMap,
 	env::{self},
-	ffi::c_void,
+	fs,
 	rc::Rc,
 	str::FromStr,
 	sync::mpsc::{self, TryRecvError},
@@ -39,11 +41,29 @@ use std::{
 
 // only call if we want to overwrite the backend
 #[deno_core::op]
-pub(crate) async fn as__client_new(
-	_op_state: Rc<RefCell<OpState>>,
-	_backend_rid: Option<ResourceId>,
-) -> Result<()> {
-	Ok(())
+pub(crate) async fn as__client_new<T>(
+	op_state: Rc<RefCell<OpState>>,
+	backend_rid: Option<ResourceId>,
+) -> Result<()>
+where
+	T: Config,
+{
+	let backend_rid = match backend_rid {
+		Some(backend_rid) => backend_rid,
+		None => return Ok(()),
+	};
+
+	let mut op_state = op_state.borrow_mut();
+	let backend = op_state.resource_table.take::<StateInner>(backend_rid)?;
+	let backend = Rc::try_unwrap(backend).map_err(|_| anyhow!("state backend already in use"))?;
+
+	// the lock taken before the script is evaluated moves to the new backend
+	let mashin = op_state.borrow::<Rc<MashinEngine<T>>>();
+	let mut state_handler = mashin.state_handler.borrow_mut();
+	state_handler.replace(BackendState::Plugin(backend), mashin.state_lock.as_ref())?;
+
+	// make sure the passphrase can decrypt the state stored in the new backend
+	verify_key(&state_handler, &mashin.key)
 }
 
 #[derive(Deserialize, Debug)]
@@ -65,6 +85,8 @@ where
 
 	if mashin.command == RuntimeCommand::Prepare {
 		mashin.inc_resources_count();
+		// see `ExecutedResources::deletion_order`
+		mashin.declared_resources.borrow_mut().push(Urn::from_str(&args.urn)?);
 		return Ok(Default::default())
 	}
 
@@ -78,14 +100,43 @@ where
 	let provider_name = urn.as_provider()?;
 	let display_urn = urn.as_display();
 
-	let already_executed_resource = executed_resouces.get(&urn);
+	// only the imported resource is read, the others are left untouched
+	let import_id = match &mashin.command {
+		RuntimeCommand::Import { urn: import_urn, id } if import_urn == &*urn => Some(id),
+		RuntimeCommand::Import { .. } =>
+			return Ok(mashin.current_state(&urn)?.generate_ts_output()),
+		_ => None,
+	};
 
-	let expected_resource_action =
-		if let Some(already_executed_resource) = already_executed_resource {
-			already_executed_resource.required_change.clone().unwrap_or(ResourceAction::Get)
+	// only the resources of the interrupted apply are read back
+	if let RuntimeCommand::Reconcile { urns } = &mashin.command {
+		let state = if urns.contains(&urn) {
+			mashin.reconcile_resource(&urn, &raw_config)?
 		} else {
-			ResourceAction::Get
+			mashin.current_state(&urn)?
 		};
+		return Ok(state.generate_ts_output())
+	}
+
+	let already_executed_resource = executed_resouces.get(&urn);
+
+	// the planned actions are applied with the config they have been planned
+	// with, a resource not planned is not applied
+	let raw_config = match already_executed_resource {
+		Some(planned) if mashin.command == RuntimeCommand::Apply && !planned.config.is_null() =>
+			Rc::new(planned.config.clone()),
+		None if mashin.command == RuntimeCommand::Apply =>
+			return Err(anyhow!("`{display_urn}` has not been planned, run `mashin plan` again")),
+		_ => raw_config,
+	};
+
+	let expected_resource_action = if let Some(id) = import_id {
+		ResourceAction::Import { id: id.clone() }
+	} else if let Some(already_executed_resource) = already_executed_resource {
+		already_executed_resource.required_change.clone().unwrap_or(ResourceAction::Get)
+	} else {
+		ResourceAction::Get
+	};
 
 	let pm = &mashin.progress_manager;
 	let pb = pm.progress_bar();
@@ -96,6 +147,15 @@ where
 		pb.enable_steady_tick(Duration::from_secs(1));
 	}
 
+	// the state is only written once the refresh is confirmed
+	if mashin.command == RuntimeCommand::Refresh {
+		let refreshed_state = mashin.refresh_resource(&urn, &raw_config)?;
+		if let Some(pb) = &pb {
+			pb.disable_steady_tick();
+		}
+		return Ok(refreshed_state.generate_ts_output())
+	}
+
 	let backend = mashin.state_handler.borrow();
 	let providers = mashin.providers.borrow();
 	let provider = providers.get(&provider_name).ok_or(anyhow!("provider initialized"))?;
@@ -132,8 +192,28 @@ where
 		raw_state: raw_state.clone(),
 		urn: urn.clone(),
 	};
+
+	// mutations are journaled, so an interrupted apply can be reconciled. The
+	// deletes are journaled by `MashinEngine::delete_resource`
+	let journal = (mashin.command == RuntimeCommand::Apply &&
+		matches!(args.action.as_ref(), ResourceAction::Create | ResourceAction::Update { .. }))
+	.then(|| Journal::new(&mashin.mashin_dir));
+	if let Some(journal) = &journal {
+		journal.record(&JournalEntry::Intent {
+			urn: urn.as_ref().clone(),
+			action: args.action.as_ref().clone(),
+		})?;
+	}
+
 	let provider_state = provider.dylib.call_resource(provider.ptr, &args)?;
-	let new_state = provider_state.inner().into();
+	let new_state: RawState = provider_state.inner().into();
+
+	if let Some(journal) = &journal {
+		journal.record(&JournalEntry::Result {
+			urn: urn.as_ref().clone(),
+			state: Some(new_state.encrypt(&mashin.key)?),
+		})?;
+	}
 
 	// close the log thread
 	tx.send(())?;
@@ -142,7 +222,7 @@ where
 	let current_state = raw_state.as_ref().take().into();
 
 	// this is the first run
-	if already_executed_resource.is_none() {
+	if already_executed_resource.is_none() && import_id.is_none() {
 		let executed_resource = ExecutedResource::new(
 			provider_name,
 			//args,
@@ -154,6 +234,10 @@ where
 	} else {
 		backend.save(&urn, &new_state.encrypt(&mashin.key)?)?;
 		executed_resouces.remove(&urn);
+
+		if let Some(journal) = &journal {
+			journal.record(&JournalEntry::Committed { urn: urn.as_ref().clone() })?;
+		}
 	}
 
 	if let Some(pb) = &pb {
@@ -163,6 +247,90 @@ where
 	Ok(new_state.generate_ts_output())
 }
 
+#[derive(Deserialize, Debug)]
+pub struct OutputExportArgs {
+	name: String,
+	value: serde_json::Value,
+	#[serde(default)]
+	sensitive: bool,
+}
+
+/// Export a named output of the stack, the outputs are saved within the state
+/// once the changes are applied.
+#[deno_core::op]
+pub(crate) fn as__runtime__output_export<T>(
+	op_state: &mut OpState,
+	args: OutputExportArgs,
+) -> Result<()>
+where
+	T: Config,
+{
+	let mashin = op_state.borrow::<Rc<MashinEngine<T>>>();
+
+	// the values of the resources are not available yet
+	if mashin.command == RuntimeCommand::Prepare {
+		return Ok(())
+	}
+
+	mashin
+		.outputs
+		.borrow_mut()
+		.insert(&args.name, StackOutput { value: args.value, sensitive: args.sensitive })
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StackReferenceReadArgs {
+	path: String,
+	workspace: Option<String>,
+	passphrase_env: Option<String>,
+	key_file: Option<String>,
+}
+
+/// Read the outputs of another project, its state is opened read-only.
+#[deno_core::op]
+pub(crate) fn as__runtime__stack_reference_read<T>(
+	op_state: &mut OpState,
+	args: StackReferenceReadArgs,
+) -> Result<serde_json::Value>
+where
+	T: Config,
+{
+	let mashin = op_state.borrow::<Rc<MashinEngine<T>>>();
+
+	if mashin.command == RuntimeCommand::Prepare {
+		return Ok(Default::default())
+	}
+
+	// the key command of the referenced project, then the passphrase of this
+	// project are used by default
+	let maybe_passphrase = if let Some(key_file) = &args.key_file {
+		Some(
+			fs::read_to_string(key_file)
+				.map_err(|err| anyhow!("unable to read key file `{key_file}`; {err}"))?
+				.trim_end_matches(&['\n', '\r'][..])
+				.to_string(),
+		)
+	} else if let Some(passphrase_env) = &args.passphrase_env {
+		Some(env::var(passphrase_env).map_err(|_| anyhow!("`{passphrase_env}` is not set"))?)
+	} else {
+		None
+	};
+
+	let outputs = mashin.read_stack_reference(
+		&args.path,
+		args.workspace.as_deref().unwrap_or(DEFAULT_WORKSPACE),
+		maybe_passphrase.as_deref().map(str::as_bytes),
+	)?;
+
+	Ok(serde_json::Value::Object(
+		outputs
+			.iter()
+			.map(|(name, output)| (name.clone(), output.value.clone()))
+			.collect(),
+	))
+}
+
 #[derive(Default, Deserialize, Debug)]
 pub enum ProviderDownloadSource {
 	#[default]
@@ -236,50 +404,7 @@ where
 	let mashin = op_state.borrow_mut::<Rc<MashinEngine<T>>>();
 	let mut providers = mashin.providers.borrow_mut();
 
-	let lib = Library::open(&path).map_err(|e| {
-		dlopen::Error::OpeningLibraryError(std::io::Error::new(
-			std::io::ErrorKind::Other,
-			super::ffi::format_error(e, path),
-		))
-	})?;
-	let mut resource = DynamicLibraryResource { lib, symbols: HashMap::new() };
-
-	for (symbol_key, foreign_fn) in args.symbols {
-		let symbol = match &foreign_fn.name {
-			Some(symbol) => symbol,
-			None => &symbol_key,
-		};
-		// By default, Err returned by this function does not tell
-		// which symbol wasn't exported. So we'll modify the error
-		// message to include the name of symbol.
-		let fn_ptr =
-        // SAFETY: The obtained T symbol is the size of a pointer.
-        match unsafe { resource.lib.symbol::<*const c_void>(symbol) } {
-            Ok(value) => Ok(value),
-            Err(err) => Err(generic_error(format!(
-            "Failed to register symbol {symbol}: {err}"
-            ))),
-        }?;
-		let ptr = libffi::middle::CodePtr::from_ptr(fn_ptr as _);
-		let cif = libffi::middle::Cif::new(
-			foreign_fn
-				.parameters
-				.clone()
-				.into_iter()
-				.map(libffi::middle::Type::try_from)
-				.collect::<Result<Vec<_>, _>>()?,
-			foreign_fn.result.clone().try_into()?,
-		);
-
-		let sym: Box<Symbol> = Box::new(Symbol {
-			cif,
-			ptr,
-			parameter_types: foreign_fn.parameters,
-			result_type: foreign_fn.result,
-		});
-
-		resource.symbols.insert(symbol_key, sym.clone());
-	}
+	let resource = open_dynamic_library(path, args.symbols)?;
 
 	// create new provider pointer
 
@@ -292,6 +417,39 @@ where
 	Ok(())
 }
 
+#[derive(Deserialize, Debug)]
+pub struct BackendAllocateArgs {
+	path: String,
+	symbols: HashMap<String, ForeignFunction>,
+	props: Value,
+}
+
+/// Load the state backend, the returned resource id is then passed to
+/// `as__client_new` to replace the current backend.
+#[deno_core::op]
+pub fn as__runtime__register_backend__allocate(
+	op_state: &mut OpState,
+	args: BackendAllocateArgs,
+) -> Result<ResourceId> {
+	let resource = open_dynamic_library(args.path, args.symbols)?;
+	let backend = StateInner::new(resource, &args.props)?;
+
+	Ok(op_state.resource_table.add(backend))
+}
+
+fn open_dynamic_library(
+	path: String,
+	symbols: HashMap<String, ForeignFunction>,
+) -> Result<DynamicLibraryResource> {
+	let lib = Library::open(&path).map_err(|e| {
+		dlopen::Error::OpeningLibraryError(std::io::Error::new(
+			std::io::ErrorKind::Other,
+			super::ffi::format_error(e, path),
+		))
+	})?;
+	DynamicLibraryResource::new(lib, symbols)
+}
+
 #[deno_core::op]
 pub(crate) fn op_get_env(key: String) -> Result<Option<String>> {
 	if key.is_empty() {
@@ -325,9 +483,12 @@ pub(crate) fn op_decls<T: Config>() -> Vec<::deno_core::OpDecl> {
 	vec![
 		op_get_env::decl(),
 		as__client_print::decl(),
-		as__client_new::decl(),
+		as__client_new::decl::<T>(),
 		as__runtime__register_provider__download::decl::<T>(),
 		as__runtime__register_provider__allocate::decl::<T>(),
+		as__runtime__register_backend__allocate::decl(),
 		as__runtime__resource_execute::decl::<T>(),
+		as__runtime__output_export::decl::<T>(),
+		as__runtime__stack_reference_read::decl::<T>(),
 	]
 }
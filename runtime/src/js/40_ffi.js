@@ -33,6 +33,44 @@ class DynamicProvider {
   }
 }
 
+const BACKEND_CALL = {
+  parameters: ["pointer", "pointer", "usize"],
+  result: "pointer",
+};
+
+class DynamicBackend {
+  #rid;
+  constructor(path, props) {
+    this.#rid = ops.as__runtime__register_backend__allocate({
+      path: pathFromURL(path),
+      symbols: {
+        new: BACKEND_CALL,
+        get: BACKEND_CALL,
+        save: BACKEND_CALL,
+        delete: BACKEND_CALL,
+        list: BACKEND_CALL,
+        lock: BACKEND_CALL,
+        unlock: BACKEND_CALL,
+        force_unlock: BACKEND_CALL,
+        drop: {
+          parameters: ["pointer"],
+          result: "void",
+        },
+      },
+      props,
+    });
+  }
+
+  get rid() {
+    return this.#rid;
+  }
+}
+
+// replace the state backend of the engine, the backend can't be reused after
+async function setBackend(backend) {
+  return await core.opAsync("as__client_new", backend.rid);
+}
+
 class DynamicResource {
   #output;
   constructor(urn, config) {
@@ -47,4 +85,42 @@ class DynamicResource {
   }
 }
 
-export { DynamicProvider, DynamicResource, downloadProvider };
+// export a named output of the stack, eg; `Mashin.output("endpoint", bucket.url)`
+function exportOutput(name, value, options = {}) {
+  ops.as__runtime__output_export({
+    name,
+    value,
+    sensitive: options.sensitive ?? false,
+  });
+}
+
+// outputs of another project, eg; `new StackReference("../network/.mashin", "prod")`
+class StackReference {
+  #outputs;
+  constructor(path, workspace = "default", options = {}) {
+    this.#outputs = ops.as__runtime__stack_reference_read({
+      path,
+      workspace,
+      passphraseEnv: options.passphraseEnv,
+      keyFile: options.keyFile,
+    });
+  }
+
+  get outputs() {
+    return this.#outputs;
+  }
+
+  getOutput(name) {
+    return this.#outputs?.[name];
+  }
+}
+
+export {
+  DynamicBackend,
+  DynamicProvider,
+  DynamicResource,
+  downloadProvider,
+  exportOutput,
+  setBackend,
+  StackReference,
+};
@@ -0,0 +1,91 @@
+const primordials = globalThis.__bootstrap.primordials;
+const {
+  ArrayIsArray,
+  ArrayPrototypeIncludes,
+  ArrayPrototypePush,
+  ObjectDefineProperties,
+  ObjectEntries,
+  ObjectFreeze,
+  Promise,
+  String,
+  TypeError,
+} = primordials;
+const core = globalThis.Deno.core;
+
+import * as console from "ext:deno_console/01_console.js";
+
+const ENFORCEMENTS = ["advisory", "mandatory"];
+const policies = [];
+
+// every exported object with a `validate` function is a policy, eg;
+// `export const noPublicBucket = { enforcement: "mandatory", validate(resource) {} }`
+function registerPolicies(file, module) {
+  for (const [name, policy] of ObjectEntries(module)) {
+    if (typeof policy?.validate !== "function") {
+      continue;
+    }
+
+    const enforcement = policy.enforcement ?? "mandatory";
+    if (!ArrayPrototypeIncludes(ENFORCEMENTS, enforcement)) {
+      throw new TypeError(
+        `Policy ${file}:${name} has an invalid enforcement "${enforcement}", expected "advisory" or "mandatory"`
+      );
+    }
+
+    ArrayPrototypePush(policies, {
+      name: `${file}:${name}`,
+      enforcement,
+      validate: policy.validate,
+    });
+  }
+}
+
+// validate each planned resource, a policy returns a message or a list of
+// messages when the resource is not compliant
+function checkPolicies(resources) {
+  const violations = [];
+  for (const policy of policies) {
+    for (const resource of resources) {
+      const result = policy.validate(ObjectFreeze(resource));
+      if (result instanceof Promise) {
+        throw new TypeError(
+          `Policy ${policy.name} returned a promise, policies are synchronous`
+        );
+      }
+
+      const messages =
+        result === undefined || result === null
+          ? []
+          : ArrayIsArray(result)
+          ? result
+          : [result];
+      for (const message of messages) {
+        ArrayPrototypePush(violations, {
+          policy: policy.name,
+          urn: resource.urn,
+          enforcement: policy.enforcement,
+          message: String(message),
+        });
+      }
+    }
+  }
+  return violations;
+}
+
+ObjectDefineProperties(globalThis, {
+  console: {
+    value: new console.Console((msg, level) => core.print(msg, level > 1)),
+    enumerable: false,
+    configurable: true,
+    writable: true,
+  },
+  __mashinPolicy: {
+    value: ObjectFreeze({
+      register: registerPolicies,
+      check: checkPolicies,
+    }),
+    enumerable: false,
+    configurable: false,
+    writable: false,
+  },
+});
@@ -1,5 +1,5 @@
 const primordials = globalThis.__bootstrap.primordials;
-const { ObjectDefineProperties } = primordials;
+const { ObjectDefineProperties, ObjectFreeze } = primordials;
 const core = globalThis.Deno.core;
 
 import { globalScope } from "ext:mashin_core/98_global_scope.js";
@@ -7,8 +7,12 @@ import { env } from "ext:mashin_core/30_os.js";
 import { errors } from "ext:mashin_core/01_errors.js";
 import {
   downloadProvider,
+  DynamicBackend,
   DynamicProvider,
   DynamicResource,
+  exportOutput,
+  setBackend,
+  StackReference,
 } from "ext:mashin_core/40_ffi.js";
 import DOMException from "ext:deno_web/01_dom_exception.js";
 import * as util from "ext:mashin_core/06_util.js";
@@ -48,6 +52,16 @@ function bootstrapMainRuntime(runtimeOptions) {
         : new console.Console((_msg, _level) => {})
     ),
   });
+  // expose the engine context, eg; `Mashin.workspace`
+  ObjectDefineProperties(globalThis, {
+    Mashin: util.readOnly(
+      ObjectFreeze({
+        workspace: runtimeOptions.workspace,
+        output: exportOutput,
+        StackReference,
+      })
+    ),
+  });
   core.setBuildInfo(runtimeOptions.target);
 
   core.registerErrorClass("NotFound", errors.NotFound);
@@ -119,7 +133,11 @@ globalThis.bootstrap = {
 };
 
 globalThis.__mashin = {
+  DynamicBackend,
   DynamicProvider,
   DynamicResource,
   downloadProvider,
+  exportOutput,
+  setBackend,
+  StackReference,
 };
@@ -13,7 +13,7 @@
  *                                                          *
 \* ---------------------------------------------------------*/
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use deno_core::{
 	include_js_files, resolve_path, serde_json::json, Extension, JsRuntime, ModuleLoader,
 	ModuleSpecifier, OpDecl, RuntimeOptions,
@@ -21,10 +21,15 @@ use deno_core::{
 use deno_fetch::FetchPermissions;
 use deno_web::{BlobStore, TimersPermission};
 use deno_websocket::WebSocketPermissions;
-use mashin_core::sdk::ResourceAction;
 pub use mashin_core::{
-	mashin_dir::MashinDir, BackendState, Config, ExecutedResource, ExecutedResources, HeadersMap,
-	HttpCache, HttpClient, MashinBuilder, MashinEngine, ProgressManager, RuntimeCommand,
+	add_recipient, generate_identity,
+	mashin_dir::{MashinDir, DEFAULT_WORKSPACE},
+	migrate_state, rekey_state, remove_recipient, run_key_command, set_client_version, state_key,
+	verify_key, BackendConfig, BackendState, Config, ExecutedResource, ExecutedResources,
+	HeadersMap, HttpCache, HttpClient, Journal, KeyCommandOutput, MashinBuilder, MashinEngine,
+	Plan, PlanReport, PlannedResource, ProgressManager, ProjectConfig, RawState, Recipients,
+	RefreshedResources, RuntimeCommand, Snapshot, StackOutputs, StackReferences,
+	StateEntryMetadata, StateExport, StateOperation,
 };
 use std::{
 	cell::RefCell,
@@ -36,6 +41,9 @@ use std::{
 };
 
 mod builtin;
+mod policy;
+
+pub use policy::{Enforcement, PolicyRuntime, PolicyViolation};
 
 #[macro_export]
 macro_rules! log {
@@ -196,11 +204,7 @@ impl<T: Config> Runtime<T> {
 			if !executed_resources.contains_key(urn) {
 				executed_resources.insert(
 					urn,
-					ExecutedResource {
-						diff: None,
-						provider: urn.as_provider()?,
-						required_change: Some(ResourceAction::Delete),
-					},
+					ExecutedResource::new_delete(urn.as_provider()?, &engine.current_state(urn)?),
 				);
 			}
 		}
@@ -208,6 +212,191 @@ impl<T: Config> Runtime<T> {
 		Ok(RuntimeResult { executed_resources: executed_resources_rc.clone() })
 	}
 
+	// run the main module to register the providers, then mark
+	// all resources available within the state to be deleted
+	pub async fn destroy(&mut self) -> Result<RuntimeResult> {
+		self.run_main_module().await?;
+
+		let rc_op_state = self.runtime.op_state();
+		let op_state = rc_op_state.borrow();
+		let engine = op_state.borrow::<Rc<MashinEngine<T>>>();
+		let executed_resources_rc = &engine.executed_resources;
+		let all_resources_in_state = engine.state_handler.borrow().resources()?;
+		let mut executed_resources = executed_resources_rc.borrow_mut();
+
+		for urn in &all_resources_in_state {
+			executed_resources.insert(
+				urn,
+				ExecutedResource::new_delete(urn.as_provider()?, &engine.current_state(urn)?),
+			);
+		}
+
+		Ok(RuntimeResult { executed_resources: executed_resources_rc.clone() })
+	}
+
+	// run the main module, each resource is read with its stored config to
+	// catch the out-of-band changes, and with the config of the code
+	pub async fn refresh(&mut self) -> Result<Rc<RefCell<RefreshedResources>>> {
+		self.run_main_module().await?;
+
+		let all_resources_in_state = self.engine.state_handler.borrow().resources()?;
+
+		// they are available within the state but not in the code, they are
+		// read with the provider still declared by the code
+		for urn in &all_resources_in_state {
+			if self.engine.refreshed_resources.borrow().states.contains_key(urn) {
+				continue
+			}
+
+			let provider_name = urn.as_provider()?;
+			if self.engine.has_provider(&provider_name) {
+				self.engine.refresh_removed_resource(urn)?;
+			} else {
+				log::warn!(
+					"    Unable to refresh {}, the provider `{provider_name}` is not declared",
+					urn.as_display()
+				);
+				self.engine.refreshed_resources.borrow_mut().code_changes.insert(
+					urn,
+					ExecutedResource::new_delete(provider_name, &self.engine.current_state(urn)?),
+				);
+			}
+		}
+
+		Ok(self.engine.refreshed_resources.clone())
+	}
+
+	// write the refreshed state of the resources changed outside of Mashin,
+	// no provider hook is called
+	pub fn save_refreshed_states(&self) -> Result<usize> {
+		let refreshed_resources = self.engine.refreshed_resources.borrow();
+		let mut operations = Vec::new();
+
+		for (urn, refreshed_state) in &refreshed_resources.states {
+			if refreshed_resources.drifted.contains_key(urn) {
+				operations.push(StateOperation::Save(
+					urn.clone(),
+					refreshed_state.encrypt(&self.engine.key)?,
+				));
+			}
+		}
+
+		let total_resources = operations.len();
+		self.engine.state_handler.borrow().apply_batch(operations)?;
+
+		Ok(total_resources)
+	}
+
+	// outputs exported by the main module during the last run
+	pub fn outputs(&self) -> StackOutputs {
+		self.engine.outputs.borrow().clone()
+	}
+
+	// outputs saved by the last apply
+	pub fn stored_outputs(&self) -> Result<StackOutputs> {
+		StackOutputs::load(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// replace the stored outputs with the ones exported during the last run
+	pub fn save_outputs(&self) -> Result<()> {
+		self.engine
+			.outputs
+			.borrow()
+			.save(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// remove the stored outputs, once the stack is destroyed
+	pub fn clear_outputs(&self) -> Result<()> {
+		StackOutputs::default().save(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// outputs of the other projects read during the last run
+	pub fn stack_references(&self) -> StackReferences {
+		self.engine.stack_references.borrow().clone()
+	}
+
+	// outputs of the other projects saved by the last apply
+	pub fn stored_stack_references(&self) -> Result<StackReferences> {
+		StackReferences::load(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// replace the stored stack references with the ones read during the last run
+	pub fn save_stack_references(&self) -> Result<()> {
+		self.engine
+			.stack_references
+			.borrow()
+			.save(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// hash of the resources within the state, see `Plan::state_hash`
+	pub fn state_hash(&self) -> Result<String> {
+		Plan::state_hash(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// make sure the state has not changed since the plan was made
+	pub fn verify_plan(&self, plan: &Plan) -> Result<()> {
+		plan.verify_state(&self.engine.state_handler.borrow(), &self.engine.key)
+	}
+
+	// the main module resolved from the current directory, unless it's an url
+	pub fn main_module_specifier(&self) -> Result<ModuleSpecifier> {
+		let main_module_path = &self.main_module;
+		Ok(if main_module_path.starts_with("https") || main_module_path.starts_with("file:") {
+			ModuleSpecifier::from_str(main_module_path)?
+		} else {
+			resolve_path(main_module_path, current_dir()?.as_path())?
+		})
+	}
+
+	// run the main module, the resources of the interrupted apply are read back
+	// by their provider and saved within the state
+	pub async fn reconcile(&mut self) -> Result<()> {
+		self.run_main_module().await
+	}
+
+	// run the main module, only the resource targeted by the import command is
+	// read by its provider and saved within the state
+	pub async fn import(&mut self) -> Result<RawState> {
+		let urn = match &self.engine.command {
+			RuntimeCommand::Import { urn, .. } => urn.clone(),
+			_ => bail!("the engine is not built to import a resource"),
+		};
+
+		if !self.engine.current_state(&urn)?.is_null() {
+			bail!(
+				"`{}` already exists in the state, run `mashin state rm` to forget it first",
+				urn.as_display()
+			)
+		}
+
+		self.run_main_module().await?;
+
+		let state = self.engine.current_state(&urn)?;
+		if state.is_null() {
+			bail!("resource `{}` is not declared in `{}`", urn.as_display(), self.main_module)
+		}
+
+		Ok(state)
+	}
+
+	// call the provider `delete` hook for all resources marked to be deleted
+	pub fn delete_resources(&self, executed_resources: &ExecutedResources) -> Result<()> {
+		let pb = self.engine.progress_manager.progress_bar();
+
+		// the resources declared by the main module are known once it ran
+		let declared_urns = self.engine.declared_resources.borrow().clone();
+		for urn in executed_resources.deletion_order(&declared_urns)? {
+			if let Some(pb) = &pb {
+				pb.inc(1);
+				pb.set_message(urn.as_display());
+			}
+
+			self.engine.delete_resource(&urn)?;
+		}
+
+		Ok(())
+	}
+
 	// trigger `bootstrapMainRuntime` in `js/99_main.js`
 	fn bootstrap(&mut self) -> Result<()> {
 		self.runtime.execute_script(
@@ -220,7 +409,9 @@ impl<T: Config> Runtime<T> {
 					// allow parsing env with Deno.args
 					"args": self.raw_args,
 					// allow target with Deno.env
-					"target": env!("TARGET")
+					"target": env!("TARGET"),
+					// allow per-workspace config with `Mashin.workspace`
+					"workspace": self.engine.mashin_dir.workspace(),
 				})
 			)
 			.into(),
@@ -230,12 +421,7 @@ impl<T: Config> Runtime<T> {
 
 	// run the main module, evaluating each resource
 	async fn run_main_module(&mut self) -> Result<()> {
-		let main_module_path = &self.main_module;
-		let main_module = if main_module_path.starts_with("https") {
-			ModuleSpecifier::from_str(main_module_path)?
-		} else {
-			resolve_path(&self.main_module, current_dir()?.as_path())?
-		};
+		let main_module = self.main_module_specifier()?;
 		if self.engine.command == RuntimeCommand::Prepare {
 			log::info!("    Fetching dependencies");
 		}
@@ -0,0 +1,258 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use anyhow::{anyhow, bail, Result};
+use deno_core::{
+	include_js_files, resolve_path, serde_json, serde_v8, v8, Extension, JsRuntime, ModuleLoader,
+	RuntimeOptions,
+};
+use mashin_core::PlannedResource;
+use serde::Deserialize;
+use std::{env::current_dir, fs, path::Path, rc::Rc};
+
+/// Extensions of the policy modules
+const POLICY_EXTENSIONS: [&str; 4] = ["ts", "js", "mts", "mjs"];
+
+/// What happens when a policy is violated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Enforcement {
+	/// Reported, the changes can still be applied
+	Advisory,
+	/// The changes can't be applied
+	Mandatory,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyViolation {
+	/// Policy name, `{file}:{export}`
+	pub policy: String,
+	pub urn: String,
+	pub enforcement: Enforcement,
+	pub message: String,
+}
+
+/// Runtime evaluating the policy modules of a folder, isolated from the
+/// engine; the policies only get the planned resources, see `PlanReport`.
+///
+/// The resources are the ones of the JSON plan, their sensitive values are
+/// `"[sensitive]"`: a policy can require a secret to be set, not check its
+/// value.
+///
+/// ```ts
+/// export const privateBuckets = {
+///   // `advisory` or `mandatory`, default to `mandatory`
+///   enforcement: "mandatory",
+///   // return a message, or a list of messages, when the resource is not compliant
+///   validate: (resource) =>
+///     resource.config.acl === "public-read" ? "buckets must be private" : undefined,
+/// };
+/// ```
+pub struct PolicyRuntime {
+	runtime: JsRuntime,
+}
+
+impl PolicyRuntime {
+	/// Load all policy modules of the folder, the exported objects with a
+	/// `validate` function are registered as policies.
+	pub async fn new(policy_dir: &Path, module_loader: Rc<dyn ModuleLoader>) -> Result<Self> {
+		let cwd = current_dir()?;
+		let mut modules = fs::read_dir(policy_dir)
+			.map_err(|err| anyhow!("unable to read policies `{}`; {err}", policy_dir.display()))?
+			.map(|entry| Ok(entry?.path()))
+			.collect::<Result<Vec<_>>>()?;
+		modules.retain(|path| {
+			path.is_file() &&
+				path.extension()
+					.and_then(|extension| extension.to_str())
+					.map_or(false, |extension| POLICY_EXTENSIONS.contains(&extension))
+		});
+		modules.sort();
+
+		if modules.is_empty() {
+			bail!("no policy module found in `{}`", policy_dir.display())
+		}
+
+		// the modules are imported by a generated entry point, registering their policies
+		let mut imports = String::new();
+		let mut registrations = String::new();
+		for (idx, path) in modules.iter().enumerate() {
+			let specifier = resolve_path(&path.to_string_lossy(), &cwd)?;
+			let file = path.file_name().unwrap_or_default().to_string_lossy();
+			imports.push_str(&format!(
+				"import * as policy{idx} from {};\n",
+				serde_json::to_string(specifier.as_str())?
+			));
+			registrations.push_str(&format!(
+				"globalThis.__mashinPolicy.register({}, policy{idx});\n",
+				serde_json::to_string(&file)?
+			));
+		}
+
+		let extension = Extension::builder("mashin_policy")
+			.esm(include_js_files!(
+				mashin_policy dir "js",
+				"70_policy.js",
+			))
+			.esm_entry_point("ext:mashin_policy/70_policy.js")
+			.build();
+
+		let mut runtime = JsRuntime::new(RuntimeOptions {
+			extensions: vec![
+				deno_console::deno_console::init_ops_and_esm(),
+				deno_webidl::deno_webidl::init_ops_and_esm(),
+				extension,
+			],
+			module_loader: Some(module_loader),
+			..Default::default()
+		});
+
+		let entry_point =
+			resolve_path(&policy_dir.join("__mashin_policies.js").to_string_lossy(), &cwd)?;
+		let mod_id = runtime
+			.load_main_module(&entry_point, Some(format!("{imports}{registrations}").into()))
+			.await?;
+		let result = runtime.mod_evaluate(mod_id);
+		runtime.run_event_loop(false).await?;
+		result.await??;
+
+		Ok(Self { runtime })
+	}
+
+	/// Validate each planned resource against all policies.
+	pub fn check(&mut self, resources: &[PlannedResource]) -> Result<Vec<PolicyViolation>> {
+		let violations = self.runtime.execute_script(
+			"file://__mashin_policy_check.js",
+			format!("globalThis.__mashinPolicy.check({})", serde_json::to_string(resources)?)
+				.into(),
+		)?;
+
+		let scope = &mut self.runtime.handle_scope();
+		let violations = v8::Local::new(scope, violations);
+		Ok(serde_v8::from_v8(scope, violations)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use deno_core::FsModuleLoader;
+	use serde_json::json;
+	use tempfile::TempDir;
+
+	fn policy_dir(policies: &str) -> TempDir {
+		let policy_dir = tempfile::tempdir().unwrap();
+		fs::write(policy_dir.path().join("buckets.js"), policies).unwrap();
+		policy_dir
+	}
+
+	fn bucket(acl: &str) -> PlannedResource {
+		PlannedResource {
+			urn: format!("urn:provider:aws:s3:bucket?={acl}"),
+			provider: "aws".to_string(),
+			action: "create".to_string(),
+			config: json!({ "acl": acl }),
+			changes: vec![],
+		}
+	}
+
+	#[tokio::test]
+	async fn violations_keep_their_enforcement() {
+		let policy_dir = policy_dir(
+			r#"
+			export const privateBuckets = {
+				validate: (resource) =>
+					resource.config.acl === "public-read" ? "buckets must be private" : undefined,
+			};
+			export const aclReview = {
+				enforcement: "advisory",
+				validate: (resource) => [`acl ${resource.config.acl}`, "review the acl"],
+			};
+			export const notAPolicy = { enforcement: "mandatory" };
+			"#,
+		);
+
+		let mut policy_runtime =
+			PolicyRuntime::new(policy_dir.path(), Rc::new(FsModuleLoader)).await.unwrap();
+		let mut violations =
+			policy_runtime.check(&[bucket("private"), bucket("public-read")]).unwrap();
+		violations.sort_by(|a, b| (&a.urn, &a.message).cmp(&(&b.urn, &b.message)));
+
+		let violations = violations
+			.iter()
+			.map(|violation| {
+				(
+					violation.policy.as_str(),
+					violation.urn.as_str(),
+					violation.enforcement,
+					violation.message.as_str(),
+				)
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			violations,
+			vec![
+				(
+					"buckets.js:aclReview",
+					"urn:provider:aws:s3:bucket?=private",
+					Enforcement::Advisory,
+					"acl private"
+				),
+				(
+					"buckets.js:aclReview",
+					"urn:provider:aws:s3:bucket?=private",
+					Enforcement::Advisory,
+					"review the acl"
+				),
+				(
+					"buckets.js:aclReview",
+					"urn:provider:aws:s3:bucket?=public-read",
+					Enforcement::Advisory,
+					"acl public-read"
+				),
+				(
+					"buckets.js:privateBuckets",
+					"urn:provider:aws:s3:bucket?=public-read",
+					Enforcement::Mandatory,
+					"buckets must be private"
+				),
+				(
+					"buckets.js:aclReview",
+					"urn:provider:aws:s3:bucket?=public-read",
+					Enforcement::Advisory,
+					"review the acl"
+				),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn invalid_enforcement_is_rejected() {
+		let policy_dir = policy_dir(
+			r#"
+			export const privateBuckets = {
+				enforcement: "strict",
+				validate: () => undefined,
+			};
+			"#,
+		);
+
+		let err = PolicyRuntime::new(policy_dir.path(), Rc::new(FsModuleLoader))
+			.await
+			.err()
+			.expect("invalid enforcement");
+		assert!(err.to_string().contains(r#"invalid enforcement "strict""#), "{err}");
+	}
+}
@@ -91,7 +91,9 @@ pub fn provider(attr: TokenStream, item: TokenStream) -> TokenStream {
 ///                        fields to the Typescript environment, use the `#[sensitive]` attribute on those fields.
 ///                        As a result, sensitive data will only exist within the encrypted state and will not be
 ///                        accessible in the Typescript environment. Typescript bindings are automatically generated
-///                        for all fields, excluding sensitive ones.
+///                        for all fields, excluding sensitive ones. When the shape of the resource changes, bump
+///                        the state schema version with `#[mashin::resource(version = 2)]` and rewrite the older
+///                        states within `Resource::migrate_state`.
 ///
 /// `#[mashin::calls]`:    This attribute is used to define the CRUD operations. Developers can implement the required
 ///                        methods for creating, reading, updating, and deleting resources.
@@ -197,15 +197,7 @@ pub fn expand_provider(def: &mut Def) -> proc_macro2::TokenStream {
 				resource.set_raw_config(raw_config);
 
 				runtime
-					.block_on(async {
-						match args.action.as_ref() {
-							::mashin_sdk::ResourceAction::Update { diff } => resource.update(provider_state, diff),
-							::mashin_sdk::ResourceAction::Create => resource.create(provider_state),
-							::mashin_sdk::ResourceAction::Delete => resource.delete(provider_state),
-							::mashin_sdk::ResourceAction::Get => resource.get(provider_state),
-						}
-						.await
-					})
+					.block_on(::mashin_sdk::call_resource(&mut *resource, provider_state, args.action.as_ref()))
 					.expect("valid execution");
 
 				let state = resource.to_raw_state().expect("valid resource");
@@ -110,6 +110,18 @@ pub fn expand_resource(def: &mut Def) -> proc_macro2::TokenStream {
 		fields_json.push(serializer);
 	});
 
+	let version = resource.version;
+	fields_json.push(quote! {
+		  state.serialize_field(::mashin_sdk::KEY_VERSION,
+			   &::mashin_sdk::ext::serde_json::json! {
+					{
+						  "__value": #version,
+						  "__sensitive": false,
+					}
+			   }
+		  )?;
+	});
+
 	let total_fields = fields_json.len();
 	let vis = &resource_item.vis;
 	let fields = resource_item.fields.iter().collect::<Vec<_>>();
@@ -173,6 +185,12 @@ pub fn expand_resource(def: &mut Def) -> proc_macro2::TokenStream {
 					  self.__config = ::mashin_sdk::ext::serde_json::from_value::<#config_ident>(config).unwrap_or_default();
 				 }
 
+				 fn state_version() -> u32
+				 where
+					  Self: Sized {
+							#version
+					  }
+
 				 fn name(&self) -> &str {
 					  self.__name.as_str()
 				 }
@@ -51,8 +51,8 @@ impl Def {
 
 			// root attrs
 			match resource_attr {
-				Some(ProviderAttr::Resource(span)) if resource.is_none() =>
-					resource = Some(resource::ResourceDef::try_from(span, index, item)?),
+				Some(ProviderAttr::Resource(span, version)) if resource.is_none() =>
+					resource = Some(resource::ResourceDef::try_from(span, version, index, item)?),
 				Some(ProviderAttr::Config(span)) if config.is_none() =>
 					config = Some(config::ConfigDef::try_from(span, index, item)?),
 				Some(ProviderAttr::ResourceImpl(span)) if resource_calls.is_none() =>
@@ -89,11 +89,13 @@ mod keyword {
 	syn::custom_keyword!(resource);
 	syn::custom_keyword!(calls);
 	syn::custom_keyword!(ts);
+	syn::custom_keyword!(version);
 }
 
 #[derive(Debug)]
 enum ProviderAttr {
-	Resource(proc_macro2::Span),
+	/// `#[mashin::resource]` with the optional `version = N` of the state schema
+	Resource(proc_macro2::Span, Option<syn::LitInt>),
 	ResourceImpl(proc_macro2::Span),
 	Config(proc_macro2::Span),
 	Ts(proc_macro2::Span),
@@ -102,7 +104,7 @@ enum ProviderAttr {
 impl ProviderAttr {
 	fn span(&self) -> proc_macro2::Span {
 		match self {
-			Self::Resource(span) => *span,
+			Self::Resource(span, _) => *span,
 			Self::Config(span) => *span,
 			Self::ResourceImpl(span) => *span,
 			Self::Ts(span) => *span,
@@ -121,7 +123,16 @@ impl syn::parse::Parse for ProviderAttr {
 		let lookahead = content.lookahead1();
 
 		if lookahead.peek(keyword::resource) {
-			return Ok(ProviderAttr::Resource(content.parse::<keyword::resource>()?.span()))
+			let span = content.parse::<keyword::resource>()?.span();
+			let mut version = None;
+			if content.peek(syn::token::Paren) {
+				let args;
+				syn::parenthesized!(args in content);
+				args.parse::<keyword::version>()?;
+				args.parse::<syn::Token![=]>()?;
+				version = Some(args.parse::<syn::LitInt>()?);
+			}
+			return Ok(ProviderAttr::Resource(span, version))
 		} else if lookahead.peek(keyword::config) {
 			return Ok(ProviderAttr::Config(content.parse::<keyword::config>()?.span()))
 		} else if lookahead.peek(keyword::calls) {
@@ -24,6 +24,8 @@ pub struct ResourceDef {
 	pub index: usize,
 	pub attr_span: proc_macro2::Span,
 	pub docs: Vec<syn::Expr>,
+	/// Version of the state schema, bumped when the shape of the resource changes
+	pub version: u32,
 }
 
 mod keyword {
@@ -33,9 +35,22 @@ mod keyword {
 impl ResourceDef {
 	pub fn try_from(
 		attr_span: proc_macro2::Span,
+		version: Option<syn::LitInt>,
 		index: usize,
 		item: &mut syn::Item,
 	) -> syn::Result<Self> {
+		let version = match version {
+			Some(version) => {
+				let value = version.base10_parse::<u32>()?;
+				if value == 0 {
+					let msg = "Invalid mashin::resource, version must start at 1";
+					return Err(syn::Error::new(version.span(), msg))
+				}
+				value
+			},
+			None => 1,
+		};
+
 		let item = if let syn::Item::Struct(item) = item {
 			item
 		} else {
@@ -52,7 +67,7 @@ impl ResourceDef {
 
 		syn::parse2::<keyword::Resource>(item.ident.to_token_stream())?;
 
-		Ok(Self { name: "".into(), index, attr_span, docs })
+		Ok(Self { name: "".into(), index, attr_span, docs, version })
 	}
 }
 
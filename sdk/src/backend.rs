@@ -0,0 +1,400 @@
+/* -------------------------------------------------------- *\
+ *                                                          *
+ *      ███╗░░░███╗░█████╗░░██████╗██╗░░██╗██╗███╗░░██╗     *
+ *      ████╗░████║██╔══██╗██╔════╝██║░░██║██║████╗░██║     *
+ *      ██╔████╔██║███████║╚█████╗░███████║██║██╔██╗██║     *
+ *      ██║╚██╔╝██║██╔══██║░╚═══██╗██╔══██║██║██║╚████║     *
+ *      ██║░╚═╝░██║██║░░██║██████╔╝██║░░██║██║██║░╚███║     *
+ *      ╚═╝░░░░░╚═╝╚═╝░░╚═╝╚═════╝░╚═╝░░╚═╝╚═╝╚═╝░░╚══╝     *
+ *                                         by Nutshimit     *
+ * -------------------------------------------------------- *
+ *                                                          *
+ *  This file is licensed as MIT. See LICENSE for details.  *
+ *                                                          *
+\* ---------------------------------------------------------*/
+
+use crate::{Result, Urn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{env, fmt, mem, process, slice};
+
+/// Arguments sent by the engine to the `save` symbol of a state backend.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackendSaveArgs {
+	pub urn: Urn,
+	/// Encrypted state, the backend should store it as-is.
+	pub state: String,
+}
+
+/// Holder of the state lock, recorded by the backend to diagnose stale locks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockInfo {
+	/// Unique id of the lock, only its holder can release it
+	pub id: String,
+	pub pid: u32,
+	pub host: String,
+	pub user: String,
+	/// RFC 3339 date when the lock was acquired
+	pub started_at: String,
+}
+
+impl LockInfo {
+	/// Lock info of the current process.
+	pub fn current() -> Self {
+		let now = chrono::Utc::now();
+		let pid = process::id();
+		let host = hostname::get()
+			.map(|host| host.to_string_lossy().to_string())
+			.unwrap_or_else(|_| "unknown".to_string());
+		let user = env::var("USER")
+			.or_else(|_| env::var("USERNAME"))
+			.unwrap_or_else(|_| "unknown".to_string());
+
+		Self {
+			id: format!("{host}-{pid}-{}", now.timestamp_nanos()),
+			pid,
+			host,
+			user,
+			started_at: now.to_rfc3339(),
+		}
+	}
+}
+
+impl fmt::Display for LockInfo {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}@{} (pid {}) since {}", self.user, self.host, self.pid, self.started_at)
+	}
+}
+
+/// A trait representing a state backend loaded by the engine from a cdylib.
+///
+/// The engine encrypts the states before they reach the backend, a backend
+/// only has to store and return opaque strings indexed by their URN.
+pub trait StateBackend {
+	/// Initialize the backend with the props given in the MashinScript.
+	fn new(props: Value) -> Result<Self>
+	where
+		Self: Sized;
+	/// Returns the encrypted state of the `urn`, if any.
+	fn get(&self, urn: &Urn) -> Result<Option<String>>;
+	/// Insert or replace the encrypted state of the `urn`.
+	fn save(&self, urn: &Urn, state: &str) -> Result<()>;
+	/// Remove the state of the `urn`.
+	fn delete(&self, urn: &Urn) -> Result<()>;
+	/// Returns all the URNs stored in the backend.
+	fn list(&self) -> Result<Vec<Urn>>;
+	/// Try to acquire the state lock, returns the current holder if the
+	/// state is already locked.
+	fn lock(&self, _lock: &LockInfo) -> Result<Option<LockInfo>> {
+		Ok(None)
+	}
+	/// Release the state lock, if it is held by `lock`.
+	fn unlock(&self, _lock: &LockInfo) -> Result<()> {
+		Ok(())
+	}
+	/// Release the state lock, whoever holds it.
+	fn force_unlock(&self) -> Result<()> {
+		Ok(())
+	}
+}
+
+/// Deserialize the args, execute the `call` on the backend and encode the result
+/// the way the engine expect it (JSON prefixed by its length).
+///
+/// # Safety
+///
+/// `handle_ptr` must be a pointer returned by the `new` symbol, and `args_ptr`
+/// must be valid for `args_length` bytes.
+#[doc(hidden)]
+pub unsafe fn call_backend<B, A, R, F>(
+	handle_ptr: *mut B,
+	args_ptr: *const u8,
+	args_length: usize,
+	call: F,
+) -> *const u8
+where
+	B: StateBackend,
+	A: DeserializeOwned,
+	R: Serialize,
+	F: FnOnce(&B, A) -> Result<R>,
+{
+	let result = match handle_ptr.as_ref() {
+		Some(backend) => serde_json::from_slice::<A>(slice::from_raw_parts(args_ptr, args_length))
+			.map_err(Into::into)
+			.and_then(|args| call(backend, args)),
+		None => Err(anyhow::anyhow!("the state backend is not initialized")),
+	};
+
+	encode_result(result)
+}
+
+/// Deserialize the props and initialize the backend, the engine gets the
+/// address of the backend or the error, the way [`call_backend`] returns them.
+///
+/// # Safety
+///
+/// `args_ptr` must be valid for `args_length` bytes.
+#[doc(hidden)]
+pub unsafe fn new_backend<B: StateBackend>(args_ptr: *const u8, args_length: usize) -> *const u8 {
+	let result = serde_json::from_slice::<Value>(slice::from_raw_parts(args_ptr, args_length))
+		.map_err(Into::into)
+		.and_then(B::new)
+		.map(|backend| Box::into_raw(Box::new(backend)) as usize);
+
+	encode_result(result)
+}
+
+/// Encode the result as JSON prefixed by its length (u32), the errors are
+/// sent as strings; nothing can panic across the FFI boundary.
+fn encode_result<R: Serialize>(result: Result<R>) -> *const u8 {
+	let result = result.map_err(|err| err.to_string());
+	let encoded = serde_json::to_vec(&result).unwrap_or_else(|err| {
+		serde_json::to_vec(&Err::<(), _>(format!("invalid backend result; {err}")))
+			.unwrap_or_default()
+	});
+	let mut v = (encoded.len() as u32).to_be_bytes().to_vec();
+	v.extend(encoded);
+
+	let ret = v.as_ptr();
+	mem::forget(v);
+	ret
+}
+
+/// The `construct_backend!` macro exposes a type implementing [`StateBackend`]
+/// to the Mashin engine. It generates the `new`, `drop`, `get`, `save`, `delete`,
+/// `list`, `lock`, `unlock` and `force_unlock` symbols loaded by the engine.
+///
+/// The backend is then selected in `.mashin/config.json`, so every command,
+/// including `mashin state`, locks and reads the same state:
+///
+/// ```json
+/// { "backend": { "type": "plugin", "path": "./libmy_backend.so", "props": { "bucket": "my-state" } } }
+/// ```
+///
+/// It can also be selected from the MashinScript, the state lock is then moved
+/// to the backend once the script is evaluated:
+///
+/// ```ts
+/// const backend = new __mashin.DynamicBackend("file:///path/to/libmy_backend.so", { bucket: "my-state" });
+/// await __mashin.setBackend(backend);
+/// ```
+#[macro_export]
+macro_rules! construct_backend {
+	($backend:ty) => {
+		static __MASHIN_BACKEND_LOG_INIT: ::std::sync::Once = ::std::sync::Once::new();
+
+		#[no_mangle]
+		pub extern "C" fn new(
+			logger_ptr: *mut &'static $crate::CliLogger,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			__MASHIN_BACKEND_LOG_INIT.call_once(|| {
+				let logger = unsafe { Box::from_raw(logger_ptr) };
+				::log::set_max_level(::log::LevelFilter::Info);
+				// the logger may already be set by the host
+				let _ = ::log::set_boxed_logger(Box::new(logger));
+			});
+
+			unsafe { $crate::backend::new_backend::<$backend>(args_ptr, args_length) }
+		}
+
+		#[no_mangle]
+		pub extern "C" fn get(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, urn: $crate::Urn| $crate::StateBackend::get(backend, &urn),
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn save(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, args: $crate::BackendSaveArgs| {
+						$crate::StateBackend::save(backend, &args.urn, &args.state)
+					},
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn delete(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, urn: $crate::Urn| $crate::StateBackend::delete(backend, &urn),
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn list(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, _: $crate::ext::serde_json::Value| {
+						$crate::StateBackend::list(backend)
+					},
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn lock(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, lock: $crate::LockInfo| $crate::StateBackend::lock(backend, &lock),
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn unlock(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, lock: $crate::LockInfo| $crate::StateBackend::unlock(backend, &lock),
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn force_unlock(
+			handle_ptr: *mut $backend,
+			args_ptr: *const u8,
+			args_length: usize,
+		) -> *const u8 {
+			unsafe {
+				$crate::backend::call_backend(
+					handle_ptr,
+					args_ptr,
+					args_length,
+					|backend, _: $crate::ext::serde_json::Value| {
+						$crate::StateBackend::force_unlock(backend)
+					},
+				)
+			}
+		}
+
+		#[no_mangle]
+		pub extern "C" fn drop(handle: *mut $backend) {
+			assert!(!handle.is_null());
+			unsafe {
+				::std::ptr::drop_in_place(handle);
+				::std::alloc::dealloc(handle as *mut u8, ::std::alloc::Layout::new::<$backend>());
+			}
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	struct TestBackend;
+
+	impl StateBackend for TestBackend {
+		fn new(props: Value) -> Result<Self> {
+			match props["fail"].as_bool() {
+				Some(true) => Err(anyhow::anyhow!("invalid props")),
+				_ => Ok(Self),
+			}
+		}
+		fn get(&self, _urn: &Urn) -> Result<Option<String>> {
+			Ok(Some("state".to_string()))
+		}
+		fn save(&self, _urn: &Urn, _state: &str) -> Result<()> {
+			Ok(())
+		}
+		fn delete(&self, _urn: &Urn) -> Result<()> {
+			Ok(())
+		}
+		fn list(&self) -> Result<Vec<Urn>> {
+			Ok(Vec::new())
+		}
+	}
+
+	fn decode<R: DeserializeOwned>(ptr: *const u8) -> std::result::Result<R, String> {
+		unsafe {
+			let length = u32::from_be_bytes(*(ptr as *const [u8; 4])) as usize;
+			serde_json::from_slice(slice::from_raw_parts(ptr.add(4), length)).unwrap()
+		}
+	}
+
+	fn new(props: Value) -> std::result::Result<usize, String> {
+		let args = serde_json::to_vec(&props).unwrap();
+		decode(unsafe { new_backend::<TestBackend>(args.as_ptr(), args.len()) })
+	}
+
+	#[test]
+	fn new_returns_the_error_instead_of_panicking() {
+		assert_eq!(new(json!({ "fail": true })), Err("invalid props".to_string()));
+		assert!(new(json!("not an object")).is_ok());
+		assert!(new(Value::Null).is_ok());
+
+		let args = b"{ invalid json";
+		let result = decode::<usize>(unsafe { new_backend::<TestBackend>(args.as_ptr(), 14) });
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn call_backend_uses_the_initialized_backend() {
+		let handle = new(Value::Null).unwrap() as *mut TestBackend;
+		let args = serde_json::to_vec("urn:provider:test:state").unwrap();
+		let get = |handle: *mut TestBackend| -> std::result::Result<Option<String>, String> {
+			decode(unsafe {
+				call_backend(handle, args.as_ptr(), args.len(), |backend, urn: Urn| {
+					backend.get(&urn)
+				})
+			})
+		};
+
+		assert_eq!(get(handle), Ok(Some("state".to_string())));
+		assert!(get(std::ptr::null_mut()).is_err());
+
+		unsafe { drop(Box::from_raw(handle)) };
+	}
+}
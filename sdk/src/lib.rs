@@ -31,6 +31,9 @@
 //!   for custom resources. Users only need to define the resource's properties and implement the
 //!   logic for creating, updating, and deleting the resource using the provider.
 //!
+//! - **`construct_backend!` macro**: This macro exposes a custom state backend, implementing the
+//!   `StateBackend` trait, to the Mashin engine.
+//!
 //!
 //! # Key concepts
 //!
@@ -68,6 +71,7 @@
 pub use crate::urn::Urn;
 pub use anyhow::Result;
 use async_trait::async_trait;
+pub use backend::{BackendSaveArgs, LockInfo, StateBackend};
 pub use build::build;
 pub use deserialize::deserialize_state_field;
 pub use logger::CliLogger;
@@ -78,6 +82,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{any::Any, cell::RefCell, fmt::Debug, rc::Rc, sync::Arc};
 
+#[doc(hidden)]
+pub mod backend;
 mod build;
 mod deserialize;
 mod logger;
@@ -89,8 +95,9 @@ pub const KEY_CONFIG: &str = "__config";
 pub const KEY_URN: &str = "__urn";
 pub const KEY_NAME: &str = "__name";
 pub const KEY_SENSITIVE: &str = "__sensitive";
+pub const KEY_VERSION: &str = "__version";
 // keys to skip
-pub const KEYS_CORE: [&str; 1] = [KEY_SENSITIVE];
+pub const KEYS_CORE: [&str; 2] = [KEY_SENSITIVE, KEY_VERSION];
 pub const KEY_VALUE: &str = "__value";
 
 /// Re-exports some helpers from other libraries
@@ -127,6 +134,10 @@ pub enum ResourceAction {
 	Delete,
 	#[default]
 	Get,
+	/// Adopt an existing resource, identified by its provider id
+	Import {
+		id: String,
+	},
 }
 
 impl ResourceAction {
@@ -137,6 +148,7 @@ impl ResourceAction {
 			ResourceAction::Create => "Creating",
 			ResourceAction::Delete => "Deleting",
 			ResourceAction::Get => "Reading",
+			ResourceAction::Import { .. } => "Importing",
 		}
 	}
 	/// Simple present of the action
@@ -146,6 +158,7 @@ impl ResourceAction {
 			ResourceAction::Create => "Create",
 			ResourceAction::Delete => "Delete",
 			ResourceAction::Get => "Read",
+			ResourceAction::Import { .. } => "Import",
 		}
 	}
 
@@ -155,6 +168,7 @@ impl ResourceAction {
 			ResourceAction::Create => "Created",
 			ResourceAction::Delete => "Deleted",
 			ResourceAction::Get => "Read",
+			ResourceAction::Import { .. } => "Imported",
 		}
 	}
 }
@@ -214,13 +228,20 @@ pub trait ResourceDefault {
 	where
 		Self: Sized;
 	fn set_raw_config(&mut self, config: &Rc<Value>);
+	/// Version of the state schema, set with `#[mashin::resource(version = N)]`.
+	fn state_version() -> u32
+	where
+		Self: Sized,
+	{
+		1
+	}
 	fn from_current_state(
 		name: &str,
 		urn: &str,
 		raw_state: Rc<RefCell<Value>>,
 	) -> Result<Rc<RefCell<Self>>>
 	where
-		Self: Default,
+		Self: Default + Resource,
 		for<'de> Self: Deserialize<'de>,
 	{
 		let state = raw_state.borrow_mut();
@@ -228,6 +249,24 @@ pub trait ResourceDefault {
 			Ok(Rc::new(RefCell::new(Self::new(name, urn))))
 		} else {
 			let mut state = state.clone();
+
+			// states written before the schema versioning are version 1
+			let from_version = state
+				.get(KEY_VERSION)
+				.and_then(|version| version.get(KEY_VALUE))
+				.and_then(Value::as_u64)
+				.unwrap_or(1) as u32;
+			let version = Self::state_version();
+
+			if from_version > version {
+				anyhow::bail!(
+					"the state of `{urn}` has been written by a newer version of the provider (state version {from_version}, supported {version})"
+				)
+			}
+
+			if from_version < version {
+				state = Self::migrate_state(from_version, state)?;
+			}
 			let merge_fields = json!({
 				"__name": {
 					"value": name,
@@ -304,6 +343,59 @@ pub trait Resource: ResourceEq + ResourceSerialize + ResourceDefault {
 		provider_state: Arc<Mutex<ProviderState>>,
 		diff: &ResourceDiff,
 	) -> Result<()>;
+	/// Upgrades a state written with an older version of the state schema,
+	/// called before the state is deserialized. The raw state holds every field
+	/// as `{ "__value": .., "__sensitive": .. }`.
+	///
+	/// The default implementation keeps the state as-is, missing fields then
+	/// take their default value.
+	///
+	/// ### Arguments
+	///
+	/// * `from_version` - The version of the state schema the state has been written with.
+	/// * `raw` - The raw state as stored by the engine.
+	///
+	/// ### Returns
+	///
+	/// The raw state matching the current version of the state schema.
+	fn migrate_state(from_version: u32, raw: Value) -> Result<Value>
+	where
+		Self: Sized,
+	{
+		let _ = from_version;
+		Ok(raw)
+	}
+	/// Reads an existing resource, not yet managed by Mashin, into `self`.
+	///
+	/// The provider looks the resource up with `id`, its own identifier of the
+	/// resource. A resource that can't be imported returns an error saying so.
+	///
+	/// ### Arguments
+	///
+	/// * `provider_state` - An `Arc<Mutex<ProviderState>>` that represents the current state of the provider.
+	/// * `id` - The identifier of the resource within the provider.
+	///
+	/// ### Returns
+	///
+	/// A `Result` that indicates whether the operation was successful or not.
+	async fn import(&mut self, provider_state: Arc<Mutex<ProviderState>>, id: &str) -> Result<()>;
+}
+
+/// Call the hook of the resource matching the action, used by the providers
+/// generated with `#[mashin::provider]`.
+#[doc(hidden)]
+pub async fn call_resource(
+	resource: &mut dyn Resource,
+	provider_state: Arc<Mutex<ProviderState>>,
+	action: &ResourceAction,
+) -> Result<()> {
+	match action {
+		ResourceAction::Update { diff } => resource.update(provider_state, diff).await,
+		ResourceAction::Create => resource.create(provider_state).await,
+		ResourceAction::Delete => resource.delete(provider_state).await,
+		ResourceAction::Get => resource.get(provider_state).await,
+		ResourceAction::Import { id } => resource.import(provider_state, id).await,
+	}
 }
 
 impl<R: 'static + PartialEq> ResourceEq for R {
@@ -419,3 +511,106 @@ pub fn merge_json(a: &mut serde_json::Value, b: &serde_json::Value) {
 		},
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Default, PartialEq)]
+	struct Recorder {
+		calls: Vec<String>,
+	}
+
+	impl ResourceSerialize for Recorder {
+		fn to_raw_state(&self) -> Result<Value> {
+			Ok(json!(self.calls))
+		}
+	}
+
+	impl ResourceDefault for Recorder {
+		fn new(_name: &str, _urn: &str) -> Self {
+			Self::default()
+		}
+		fn set_raw_config(&mut self, _config: &Rc<Value>) {}
+		fn name(&self) -> &str {
+			"recorder"
+		}
+		fn urn(&self) -> &str {
+			"urn:provider:test:recorder?=recorder"
+		}
+	}
+
+	#[async_trait]
+	impl Resource for Recorder {
+		async fn get(&mut self, _provider_state: Arc<Mutex<ProviderState>>) -> Result<()> {
+			self.calls.push("get".to_string());
+			Ok(())
+		}
+		async fn create(&mut self, _provider_state: Arc<Mutex<ProviderState>>) -> Result<()> {
+			self.calls.push("create".to_string());
+			Ok(())
+		}
+		async fn delete(&mut self, _provider_state: Arc<Mutex<ProviderState>>) -> Result<()> {
+			self.calls.push("delete".to_string());
+			Ok(())
+		}
+		async fn update(
+			&mut self,
+			_provider_state: Arc<Mutex<ProviderState>>,
+			diff: &ResourceDiff,
+		) -> Result<()> {
+			self.calls.push(format!("update {}", diff.0.join(",")));
+			Ok(())
+		}
+		async fn import(
+			&mut self,
+			_provider_state: Arc<Mutex<ProviderState>>,
+			id: &str,
+		) -> Result<()> {
+			if id.is_empty() {
+				anyhow::bail!("`{}` can't be imported without id", self.urn());
+			}
+			self.calls.push(format!("import {id}"));
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn actions_are_dispatched_to_their_hook() {
+		let runtime = tokio::runtime::Runtime::new().unwrap();
+		let provider_state = Arc::new(Mutex::new(ProviderState::default()));
+		let resource: Rc<RefCell<dyn Resource>> = Rc::new(RefCell::new(Recorder::default()));
+
+		for action in [
+			ResourceAction::Create,
+			ResourceAction::Get,
+			ResourceAction::Update { diff: Rc::new(ResourceDiff::new(vec!["size".to_string()])) },
+			ResourceAction::Import { id: "i-0abc".to_string() },
+			ResourceAction::Delete,
+		] {
+			let mut resource = resource.borrow_mut();
+			runtime
+				.block_on(call_resource(&mut *resource, provider_state.clone(), &action))
+				.unwrap();
+		}
+
+		assert_eq!(
+			resource.borrow().to_raw_state().unwrap(),
+			json!(["create", "get", "update size", "import i-0abc", "delete"])
+		);
+
+		// the provider id is passed as-is, the provider decides how to import
+		let mut resource = resource.borrow_mut();
+		let err = runtime
+			.block_on(call_resource(
+				&mut *resource,
+				provider_state,
+				&ResourceAction::Import { id: String::new() },
+			))
+			.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"`urn:provider:test:recorder?=recorder` can't be imported without id"
+		);
+	}
+}